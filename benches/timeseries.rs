@@ -0,0 +1,115 @@
+//! Columnar `TimeSeries` against the `BTreeMap` it replaced, on 25 years of trading days.
+//!
+//! Run with `cargo bench --bench timeseries`.
+
+use std::collections::BTreeMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mysql::chrono::{Datelike, Duration, NaiveDate, Weekday};
+use mysql_common::bigdecimal::BigDecimal;
+use rusty_stocks::timeseries::{Bar, TimeSeries};
+
+fn history() -> TimeSeries {
+    let mut ts = TimeSeries::new("bench");
+    let mut date = NaiveDate::from_ymd_opt(1999, 1, 1).unwrap();
+    while date.year() < 2024 {
+        if date.weekday() != Weekday::Sat && date.weekday() != Weekday::Sun {
+            ts.insert(date, Bar::from_close(BigDecimal::from(100 + ts.len() as i64 % 50)).with_split(1));
+        }
+        date += Duration::days(1);
+    }
+    ts
+}
+
+fn positional(c: &mut Criterion) {
+    let ts = history();
+    let map = ts.clone().into_map();
+    let mut group = c.benchmark_group("positional access over all days");
+    group.sample_size(10);
+    group.bench_function("btreemap iter().nth", |b| b.iter(|| {
+        (0..map.len()).filter(|i| map.iter().nth(*i).unwrap().1.has_split()).count()
+    }));
+    group.bench_function("columnar nth", |b| b.iter(|| {
+        (0..ts.len()).filter(|i| ts.nth(*i).unwrap().1.has_split()).count()
+    }));
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let ts = history();
+    let map = ts.clone().into_map();
+    let dates: Vec<NaiveDate> = ts.dates().cloned().collect();
+    let mut group = c.benchmark_group("date lookup of all days");
+    group.bench_function("btreemap get", |b| b.iter(|| dates.iter().filter_map(|d| map.get(d)).count()));
+    group.bench_function("columnar get", |b| b.iter(|| dates.iter().filter_map(|d| ts.get(d)).count()));
+    group.finish();
+}
+
+fn slicing(c: &mut Criterion) {
+    let ts = history();
+    let map = ts.clone().into_map();
+    let from = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let mut group = c.benchmark_group("copy of the last year");
+    group.bench_function("btreemap range", |b| b.iter(|| map.range(black_box(from)..).map(|x| (*x.0, x.1.clone())).collect::<BTreeMap<NaiveDate, Bar>>()));
+    group.bench_function("columnar slice", |b| b.iter(|| ts.slice(black_box(from)..)));
+    group.finish();
+}
+
+fn conversion(c: &mut Criterion) {
+    let ts = history();
+    let map = ts.clone().into_map();
+    let mut group = c.benchmark_group("conversion");
+    group.bench_function("from map", |b| b.iter(|| TimeSeries::from_map("bench", map.clone())));
+    group.bench_function("into map", |b| b.iter(|| ts.clone().into_map()));
+    group.bench_function("clone of the map alone", |b| b.iter(|| map.clone()));
+    group.finish();
+}
+
+/// An update fetches the last 100 days again, 20 of them are new. They are found walking back from
+/// the end, appended and get their 200 day average.
+fn incremental_update(c: &mut Criterion) {
+    let full = history();
+    let stored = full.slice(..*full.nth(full.len() - 20).unwrap().0);
+    let fetched = full.slice(*full.nth(full.len() - 100).unwrap().0..);
+    let max_date = *stored.last().unwrap().0;
+    let (stored_map, fetched_map) = (stored.clone().into_map(), fetched.clone().into_map());
+    let mut group = c.benchmark_group("incremental update of 20 days");
+    group.sample_size(10);
+    group.bench_function("btreemap iter().nth", |b| b.iter(|| {
+        let mut map = stored_map.clone();
+        let mut averages = Vec::new();
+        for i in (0..fetched_map.len()).rev() {
+            let (date, bar) = fetched_map.iter().nth(i).unwrap();
+            if *date <= max_date {
+                break;
+            }
+            map.insert(*date, bar.clone());
+        }
+        for i in map.len() - 20..map.len() {
+            let sum: BigDecimal = (i + 1 - 200..=i).map(|j| map.iter().nth(j).unwrap().1.close.clone()).sum();
+            averages.push(sum / BigDecimal::from(200));
+        }
+        averages
+    }));
+    group.bench_function("columnar nth", |b| b.iter(|| {
+        let mut ts = stored.clone();
+        let mut averages = Vec::new();
+        for i in (0..fetched.len()).rev() {
+            let (date, bar) = fetched.nth(i).unwrap();
+            if *date <= max_date {
+                break;
+            }
+            ts.insert(*date, bar.to_bar());
+        }
+        let closes = ts.close_column();
+        for i in ts.len() - 20..ts.len() {
+            let sum: BigDecimal = closes[i + 1 - 200..=i].iter().sum();
+            averages.push(sum / BigDecimal::from(200));
+        }
+        averages
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, positional, lookup, slicing, conversion, incremental_update);
+criterion_main!(benches);
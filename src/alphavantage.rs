@@ -3,7 +3,12 @@ use std::{collections::BTreeMap, str::FromStr};
 use mysql::chrono::{NaiveDate};
 use mysql_common::bigdecimal::BigDecimal;
 
-use crate::timeseries::TimeSeries;
+use crate::timeseries::{Bar, TimeSeries};
+
+/// Source of daily close and split data. A compact fetch only needs to cover the most recent days.
+pub trait DataProvider {
+    fn fetch_daily(&self, symbol: &str, compact: bool) -> TimeSeries;
+}
 
 pub struct AlphaVantage {
     key: String,
@@ -15,32 +20,38 @@ impl AlphaVantage {
             key: String::from_str(key).unwrap(),
         }
     }
+}
 
-    pub fn fetch_daily(&self, symbol: &str, compact: bool) -> TimeSeries {
-        let url;
-        if compact {
-            url = format!("https://www.alphavantage.co/query?function=TIME_SERIES_DAILY_ADJUSTED&outputsize=compact&symbol={}&apikey={}", symbol, self.key);
+impl DataProvider for AlphaVantage {
+    fn fetch_daily(&self, symbol: &str, compact: bool) -> TimeSeries {
+        let url = if compact {
+            format!("https://www.alphavantage.co/query?function=TIME_SERIES_DAILY_ADJUSTED&outputsize=compact&symbol={}&apikey={}", symbol, self.key)
         } else {
-            url = format!("https://www.alphavantage.co/query?function=TIME_SERIES_DAILY_ADJUSTED&outputsize=full&symbol={}&apikey={}", symbol, self.key);
-        }
+            format!("https://www.alphavantage.co/query?function=TIME_SERIES_DAILY_ADJUSTED&outputsize=full&symbol={}&apikey={}", symbol, self.key)
+        };
         
-        let json = reqwest::blocking::get(&url).expect(&format!("Couldn't get json from Alphavantage! Request url: {}", &url)).text().unwrap();
+        let json = reqwest::blocking::get(&url).unwrap_or_else(|_| panic!("Couldn't get json from Alphavantage! Request url: {}", &url)).text().unwrap();
         let parsed = json::parse(&json).unwrap();
 
-        let mut data: BTreeMap<NaiveDate, (BigDecimal, Option<f32>)> = BTreeMap::new();
+        let mut data: BTreeMap<NaiveDate, Bar> = BTreeMap::new();
         
         parsed["Time Series (Daily)"].entries().for_each(|x| {
             let entry_json =  &parsed["Time Series (Daily)"][x.0];
-            data.insert(NaiveDate::from_str(x.0).unwrap(),
-            (BigDecimal::from_str(&entry_json["4. close"].to_string()).unwrap(),
-                   Some(entry_json["8. split coefficient"].to_string().parse().unwrap())));
+            let decimal = |key: &str| BigDecimal::from_str(&entry_json[key].to_string()).ok();
+            data.insert(NaiveDate::from_str(x.0).unwrap(), Bar {
+                open: decimal("1. open"),
+                high: decimal("2. high"),
+                low: decimal("3. low"),
+                close: BigDecimal::from_str(&entry_json["4. close"].to_string()).unwrap(),
+                adjusted_close: decimal("5. adjusted close"),
+                volume: entry_json["6. volume"].to_string().parse().ok(),
+                dividend: decimal("7. dividend amount"),
+                split_coefficient: Some(BigDecimal::from_str(&entry_json["8. split coefficient"].to_string()).unwrap()),
+            });
         });
         if data.is_empty() {
             panic!("KEY INVALID OR EQUITY INVALID!");
         }
-        TimeSeries {
-            equity_name: String::from_str(symbol).unwrap(),
-            entries: data,
-        }
+        TimeSeries::from_map(symbol, data)
     }
 }
\ No newline at end of file
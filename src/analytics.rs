@@ -0,0 +1,327 @@
+//! Return and risk metrics over a [`TimeSeries`], meant for the adjusted series so splits don't show up as losses.
+//!
+//! Returns are per trading day and annualized with 252 trading days a year. The risk free rate is
+//! a yearly rate like 0.02 and converted to a daily one where needed.
+
+use std::iter;
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::{BigDecimal, FromPrimitive};
+
+use crate::{indicators::{aligned, closes, to_f64}, timeseries::{Bar, TimeSeries}};
+
+pub const TRADING_DAYS: f64 = 252.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drawdown {
+    /// Largest fall from a previous high, as a positive fraction.
+    pub max_drawdown: f64,
+    pub peak: NaiveDate,
+    pub trough: NaiveDate,
+    /// Date the close got back to the peak, `None` if it hasn't yet.
+    pub recovery: Option<NaiveDate>,
+    /// Longest stretch of trading days spent below a previous high.
+    pub longest_duration: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub total_return: f64,
+    pub annualized_return: f64,
+    pub volatility: Option<f64>,
+    pub sharpe: Option<f64>,
+    pub sortino: Option<f64>,
+    pub drawdown: Drawdown,
+}
+
+impl Stats {
+    /// All metrics of `ts`, `None` with less than two days of data.
+    pub fn of(ts: &TimeSeries, risk_free_rate: f64) -> Option<Self> {
+        let prices = closes(ts);
+        if prices.len() < 2 {
+            return None;
+        }
+        let r = simple_returns(&prices);
+        let total_return = prices[prices.len() - 1] / prices[0] - 1.0;
+        Some(Stats {
+            start_date: *ts.first()?.0,
+            end_date: *ts.last()?.0,
+            total_return,
+            annualized_return: (1.0 + total_return).powf(TRADING_DAYS / r.len() as f64) - 1.0,
+            volatility: annualized_volatility(&r),
+            sharpe: sharpe_ratio(&r, risk_free_rate),
+            sortino: sortino_ratio(&r, risk_free_rate),
+            drawdown: drawdown(ts)?,
+        })
+    }
+}
+
+/// Daily simple returns, keyed by the later of the two days.
+pub fn returns(ts: &TimeSeries) -> TimeSeries {
+    let mut values = vec![None];
+    values.extend(simple_returns(&closes(ts)).into_iter().map(Some));
+    aligned(ts, &values)
+}
+
+/// Daily log returns, keyed by the later of the two days.
+pub fn log_returns(ts: &TimeSeries) -> TimeSeries {
+    let prices = closes(ts);
+    let mut values = vec![None];
+    values.extend(prices.windows(2).map(|w| Some((w[1] / w[0]).ln())));
+    aligned(ts, &values)
+}
+
+/// Sample standard deviation of daily returns, scaled to a year.
+pub fn annualized_volatility(returns: &[f64]) -> Option<f64> {
+    standard_deviation(returns).map(|sd| sd * TRADING_DAYS.sqrt())
+}
+
+/// Annualized mean excess return over its volatility.
+pub fn sharpe_ratio(returns: &[f64], risk_free_rate: f64) -> Option<f64> {
+    let excess = excess_returns(returns, risk_free_rate);
+    let sd = standard_deviation(&excess).filter(|sd| *sd > 0.0)?;
+    Some(mean(&excess) / sd * TRADING_DAYS.sqrt())
+}
+
+/// Like the Sharpe ratio, but only days below the risk free rate count as risk.
+pub fn sortino_ratio(returns: &[f64], risk_free_rate: f64) -> Option<f64> {
+    let excess = excess_returns(returns, risk_free_rate);
+    if excess.is_empty() {
+        return None;
+    }
+    let downside = (excess.iter().map(|r| r.min(0.0).powi(2)).sum::<f64>() / excess.len() as f64).sqrt();
+    if downside == 0.0 {
+        return None;
+    }
+    Some(mean(&excess) / downside * TRADING_DAYS.sqrt())
+}
+
+/// Maximum drawdown of the close and the longest time spent under water, `None` for an empty series.
+pub fn drawdown(ts: &TimeSeries) -> Option<Drawdown> {
+    let first_date = *ts.first()?.0;
+    let mut peak = (first_date, f64::NEG_INFINITY);
+    let mut max = (0.0, first_date, first_date);
+    let (mut duration, mut longest_duration) = (0, 0);
+    for (date, bar) in ts.iter() {
+        let close = to_f64(bar.close);
+        if close >= peak.1 {
+            peak = (*date, close);
+            duration = 0;
+            continue;
+        }
+        duration += 1;
+        longest_duration = longest_duration.max(duration);
+        if 1.0 - close / peak.1 > max.0 {
+            max = (1.0 - close / peak.1, peak.0, *date);
+        }
+    }
+
+    let peak_close = to_f64(ts.get(&max.1)?.close);
+    let recovery = ts.slice(max.2..).iter().find(|x| to_f64(x.1.close) >= peak_close).map(|x| *x.0);
+    Some(Drawdown {
+        max_drawdown: max.0,
+        peak: max.1,
+        trough: max.2,
+        recovery: recovery.filter(|_| max.0 > 0.0),
+        longest_duration,
+    })
+}
+
+/// Return of `equity` with the cash `flows` taken out, the daily returns chained. A flow counts as
+/// paid in before its day's close. Days after a worthless one are left out, `None` with less than
+/// two days.
+pub fn time_weighted_return(equity: &TimeSeries, flows: &[(NaiveDate, f64)]) -> Option<f64> {
+    if equity.len() < 2 {
+        return None;
+    }
+    Some(growth(equity, flows).iter().product::<f64>() - 1.0)
+}
+
+/// Value of what 1 at the first close of `equity` grew to at every close, with the cash `flows`
+/// taken out like in [`time_weighted_return`]. Compares to a benchmark where `equity` can't.
+pub fn flow_adjusted(equity: &TimeSeries, flows: &[(NaiveDate, f64)]) -> TimeSeries {
+    let mut adjusted = TimeSeries::new(&equity.equity_name);
+    let mut value = 1.0;
+    for (date, growth) in equity.dates().zip(iter::once(1.0).chain(growth(equity, flows))) {
+        value *= growth;
+        adjusted.insert(*date, Bar::from_close(BigDecimal::from_f64(value).unwrap()));
+    }
+    adjusted
+}
+
+/// Growth of `equity` from every close to the next with the flows of the day taken out, 1 after a
+/// worthless day.
+fn growth(equity: &TimeSeries, flows: &[(NaiveDate, f64)]) -> Vec<f64> {
+    let values = closes(equity);
+    let dates: Vec<&NaiveDate> = equity.dates().collect();
+    let flow = |date: &NaiveDate| flows.iter().filter(|f| &f.0 == date).map(|f| f.1).sum::<f64>();
+    (1..values.len())
+        .map(|i| if values[i - 1] > 0.0 { (values[i] - flow(dates[i])) / values[i - 1] } else { 1.0 })
+        .collect()
+}
+
+/// Internal rate of return, the yearly rate at which the `flows` discount to zero. Money paid in is
+/// negative and money taken out positive, years have 365 days. `None` without a rate between -99%
+/// and 1000%.
+pub fn irr(flows: &[(NaiveDate, f64)]) -> Option<f64> {
+    let start = flows.iter().map(|f| f.0).min()?;
+    let npv = |rate: f64| flows.iter().map(|(date, x)| x / (1.0 + rate).powf((*date - start).num_days() as f64 / 365.0)).sum::<f64>();
+    let (mut low, mut high) = (-0.99, 10.0);
+    if npv(low).signum() == npv(high).signum() {
+        return None;
+    }
+    // Bisection, the value changes sign once between the bounds for a saver's flows
+    for _ in 0..200 {
+        let mid = (low + high) / 2.0;
+        if npv(mid).signum() == npv(low).signum() {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some((low + high) / 2.0)
+}
+
+/// Annualized volatility over the returns of the last `window` days.
+pub fn rolling_volatility(ts: &TimeSeries, window: usize) -> TimeSeries {
+    rolling_returns(ts, window, annualized_volatility)
+}
+
+pub fn rolling_sharpe(ts: &TimeSeries, window: usize, risk_free_rate: f64) -> TimeSeries {
+    rolling_returns(ts, window, |r| sharpe_ratio(r, risk_free_rate))
+}
+
+pub fn rolling_sortino(ts: &TimeSeries, window: usize, risk_free_rate: f64) -> TimeSeries {
+    rolling_returns(ts, window, |r| sortino_ratio(r, risk_free_rate))
+}
+
+/// Maximum drawdown within the last `window` days, including the current one.
+pub fn rolling_max_drawdown(ts: &TimeSeries, window: usize) -> TimeSeries {
+    let dates: Vec<NaiveDate> = ts.dates().cloned().collect();
+    let values: Vec<Option<f64>> = (0..dates.len()).map(|i| {
+        if window == 0 || i + 1 < window {
+            return None;
+        }
+        drawdown(&ts.slice(dates[i + 1 - window]..=dates[i])).map(|d| d.max_drawdown)
+    }).collect();
+    aligned(ts, &values)
+}
+
+/// Applies `metric` to the `window` returns ending on each day.
+fn rolling_returns<F: Fn(&[f64]) -> Option<f64>>(ts: &TimeSeries, window: usize, metric: F) -> TimeSeries {
+    let r = simple_returns(&closes(ts));
+    let mut values = vec![None];
+    values.extend((0..r.len()).map(|i| if window == 0 || i + 1 < window { None } else { metric(&r[i + 1 - window..=i]) }));
+    aligned(ts, &values)
+}
+
+pub(crate) fn simple_returns(prices: &[f64]) -> Vec<f64> {
+    prices.windows(2).map(|w| w[1] / w[0] - 1.0).collect()
+}
+
+pub(crate) fn excess_returns(returns: &[f64], risk_free_rate: f64) -> Vec<f64> {
+    let daily = (1.0 + risk_free_rate).powf(1.0 / TRADING_DAYS) - 1.0;
+    returns.iter().map(|r| r - daily).collect()
+}
+
+pub(crate) fn mean(x: &[f64]) -> f64 {
+    x.iter().sum::<f64>() / x.len() as f64
+}
+
+pub(crate) fn standard_deviation(x: &[f64]) -> Option<f64> {
+    if x.len() < 2 {
+        return None;
+    }
+    let m = mean(x);
+    Some((x.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (x.len() - 1) as f64).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::{Duration, NaiveDate};
+    use mysql_common::bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+
+    use crate::timeseries::{Bar, TimeSeries};
+
+    use super::*;
+
+    fn day(i: i64) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + Duration::days(i)
+    }
+
+    fn from_closes(closes: &[f64]) -> TimeSeries {
+        let mut ts = TimeSeries::new("test");
+        for (i, c) in closes.iter().enumerate() {
+            ts.insert(day(i as i64), Bar::from_close(BigDecimal::from_f64(*c).unwrap()));
+        }
+        ts
+    }
+
+    fn values(ts: &TimeSeries) -> Vec<f64> {
+        ts.closes().map(|x| x.to_f64().unwrap()).collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn returns_and_ratios() {
+        let ts = from_closes(&[100.0, 110.0, 99.0, 99.0]);
+        let r = values(&returns(&ts));
+        assert_eq!(returns(&ts).first().unwrap().0, &day(1));
+        assert_close(r[0], 0.1);
+        assert_close(r[1], -0.1);
+        assert_close(r[2], 0.0);
+        assert_close(values(&log_returns(&ts))[0], 1.1f64.ln());
+
+        // mean 0, sample sd 0.1
+        assert_close(annualized_volatility(&r).unwrap(), 0.1 * 252f64.sqrt());
+        assert_close(sharpe_ratio(&[0.01, 0.03], 0.0).unwrap(), 0.02 / 0.0002f64.sqrt() * 252f64.sqrt());
+        // downside deviation sqrt((0.01² + 0) / 2)
+        assert_close(sortino_ratio(&[-0.01, 0.03], 0.0).unwrap(), 0.01 / 0.00005f64.sqrt() * 252f64.sqrt());
+        assert_eq!(sortino_ratio(&[0.01, 0.03], 0.0), None);
+        assert_eq!(sharpe_ratio(&[0.01], 0.0), None);
+
+        let stats = Stats::of(&ts, 0.0).unwrap();
+        assert_close(stats.total_return, -0.01);
+        assert_close(stats.annualized_return, 0.99f64.powf(84.0) - 1.0);
+    }
+
+    #[test]
+    fn drawdowns() {
+        let ts = from_closes(&[100.0, 120.0, 90.0, 60.0, 100.0, 130.0, 125.0]);
+        let d = drawdown(&ts).unwrap();
+        assert_close(d.max_drawdown, 0.5);
+        assert_eq!((d.peak, d.trough, d.recovery), (day(1), day(3), Some(day(5))));
+        assert_eq!(d.longest_duration, 3);
+
+        let unrecovered = drawdown(&from_closes(&[100.0, 80.0, 90.0])).unwrap();
+        assert_eq!(unrecovered.recovery, None);
+        assert_eq!(unrecovered.longest_duration, 2);
+
+        let rolling = rolling_max_drawdown(&ts, 3);
+        assert_eq!(rolling.first().unwrap().0, &day(2));
+        assert_close(values(&rolling)[1], 0.5);
+        assert_close(values(&rolling)[4], 1.0 / 26.0);
+        assert_eq!(rolling_volatility(&ts, 2).len(), 5);
+    }
+
+    #[test]
+    fn weighs_returns_with_cash_flows() {
+        // 100 paid in on day 1 doesn't count as a gain
+        let ts = from_closes(&[100.0, 210.0, 231.0]);
+        assert_close(time_weighted_return(&ts, &[(day(0), 100.0), (day(1), 100.0)]).unwrap(), 0.21);
+        assert_close(time_weighted_return(&from_closes(&[0.0, 100.0, 110.0]), &[(day(1), 100.0)]).unwrap(), 0.1);
+        let adjusted = flow_adjusted(&ts, &[(day(0), 100.0), (day(1), 100.0)]);
+        assert_close(closes(&adjusted).last().unwrap() - 1.0, 0.21);
+
+        assert_close(irr(&[(day(0), -100.0), (day(365), 110.0)]).unwrap(), 0.1);
+        // The second 100 was only invested half a year
+        let rate = irr(&[(day(0), -100.0), (day(182), -100.0), (day(365), 215.0)]).unwrap();
+        assert!(rate > 0.09 && rate < 0.11, "{}", rate);
+        assert_eq!(irr(&[(day(0), -100.0), (day(365), -10.0)]), None);
+    }
+}
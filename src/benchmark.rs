@@ -0,0 +1,133 @@
+//! How a strategy did against a benchmark.
+//!
+//! The benchmark is a configured symbol like an index ETF, stored and updated like the other stocks,
+//! or else the symbol's own buy-and-hold. Both curves are lined up on their common days and compared
+//! by daily returns, annualized with 252 trading days.
+//!
+//! Backtest, walk-forward and savings results are compared, savings with the contributions taken
+//! out of the equity. Portfolios hold several symbols and are only compared to a configured
+//! benchmark. A configured benchmark without data in the date range leaves the comparison out.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{analytics::{excess_returns, mean, simple_returns, standard_deviation, TRADING_DAYS}, frame::{Fill, Frame, Join}, indicators::closes, timeseries::TimeSeries};
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Comparison {
+    pub benchmark: String,
+    /// Yearly return over the risk free rate beyond what the beta explains.
+    pub alpha: Option<f64>,
+    /// How strongly the strategy moves with the benchmark, 1 moves alike.
+    pub beta: Option<f64>,
+    /// Yearly standard deviation of the return difference.
+    pub tracking_error: Option<f64>,
+    /// Yearly return difference over the tracking error.
+    pub information_ratio: Option<f64>,
+    /// Mean return on the benchmark's up days over the benchmark's.
+    pub up_capture: Option<f64>,
+    /// Mean return on the benchmark's down days over the benchmark's, below 1 lost less.
+    pub down_capture: Option<f64>,
+    /// Largest fall of the strategy's value relative to the benchmark.
+    pub relative_drawdown: Option<f64>,
+}
+
+impl Comparison {
+    /// Compares `equity` to the closes of `benchmark`, the metrics are `None` with less than two
+    /// common days or where the benchmark didn't move.
+    pub fn of(name: &str, equity: &TimeSeries, benchmark: &TimeSeries, risk_free_rate: f64) -> Self {
+        let frame = Frame::align(&[equity, benchmark], Join::Inner, Fill::None);
+        let (values, prices) = (closes(&frame.column(0)), closes(&frame.column(1)));
+        let mut comparison = Comparison { benchmark: String::from(name), ..Comparison::default() };
+        if values.len() < 2 {
+            return comparison;
+        }
+        let (r, b) = (simple_returns(&values), simple_returns(&prices));
+
+        let (mean_r, mean_b) = (mean(&r), mean(&b));
+        let covariance = r.iter().zip(&b).map(|(r, b)| (r - mean_r) * (b - mean_b)).sum::<f64>() / (r.len() - 1) as f64;
+        comparison.beta = standard_deviation(&b).filter(|sd| *sd > 0.0).map(|sd| covariance / sd.powi(2));
+        comparison.alpha = comparison.beta.map(|beta| {
+            (mean(&excess_returns(&r, risk_free_rate)) - beta * mean(&excess_returns(&b, risk_free_rate))) * TRADING_DAYS
+        });
+
+        let difference: Vec<f64> = r.iter().zip(&b).map(|(r, b)| r - b).collect();
+        comparison.tracking_error = standard_deviation(&difference).map(|sd| sd * TRADING_DAYS.sqrt());
+        comparison.information_ratio = comparison.tracking_error.filter(|te| *te > 0.0).map(|te| mean(&difference) * TRADING_DAYS / te);
+
+        let capture = |up: bool| {
+            let days: Vec<(f64, f64)> = r.iter().zip(&b).filter(|(_, b)| if up { **b > 0.0 } else { **b < 0.0 }).map(|(r, b)| (*r, *b)).collect();
+            let benchmark = mean(&days.iter().map(|x| x.1).collect::<Vec<f64>>());
+            Some(mean(&days.iter().map(|x| x.0).collect::<Vec<f64>>()) / benchmark).filter(|_| !days.is_empty())
+        };
+        comparison.up_capture = capture(true);
+        comparison.down_capture = capture(false);
+
+        let relative: Vec<f64> = values.iter().zip(&prices).map(|(v, p)| v / p).collect();
+        let mut peak = f64::NEG_INFINITY;
+        comparison.relative_drawdown = relative.iter().map(|x| {
+            peak = peak.max(*x);
+            1.0 - x / peak
+        }).reduce(f64::max);
+        comparison
+    }
+}
+
+/// One indented line, meant to follow the metrics.
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}%", x * 100.0));
+        let ratio = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}", x));
+        write!(f, "    vs {}: alpha {}, beta {}, tracking error {}, information ratio {}, capture {} up / {} down, relative drawdown {}",
+            self.benchmark, percent(self.alpha), ratio(self.beta), percent(self.tracking_error), ratio(self.information_ratio),
+            percent(self.up_capture), percent(self.down_capture), percent(self.relative_drawdown))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::{Duration, NaiveDate};
+    use mysql_common::bigdecimal::{BigDecimal, FromPrimitive};
+
+    use crate::timeseries::{Bar, TimeSeries};
+
+    use super::Comparison;
+
+    fn from_closes(closes: &[f64]) -> TimeSeries {
+        let mut ts = TimeSeries::new("test");
+        for (i, c) in closes.iter().enumerate() {
+            ts.insert(NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + Duration::days(i as i64), Bar::from_close(BigDecimal::from_f64(*c).unwrap()));
+        }
+        ts
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn compares_to_the_benchmark() {
+        let benchmark = from_closes(&[100.0, 110.0, 99.0, 108.9, 98.01]);
+        // Half the benchmark's moves
+        let half = from_closes(&[1000.0, 1050.0, 997.5, 1047.375, 995.00625]);
+        let c = Comparison::of("spy", &half, &benchmark, 0.0);
+        assert_close(c.beta, 0.5);
+        assert_close(c.up_capture, 0.5);
+        assert_close(c.down_capture, 0.5);
+        assert_close(c.alpha, 0.0);
+        // Behind after every up day of the benchmark
+        assert_close(c.relative_drawdown, 1.0 - 105.0 / 110.0);
+
+        let same = Comparison::of("own", &benchmark, &benchmark, 0.02);
+        assert_close(same.beta, 1.0);
+        assert_close(same.tracking_error, 0.0);
+        assert_close(same.relative_drawdown, 0.0);
+        assert_eq!(same.information_ratio, None);
+
+        let empty = Comparison::of("spy", &TimeSeries::default(), &benchmark, 0.0);
+        assert_eq!((empty.benchmark.as_str(), empty.beta), ("spy", None));
+    }
+}
@@ -1,136 +1,865 @@
-use mysql::chrono::NaiveDate;
-use mysql_common::bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
-use crate::{config::Config, mysql_db::Database};
+use std::{collections::{BTreeMap, VecDeque}, fmt, io::{BufWriter, Write}};
+
+use mysql::chrono::{Datelike, NaiveDate};
+use mysql_common::bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+use crate::{analytics::{self, Stats, TRADING_DAYS}, benchmark::Comparison, config::Config, costs::{Costs, Slippage}, database::{Database, SeriesKind}, execution::{Execution, Timing}, indicators, ledger::{self, LedgerEntry, OrderType, RoundTrip, Side}, numeric::{self, NumericPolicy, Rounding, MONEY_SCALE}, persist::Persist, savings::SavingsPlan, sizing::{OrderRules, Sizing}, stops::{StopOrder, StopRule}, strategy::{Context, Signal, Strategy, StrategySpec}, tax::{Lot, TaxModel, TaxYear}, timeseries::{Bar, BarRef, Frequency, TimeSeries}};
 
 pub struct Backtest {
-    start_depot: BigDecimal,
-    avg200_range: f32,
+    pub(crate) start_depot: BigDecimal,
+    pub(crate) avg200_range: BigDecimal,
+    pub(crate) numeric: NumericPolicy,
+    pub(crate) frequency: Frequency,
+    costs: Costs,
+    tax: TaxModel,
+    orders: OrderRules,
+    pub(crate) execution: Execution,
+    stops: Vec<StopRule>,
+    lot_sizes: BTreeMap<String, u32>,
+    pub(crate) risk_free_rate: f64,
+    /// Symbol the results are compared to, each symbol's own buy-and-hold without one.
+    pub(crate) benchmark: Option<String>,
+    strategies: Vec<StrategySpec>,
+    stock_strategies: BTreeMap<String, Vec<StrategySpec>>,
 }
 
 impl Backtest {
     pub fn from_config(config: &Config) -> Self {
         Backtest {
             start_depot: config.start_depot.clone(),
-            avg200_range: config.avg200_range,
+            avg200_range: config.avg200_range.clone(),
+            numeric: config.numeric.clone(),
+            frequency: config.frequency,
+            costs: config.costs.clone(),
+            tax: config.tax.clone(),
+            orders: config.orders.clone(),
+            execution: config.execution,
+            stops: config.stops.clone(),
+            lot_sizes: config.lot_sizes.clone(),
+            risk_free_rate: config.risk_free_rate,
+            benchmark: config.benchmark.clone(),
+            strategies: config.strategies.clone(),
+            stock_strategies: config.stock_strategies.clone(),
         }
     }
 
-    pub fn full_test(&self, db: &mut Database, symbol: &str, start_date: NaiveDate, end_date: NaiveDate) {
-        println!("Backtesting {}", symbol);
-        let mut depot = Depot::new(&self.start_depot);
-        backtest_normal(db, symbol, &mut depot, start_date, end_date);
-        println!("Buy-And-Hold Endvalue: {}€", depot.money);
+    /// Without costs, taxes, stops and benchmark, for tests of what is built on backtests.
+    #[cfg(test)]
+    pub(crate) fn plain(start_depot: &BigDecimal) -> Self {
+        Backtest {
+            start_depot: start_depot.clone(),
+            avg200_range: BigDecimal::from(1),
+            numeric: NumericPolicy::default(),
+            frequency: Frequency::Daily,
+            costs: Costs::default(),
+            tax: TaxModel::default(),
+            orders: OrderRules::default(),
+            execution: Execution::default(),
+            stops: Vec::new(),
+            lot_sizes: BTreeMap::new(),
+            risk_free_rate: 0.0,
+            benchmark: None,
+            strategies: StrategySpec::defaults(),
+            stock_strategies: BTreeMap::new(),
+        }
+    }
 
-        let mut depot = Depot::new(&self.start_depot);
-        backtest_avg200(db, symbol, &mut depot, start_date, end_date);
-        println!("200 Average Endvalue: {}€", depot.money);
+    /// The strategies configured for `symbol`, the general ones if it has none of its own.
+    pub fn strategies_for(&self, symbol: &str) -> &[StrategySpec] {
+        self.stock_strategies.get(symbol).unwrap_or(&self.strategies)
+    }
 
-        let mut depot = Depot::new(&self.start_depot);
-        backtest_avg200_range(db, symbol, &mut depot, start_date, end_date, self.avg200_range);
-        println!("200 Average Range Endvalue: {}€", depot.money);
-        println!("");
+    /// The order rules for `symbol`, traded in lots if it has a lot size.
+    pub fn orders_for(&self, symbol: &str) -> OrderRules {
+        match self.lot_sizes.get(symbol) {
+            Some(size) => OrderRules { sizing: Sizing::Lots(*size), ..self.orders.clone() },
+            None => self.orders.clone(),
+        }
+    }
+
+    /// An empty depot with the configured costs, taxes, order rules, execution and stops.
+    pub(crate) fn depot(&self, symbol: &str) -> Depot {
+        Depot::new(&self.start_depot).with_costs(self.costs.clone()).with_tax(self.tax.clone()).with_orders(self.orders_for(symbol)).with_execution(self.execution).with_stops(self.stops.clone())
+    }
+
+    /// The adjusted series of the configured benchmark, `None` without one or when it has no data
+    /// between the dates.
+    pub fn configured_benchmark(&self, db: &mut dyn Database, start_date: NaiveDate, end_date: NaiveDate) -> Option<(String, TimeSeries)> {
+        let benchmark = self.benchmark.as_ref()?;
+        db.create_tables(benchmark);
+        let ts = db.get_timeseries_between(benchmark, &SeriesKind::Adjusted.table_name(benchmark), start_date, end_date);
+        Some((benchmark.clone(), ts)).filter(|(_, ts)| !ts.is_empty())
+    }
+
+    /// What results on `symbol` are compared to: the configured benchmark, or `ts`, the symbol's own
+    /// buy-and-hold, without one. `None` if the configured benchmark has no data.
+    pub fn benchmark_for(&self, db: &mut dyn Database, symbol: &str, ts: &TimeSeries, start_date: NaiveDate, end_date: NaiveDate) -> Option<(String, TimeSeries)> {
+        match &self.benchmark {
+            Some(_) => self.configured_benchmark(db, start_date, end_date),
+            None => Some((format!("{} buy_and_hold", symbol), ts.clone())),
+        }
+    }
+
+    pub fn full_test(&self, db: &mut dyn Database, symbol: &str, start_date: NaiveDate, end_date: NaiveDate) -> Vec<BacktestResult> {
+        let ts = db.get_timeseries_between(symbol, &format!("{}_adjusted", symbol), start_date, end_date);
+        let ts_200 = db.get_timeseries_between(symbol, &format!("{}_200avg", symbol), start_date, end_date);
+        let benchmark = self.benchmark_for(db, symbol, &ts, start_date, end_date);
+
+        let mut results = Vec::new();
+        for spec in self.strategies_for(symbol) {
+            let mut strategy = spec.build(&self.avg200_range, &self.numeric);
+            let mut depot = self.depot(symbol);
+            let run = run(strategy.as_mut(), &ts, &ts_200, self.frequency, None, &mut depot);
+            // Taxes take money out of the depot, so the pre-tax value needs a run of its own
+            let pre_tax = if self.tax.is_active() {
+                let mut untaxed = self.depot(symbol).with_tax(TaxModel::default());
+                self::run(spec.build(&self.avg200_range, &self.numeric).as_mut(), &ts, &ts_200, self.frequency, None, &mut untaxed);
+                untaxed.money
+            } else {
+                depot.money.clone()
+            };
+            // The same run filled at the signal's close shows what the look-ahead is worth
+            let same_close = if self.execution.looks_ahead() {
+                None
+            } else {
+                let mut biased = self.depot(symbol).with_execution(Execution::default());
+                self::run(spec.build(&self.avg200_range, &self.numeric).as_mut(), &ts, &ts_200, self.frequency, None, &mut biased);
+                Some(biased.money)
+            };
+            results.push(BacktestResult {
+                symbol: String::from(symbol),
+                strategy: strategy.name(),
+                start_date,
+                end_date,
+                start_money: self.start_depot.clone(),
+                pre_tax,
+                same_close,
+                metrics: Metrics::of(&self.start_depot, &run.equity, &ledger::round_trips(&depot.ledger), run.exposure, self.risk_free_rate),
+                benchmark: benchmark.as_ref().map(|(name, benchmark)| Comparison::of(name, &run.equity, benchmark, self.risk_free_rate)),
+                equity: run.equity,
+                depot,
+            });
+        }
+        results
     }
 }
 
-fn backtest_normal(db: &mut Database, symbol: &str, depot: &mut Depot, start_date: NaiveDate, end_date: NaiveDate) {
-    let ts = db.get_timeseries_between(symbol, &format!("{}_adjusted", symbol), start_date, end_date);
+/// Outcome of one strategy on one symbol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BacktestResult {
+    pub symbol: String,
+    pub strategy: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    #[serde(with = "crate::persist::decimal")]
+    pub start_money: BigDecimal,
+    /// End money of the same run without taxes.
+    #[serde(with = "crate::persist::decimal")]
+    pub pre_tax: BigDecimal,
+    /// End money of the same run filled at the close of the signal's bar, `None` if it already was.
+    #[serde(with = "crate::persist::option_decimal")]
+    pub same_close: Option<BigDecimal>,
+    /// Value of the depot at every day's close, the last day after everything is sold.
+    pub equity: TimeSeries,
+    pub metrics: Metrics,
+    /// `None` when the configured benchmark has no data.
+    pub benchmark: Option<Comparison>,
+    /// The depot after the last day, everything sold.
+    pub depot: Depot,
+}
+
+impl fmt::Display for BacktestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} Endvalue: {}€ pre-tax, {}€ after tax (fees {}€, slippage {}€, taxes {}€)", self.strategy, self.pre_tax, self.depot.money, self.depot.fees, self.depot.slippage, self.depot.taxes)?;
+        if let Some(same_close) = &self.same_close {
+            write!(f, ", {}€ filled at the signal's close", same_close)?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.metrics)?;
+        if let Some(benchmark) = &self.benchmark {
+            write!(f, "\n{}", benchmark)?;
+        }
+        Ok(())
+    }
+}
 
-    let first_day = ts.entries.iter().nth(0).unwrap();
-    let last_day = ts.entries.iter().last().unwrap();
-    depot.full_buy(&first_day.1.0);
-    depot.full_sell(&last_day.1.0);
+/// Two indented lines, meant to follow a summary line.
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self;
+        let percent = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}%", x * 100.0));
+        let ratio = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}", x));
+        writeln!(f, "    return {:.2}%, CAGR {:.2}%, volatility {}, max drawdown {:.2}%, Sharpe {}, Sortino {}, Calmar {}",
+            m.total_return * 100.0, m.cagr * 100.0, percent(m.volatility), m.max_drawdown * 100.0, ratio(m.sharpe), ratio(m.sortino), ratio(m.calmar))?;
+        write!(f, "    exposure {:.2}%, {} trades, win rate {}, profit factor {}", m.exposure * 100.0, m.trades, percent(m.win_rate), ratio(m.profit_factor))
+    }
 }
 
-fn backtest_avg200(db: &mut Database, symbol: &str, depot: &mut Depot, start_date: NaiveDate, end_date: NaiveDate) {
-    let ts = db.get_timeseries_between(symbol, &format!("{}_adjusted", symbol), start_date, end_date);
-    let ts_200 = db.get_timeseries_between(symbol, &format!("{}_200avg", symbol), start_date, end_date);
+/// Performance of one backtest, the returns are of the equity curve.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    pub total_return: f64,
+    pub cagr: f64,
+    pub volatility: Option<f64>,
+    pub max_drawdown: f64,
+    pub sharpe: Option<f64>,
+    pub sortino: Option<f64>,
+    /// CAGR over the maximum drawdown.
+    pub calmar: Option<f64>,
+    /// Share of the days with shares in the depot.
+    pub exposure: f64,
+    /// Closed round trips.
+    pub trades: usize,
+    pub win_rate: Option<f64>,
+    /// Profits of the winning round trips over the losses of the losing ones.
+    pub profit_factor: Option<f64>,
+}
 
-    for day in &ts.entries {
-        let day_200 = match ts_200.entries.get(&day.0) {
-            Some(s) => s,
-            None => continue,
+impl Metrics {
+    /// Returns count from `start_money`, the first day of the curve already paid its fees.
+    pub fn of(start_money: &BigDecimal, equity: &TimeSeries, trips: &[RoundTrip], exposure: f64, risk_free_rate: f64) -> Self {
+        let profit: BigDecimal = trips.iter().filter(|t| t.pnl > BigDecimal::zero()).map(|t| t.pnl.clone()).sum();
+        let loss: BigDecimal = trips.iter().filter(|t| t.pnl < BigDecimal::zero()).map(|t| -t.pnl.clone()).sum();
+        let won = trips.iter().filter(|t| t.pnl > BigDecimal::zero()).count();
+        let mut metrics = Metrics {
+            exposure,
+            trades: trips.len(),
+            win_rate: Some(won as f64 / trips.len() as f64).filter(|_| !trips.is_empty()),
+            profit_factor: if loss.is_zero() { None } else { (&profit / &loss).to_f64() },
+            ..Metrics::default()
         };
-        if day.1.0 > day_200.0 {
-            depot.full_buy(&day.1.0);
-        } else {
-            depot.full_sell(&day.1.0);
+        if let Some(stats) = Stats::of(equity, risk_free_rate) {
+            metrics.total_return = (equity.last().unwrap().1.close / start_money).to_f64().unwrap() - 1.0;
+            metrics.cagr = (1.0 + metrics.total_return).powf(TRADING_DAYS / (equity.len() - 1) as f64) - 1.0;
+            metrics.volatility = stats.volatility;
+            metrics.max_drawdown = stats.drawdown.max_drawdown;
+            metrics.sharpe = stats.sharpe;
+            metrics.sortino = stats.sortino;
+            metrics.calmar = Some(metrics.cagr / stats.drawdown.max_drawdown).filter(|_| stats.drawdown.max_drawdown > 0.0);
         }
-        
+        metrics
     }
-    depot.full_sell(&ts.entries.into_iter().last().unwrap().1.0);
 }
 
-fn backtest_avg200_range(db: &mut Database, symbol: &str, depot: &mut Depot, start_date: NaiveDate, end_date: NaiveDate, range: f32) {
-    let ts = db.get_timeseries_between(symbol, &format!("{}_adjusted", symbol), start_date, end_date);
-    let ts_200 = db.get_timeseries_between(symbol, &format!("{}_200avg", symbol), start_date, end_date);
+impl Persist for BacktestResult {
+    const KIND: &'static str = "backtest_result";
+    const SINCE: u32 = 15;
+}
 
-    for day in &ts.entries {
-        let day_200 = match ts_200.entries.get(&day.0) {
-            Some(s) => s,
-            None => continue,
-        };
-        if &day.1.0/&day_200.0 > BigDecimal::from_f32(range).unwrap() {
-            depot.full_buy(&day.1.0);
-        } else if  &day.1.0/&day_200.0 < BigDecimal::from_f32(range).unwrap() {
-            depot.full_sell(&day.1.0);
+impl Persist for Vec<BacktestResult> {
+    const KIND: &'static str = "backtest_results";
+    const SINCE: u32 = 15;
+}
+
+/// Equity curve of a run, the share of days it held shares and what a savings plan paid in.
+pub struct Run {
+    pub equity: TimeSeries,
+    pub exposure: f64,
+    /// Contributions by date.
+    pub flows: Vec<(NaiveDate, BigDecimal)>,
+}
+
+/// Feeds the bars of `ts` at `frequency` to the strategy and trades its signals with the depot's
+/// execution, from the close of the bar's last day on, and its costs. The depot is valued at every
+/// day's close. Whatever is held after the last day is sold, orders not filled by then are dropped.
+/// The depot's stop orders are checked every day, after a fill at the open and before the strategy.
+/// After a stop, buys waiting for their fill are dropped and the strategy's buys are ignored until it
+/// signals to sell. With a savings plan cash earns interest and contributions are paid in before the
+/// signal. They are invested by an order placed after the signal, filled with the execution's timing
+/// and delay while the last filled order was a buy.
+pub fn run(strategy: &mut dyn Strategy, ts: &TimeSeries, ts_200: &TimeSeries, frequency: Frequency, plan: Option<&SavingsPlan>, depot: &mut Depot) -> Run {
+    let mut state = RunState { plan: plan.cloned(), ..RunState::default() };
+    let mut run = run_days(strategy, ts, ts_200, frequency, depot, &mut state);
+    if let Some((date, last)) = ts.last() {
+        depot.full_sell(last.close, &mut state);
+        run.equity.insert(*date, Bar::from_close(depot.money.clone()));
+    }
+    run
+}
+
+/// [`run`] without the sale after the last day, what is held stays in the depot and the equity curve
+/// ends with its value. Orders not filled by then are dropped all the same.
+pub fn run_open(strategy: &mut dyn Strategy, ts: &TimeSeries, ts_200: &TimeSeries, frequency: Frequency, plan: Option<&SavingsPlan>, depot: &mut Depot) -> Run {
+    let mut state = RunState { plan: plan.cloned(), ..RunState::default() };
+    run_days(strategy, ts, ts_200, frequency, depot, &mut state)
+}
+
+/// The days of [`run`] and [`run_open`].
+fn run_days(strategy: &mut dyn Strategy, ts: &TimeSeries, ts_200: &TimeSeries, frequency: Frequency, depot: &mut Depot, state: &mut RunState) -> Run {
+    let bars = ts.resample(frequency);
+    let bars_200 = ts_200.resample(frequency);
+    let indicators: Vec<(String, TimeSeries)> = strategy.indicators().iter().flat_map(|x| x.compute(&bars)).collect();
+    let volatility = match depot.costs.slippage {
+        Slippage::Volatility(_, window) => analytics::rolling_volatility(&bars, window),
+        _ => TimeSeries::default(),
+    };
+    let atr: Vec<(usize, TimeSeries)> = depot.stops.iter()
+        .filter_map(|rule| match rule {
+            StopRule::Atr(window, _) => Some((*window, indicators::atr(ts, *window))),
+            _ => None,
+        })
+        .collect();
+    let mut equity = TimeSeries::new(&ts.equity_name);
+    let mut invested_days = 0;
+    let mut orders = VecDeque::new();
+    let mut stopped = false;
+    let mut contributions = state.plan.as_ref().map_or(Vec::new(), |plan| plan.dates(ts)).into_iter().peekable();
+    let mut flows = Vec::new();
+    // Fraction of the last filled order
+    let mut target = BigDecimal::zero();
+    for (day_index, (date, day)) in ts.iter().enumerate() {
+        state.date = *date;
+        if let Some(plan) = state.plan.as_ref().filter(|_| day_index > 0 && depot.money > BigDecimal::zero()) {
+            let interest = numeric::round(&plan.interest(&depot.money), MONEY_SCALE, Rounding::HalfUp);
+            depot.money += &interest;
+            depot.interest += interest;
+        }
+        // Orders of earlier days fill before this day's signal, at the open if that is the timing
+        if depot.execution.timing == Timing::NextOpen {
+            fill_orders(depot, &mut orders, day_index, day, &mut target, state);
+        }
+        if depot.check_stops(day, state).is_some() {
+            stopped = true;
+            target = BigDecimal::zero();
+            // Buys signalled before the stop would undo it
+            orders.retain(|order| order.fraction.as_ref().is_none_or(|fraction| fraction <= &BigDecimal::zero()));
+        }
+        let contribution = state.plan.as_ref().filter(|_| contributions.next_if_eq(date).is_some()).map(|plan| plan.amount.clone());
+        if let Some(amount) = &contribution {
+            depot.money += amount;
+            flows.push((*date, amount.clone()));
+        }
+        fill_orders(depot, &mut orders, day_index, day, &mut target, state);
+        state.atr = atr.iter().filter_map(|(window, atr)| Some((*window, atr.get(date)?.close.clone()))).collect();
+        depot.place_waiting(state);
+        // Back from a yearly to a per bar standard deviation
+        let bar_volatility = volatility.get(date).and_then(|x| x.close.to_f64()).map(|x| x / TRADING_DAYS.sqrt());
+        // Resampled bars carry the date of their last day
+        if let Ok(index) = bars.position(date) {
+            let bar = bars.bar(index);
+            let context = Context {
+                date: *date,
+                bar,
+                avg200: bars_200.get(date),
+                shares: depot.shares.clone(),
+                index,
+                series: &bars,
+                indicators: &indicators,
+            };
+            match strategy.next(&context) {
+                Signal::Target(fraction) if stopped && fraction > BigDecimal::zero() => {}
+                Signal::Target(fraction) => {
+                    stopped = false;
+                    orders.push_back(Order { day: day_index + depot.execution.offset(), fraction: Some(fraction), slippage_rate: depot.costs.slippage_rate(bar_volatility) });
+                }
+                Signal::Hold => {}
+            }
+        }
+        if contribution.is_some() {
+            orders.push_back(Order { day: day_index + depot.execution.offset(), fraction: None, slippage_rate: depot.costs.slippage_rate(bar_volatility) });
+        }
+        fill_orders(depot, &mut orders, day_index, day, &mut target, state);
+        if depot.shares > BigDecimal::zero() {
+            invested_days += 1;
         }
-        
+        equity.insert(*date, Bar::from_close(depot.value(day.close)));
     }
-    depot.full_sell(&ts.entries.into_iter().last().unwrap().1.0);
+    Run {
+        exposure: if ts.is_empty() { 0.0 } else { invested_days as f64 / ts.len() as f64 },
+        equity,
+        flows,
+    }
+}
+
+/// A signal waiting for its fill.
+struct Order {
+    /// Index of the day in the daily series.
+    day: usize,
+    /// `None` for a contribution, invested at the fraction of the last filled order.
+    fraction: Option<BigDecimal>,
+    /// Slippage of the signal's bar.
+    slippage_rate: BigDecimal,
+}
+
+/// What a run keeps next to the depot for its fills and stops.
+#[derive(Debug, Clone, Default)]
+pub struct RunState {
+    /// Date of the current bar. Gains are taxed in its year.
+    pub date: NaiveDate,
+    /// Slippage of the order being filled as share of the price.
+    pub slippage_rate: BigDecimal,
+    /// Average true range per window as of the last day, for ATR stops.
+    pub atr: BTreeMap<usize, BigDecimal>,
+    /// ATR stops of the open position whose ATR didn't exist yet at the entry, with the entry price.
+    pub waiting: Vec<(BigDecimal, StopRule)>,
+    /// Contributions and interest on cash.
+    pub plan: Option<SavingsPlan>,
 }
 
+/// Fills the orders due on `day_index`, `target` is the fraction of the last filled one.
+fn fill_orders(depot: &mut Depot, orders: &mut VecDeque<Order>, day_index: usize, day: BarRef, target: &mut BigDecimal, state: &mut RunState) {
+    while orders.front().is_some_and(|order| order.day == day_index) {
+        let order = orders.pop_front().unwrap();
+        state.slippage_rate = order.slippage_rate;
+        let price = depot.execution.price(day);
+        match order.fraction {
+            Some(fraction) => {
+                depot.target(&fraction, price, state);
+                *target = fraction;
+            }
+            None if *target > BigDecimal::zero() => depot.target(target, price, state),
+            None => {}
+        }
+    }
+}
 
+/// Writes an equity curve as `date,equity` rows.
+pub fn write_equity_csv<W: Write>(out: W, equity: &TimeSeries) {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "date,equity").unwrap();
+    for (date, bar) in equity.iter() {
+        writeln!(out, "{},{}", date, bar.close).expect("Couldn't write csv row!");
+    }
+    out.flush().unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Depot {
+    #[serde(with = "crate::persist::decimal")]
     pub money: BigDecimal,
-    pub shares: u32,
+    /// Whole or fractional, depending on the order rules.
+    #[serde(with = "crate::persist::decimal")]
+    pub shares: BigDecimal,
+    /// Fees paid on all orders.
+    #[serde(with = "crate::persist::decimal")]
+    pub fees: BigDecimal,
+    /// What filling at a worse price than the close cost.
+    #[serde(with = "crate::persist::decimal")]
+    pub slippage: BigDecimal,
+    /// Capital gains tax paid, refunds for offset losses subtracted.
+    #[serde(with = "crate::persist::decimal")]
+    pub taxes: BigDecimal,
+    /// Paid on cash by a savings plan.
+    #[serde(with = "crate::persist::decimal")]
+    pub interest: BigDecimal,
+    /// The shares held with what they cost.
+    pub lots: VecDeque<Lot>,
+    /// Realized gains and their tax per calendar year.
+    pub tax_years: BTreeMap<i32, TaxYear>,
+    /// Every fill, oldest first.
+    pub ledger: Vec<LedgerEntry>,
+    #[serde(skip)]
+    pub costs: Costs,
+    #[serde(skip)]
+    pub tax: TaxModel,
+    #[serde(skip)]
+    pub orders: OrderRules,
+    #[serde(skip)]
+    pub execution: Execution,
+    /// Placed as stop orders with every new position.
+    #[serde(skip)]
+    pub stops: Vec<StopRule>,
+    /// Stop orders of the open position.
+    #[serde(skip)]
+    pub pending: Vec<StopOrder>,
 }
 
 impl Depot {
     pub fn new(money: &BigDecimal) -> Self {
         Depot {
             money: money.clone(),
-            shares: 0,
+            shares: BigDecimal::zero(),
+            fees: BigDecimal::zero(),
+            slippage: BigDecimal::zero(),
+            taxes: BigDecimal::zero(),
+            interest: BigDecimal::zero(),
+            lots: VecDeque::new(),
+            tax_years: BTreeMap::new(),
+            ledger: Vec::new(),
+            costs: Costs::default(),
+            tax: TaxModel::default(),
+            orders: OrderRules::default(),
+            execution: Execution::default(),
+            stops: Vec::new(),
+            pending: Vec::new(),
         }
     }
 
-    pub fn buy(&mut self, amount: u32, price: &BigDecimal) {
-        self.money -= BigDecimal::from(amount) * price;
-        self.shares += amount;
+    pub fn with_costs(mut self, costs: Costs) -> Self {
+        self.costs = costs;
+        self
+    }
+
+    pub fn with_tax(mut self, tax: TaxModel) -> Self {
+        self.tax = tax;
+        self
+    }
+
+    pub fn with_orders(mut self, orders: OrderRules) -> Self {
+        self.orders = orders;
+        self
+    }
+
+    pub fn with_execution(mut self, execution: Execution) -> Self {
+        self.execution = execution;
+        self
+    }
+
+    pub fn with_stops(mut self, stops: Vec<StopRule>) -> Self {
+        self.stops = stops;
+        self
     }
 
-    pub fn sell(&mut self, amount: u32, price: &BigDecimal) {
-        self.money += BigDecimal::from(amount) * price;
-        self.shares -= amount;
+    /// Buys `amount` shares, rounded down to the sizing. Orders below the minimum value are dropped.
+    /// A buy into an empty depot places the stop orders, priced from `price`. ATR stops without an
+    /// ATR yet wait for [`Depot::place_waiting`].
+    pub fn buy(&mut self, amount: &BigDecimal, price: &BigDecimal, state: &mut RunState) {
+        let amount = self.orders.sizing.round_down(amount);
+        let value = self.fill_value(&amount, price, true, state);
+        if !self.orders.allows(&value) {
+            return;
+        }
+        let fee = self.costs.fee(&value);
+        self.slippage += &value - self.close_value(&amount, price);
+        self.tax.add_lot(&mut self.lots, &amount, &value);
+        self.money -= &value + &fee;
+        self.fees += &fee;
+        if self.shares.is_zero() {
+            self.pending.clear();
+            state.waiting = self.stops.iter().map(|rule| (price.clone(), rule.clone())).collect();
+            self.place_waiting(state);
+        }
+        self.shares += &amount;
+        self.record(state.date, Side::Buy, amount, price, value, fee, BigDecimal::zero(), OrderType::Signal);
     }
 
-    pub fn full_buy(&mut self, price: &BigDecimal) {
-        let amount = (&self.money / price).to_u32().unwrap();
-        self.buy(amount, price);
+    /// Sells `amount` shares, rounded down to the sizing unless it is everything held. Partial sells
+    /// below the minimum value are dropped.
+    pub fn sell(&mut self, amount: &BigDecimal, price: &BigDecimal, state: &mut RunState) {
+        self.sell_as(amount, price, OrderType::Signal, state);
     }
 
-    pub fn full_sell(&mut self, price: &BigDecimal) {
-        let amount = self.shares;
-        self.sell(amount, price);
+    fn sell_as(&mut self, amount: &BigDecimal, price: &BigDecimal, order: OrderType, state: &mut RunState) {
+        let amount = if amount >= &self.shares { self.shares.clone() } else { self.orders.sizing.round_down(amount) };
+        let value = self.fill_value(&amount, price, false, state);
+        if amount.is_zero() || (amount < self.shares && !self.orders.allows(&value)) {
+            return;
+        }
+        let fee = self.costs.fee(&value);
+        self.slippage += self.close_value(&amount, price) - &value;
+        let gain = &value - self.tax.take(&mut self.lots, &amount);
+        let tax = self.tax.realize(self.tax_years.entry(state.date.year()).or_default(), &gain);
+        self.money += &value - &fee - &tax;
+        self.fees += &fee;
+        self.taxes += &tax;
+        self.shares -= &amount;
+        if self.shares.is_zero() {
+            self.pending.clear();
+            state.waiting.clear();
+        }
+        self.record(state.date, Side::Sell, amount, price, value, fee, tax, order);
     }
+
+    pub fn full_buy(&mut self, price: &BigDecimal, state: &mut RunState) {
+        let amount = self.affordable(price, state);
+        self.buy(&amount, price, state);
+    }
+
+    pub fn full_sell(&mut self, price: &BigDecimal, state: &mut RunState) {
+        let amount = self.shares.clone();
+        self.sell(&amount, price, state);
+    }
+
+    /// Places the stop orders that have their ATR by now, priced from the entry.
+    pub fn place_waiting(&mut self, state: &mut RunState) {
+        for (entry, rule) in std::mem::take(&mut state.waiting) {
+            match rule.place(&entry, &state.atr) {
+                Some(order) => self.pending.push(order),
+                None => state.waiting.push((entry, rule)),
+            }
+        }
+    }
+
+    /// Sells everything if a stop order triggers on the day of `bar`, a stop before a take-profit.
+    /// Otherwise trailing stops follow the day's high.
+    pub fn check_stops(&mut self, bar: BarRef, state: &mut RunState) -> Option<OrderType> {
+        let triggered = |take_profit: bool| self.pending.iter()
+            .filter(move |o| (o.order == OrderType::TakeProfit) == take_profit)
+            .filter_map(|o| o.trigger(bar).map(|price| (o.order, price)));
+        // Falling through several stops hits the highest first, rising through take-profits the lowest
+        let fill = triggered(false).max_by(|a, b| a.1.cmp(&b.1)).or_else(|| triggered(true).min_by(|a, b| a.1.cmp(&b.1)));
+        match fill {
+            Some((order, price)) => {
+                let amount = self.shares.clone();
+                self.sell_as(&amount, &price, order, state);
+                Some(order)
+            }
+            None => {
+                self.pending.iter_mut().for_each(|o| o.trail(bar));
+                None
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record(&mut self, date: NaiveDate, side: Side, shares: BigDecimal, price: &BigDecimal, value: BigDecimal, fee: BigDecimal, tax: BigDecimal, order: OrderType) {
+        self.ledger.push(LedgerEntry {
+            date,
+            side,
+            shares,
+            price: price.clone(),
+            value,
+            fee,
+            tax,
+            cash: self.money.clone(),
+            sizing: self.orders.sizing,
+            order,
+        });
+    }
+
+    /// Value of an order after slippage, in cents. Buys fill above the close and sells below.
+    pub fn fill_value(&self, amount: &BigDecimal, price: &BigDecimal, buy: bool, state: &RunState) -> BigDecimal {
+        let slippage = if buy { BigDecimal::from(1) + &state.slippage_rate } else { BigDecimal::from(1) - &state.slippage_rate };
+        numeric::round(&(amount * price * slippage), MONEY_SCALE, Rounding::HalfUp)
+    }
+
+    /// Value of an order at the close, in cents.
+    fn close_value(&self, amount: &BigDecimal, price: &BigDecimal) -> BigDecimal {
+        numeric::round(&(amount * price), MONEY_SCALE, Rounding::HalfUp)
+    }
+
+    /// How many shares the money pays for, including slippage and fees, rounded down to the sizing.
+    pub fn affordable(&self, price: &BigDecimal, state: &RunState) -> BigDecimal {
+        let sizing = self.orders.sizing;
+        let fill = price * (BigDecimal::from(1) + &state.slippage_rate);
+        let mut amount = sizing.round_down(&(&self.money / &fill));
+        while amount > BigDecimal::zero() {
+            let value = self.fill_value(&amount, price, true, state);
+            let excess = &value + self.costs.fee(&value) - &self.money;
+            if excess <= BigDecimal::zero() {
+                break;
+            }
+            // Drop as many shares as needed to pay the excess, at least one step
+            let fewer = sizing.round_down(&(&amount - &excess / &fill));
+            amount = if fewer < amount { fewer } else { amount - sizing.step() };
+        }
+        amount.max(BigDecimal::zero())
+    }
+
+    /// Money plus the shares at `price`.
+    pub fn value(&self, price: &BigDecimal) -> BigDecimal {
+        &self.money + &self.shares * price
+    }
+
+    /// Buys or sells until about `fraction` of the value is invested, never more than the money
+    /// allows. With 1 that is a full buy, with 0 a full sell.
+    pub fn target(&mut self, fraction: &BigDecimal, price: &BigDecimal, state: &mut RunState) {
+        let wanted = self.orders.sizing.round_down(&(self.value(price) * fraction / price));
+        if wanted > self.shares {
+            let amount = (&wanted - &self.shares).min(self.affordable(price, state));
+            self.buy(&amount, price, state);
+        } else if wanted < self.shares {
+            let amount = &self.shares - &wanted;
+            self.sell(&amount, price, state);
+        }
+    }
+}
+
+impl Persist for Depot {
+    const KIND: &'static str = "depot";
+    const SINCE: u32 = 14;
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
+    use mysql::chrono::NaiveDate;
     use mysql_common::bigdecimal::BigDecimal;
 
-    use super::Depot;
+    use crate::{costs::{Costs, FeeModel, Slippage}, execution::{Execution, Timing}, ledger::{self, OrderType}, numeric::NumericPolicy, sizing::{OrderRules, Sizing}, stops::StopRule, strategy::{Avg200, StrategySpec}, tax::TaxModel, timeseries::{Bar, Frequency, TimeSeries}};
+
+    use super::{run, Depot, Metrics, RunState};
 
     #[test]
     fn works() {
         let mut depot = Depot::new(&BigDecimal::from(100000));
-        depot.full_buy(&BigDecimal::from(100));
+        let mut state = RunState::default();
+        depot.full_buy(&BigDecimal::from(100), &mut state);
         assert_eq!(depot.money, BigDecimal::from(0));
-        assert_eq!(depot.shares, 1000);
+        assert_eq!(depot.shares, BigDecimal::from(1000));
 
         let mut depot = Depot::new(&BigDecimal::from(100000));
-        depot.full_buy(&BigDecimal::from(342));
+        depot.full_buy(&BigDecimal::from(342), &mut state);
         assert_eq!(depot.money, BigDecimal::from(136));
-        assert_eq!(depot.shares, 292);
+        assert_eq!(depot.shares, BigDecimal::from(292));
+    }
+
+    #[test]
+    fn targets_a_share_of_the_value() {
+        let mut depot = Depot::new(&BigDecimal::from(1000));
+        let mut state = RunState::default();
+        depot.target(&BigDecimal::from_str("0.5").unwrap(), &BigDecimal::from(100), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (BigDecimal::from(500), BigDecimal::from(5)));
+        depot.target(&BigDecimal::from(1), &BigDecimal::from(50), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (BigDecimal::from(0), BigDecimal::from(15)));
+        depot.target(&BigDecimal::from(0), &BigDecimal::from(50), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (BigDecimal::from(750), BigDecimal::from(0)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn pays_fees_and_slippage() {
+        let costs = Costs { fee: FeeModel::Fixed(BigDecimal::from(5)), slippage: Slippage::Bps(BigDecimal::from(100)), ..Costs::default() };
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_costs(costs);
+        let mut state = RunState { slippage_rate: depot.costs.slippage_rate(None), ..RunState::default() };
+        depot.full_buy(&BigDecimal::from(100), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (BigDecimal::from(86), BigDecimal::from(9)));
+        depot.full_sell(&BigDecimal::from(100), &mut state);
+        assert_eq!(depot.money, BigDecimal::from(972));
+        assert_eq!(depot.fees, BigDecimal::from(10));
+        assert_eq!(depot.slippage, BigDecimal::from(18));
+
+        // The fee has to fit next to the shares
+        let costs = Costs { fee: FeeModel::Fixed(BigDecimal::from(5)), ..Costs::default() };
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_costs(costs);
+        depot.full_buy(&BigDecimal::from(10), &mut RunState::default());
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (BigDecimal::from(5), BigDecimal::from(99)));
+    }
+
+    #[test]
+    fn sizes_orders() {
+        let d = |s: &str| BigDecimal::from_str(s).unwrap();
+        let mut depot = Depot::new(&d("1000")).with_orders(OrderRules { sizing: Sizing::Fractional(3), min_value: None });
+        let mut state = RunState::default();
+        depot.full_buy(&d("300"), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (d("0.10"), d("3.333")));
+        assert_eq!(depot.ledger[0].sizing.to_string(), "fractional:3");
+
+        let mut depot = Depot::new(&d("10000")).with_orders(OrderRules { sizing: Sizing::Lots(100), min_value: Some(d("500")) });
+        depot.full_buy(&d("30"), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (d("1000"), d("300")));
+        // Too small to place, but selling everything always works
+        depot.buy(&d("10"), &d("30"), &mut state);
+        depot.sell(&d("150"), &d("3"), &mut state);
+        assert_eq!(depot.shares, d("300"));
+        depot.full_sell(&d("1"), &mut state);
+        assert_eq!((depot.money.clone(), depot.shares.clone()), (d("1300"), d("0")));
+    }
+
+    #[test]
+    fn taxes_realized_gains() {
+        let kest = TaxModel { rate: BigDecimal::from_str("0.275").unwrap(), ..TaxModel::default() };
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_tax(kest);
+        let mut state = RunState { date: NaiveDate::from_ymd_opt(2020, 3, 1).unwrap(), ..RunState::default() };
+        depot.full_buy(&BigDecimal::from(100), &mut state);
+        depot.sell(&BigDecimal::from(5), &BigDecimal::from(150), &mut state);
+        depot.sell(&BigDecimal::from(5), &BigDecimal::from(80), &mut state);
+        // 250 gain and 100 loss in the same year
+        assert_eq!(depot.taxes, BigDecimal::from_str("41.25").unwrap());
+        assert_eq!(depot.money, BigDecimal::from_str("1108.75").unwrap());
+        assert_eq!(depot.tax_years[&2020].gain, BigDecimal::from(150));
+        assert!(depot.lots.is_empty());
+    }
+
+    #[test]
+    fn strategies_trade_their_signals() {
+        let day = |d: u32| NaiveDate::from_ymd_opt(2020, 1, d).unwrap();
+        let mut ts = TimeSeries::new("test");
+        let mut ts_200 = TimeSeries::new("test");
+        for (d, close) in [(1, 100), (2, 110), (3, 104), (4, 120)] {
+            ts.insert(day(d), Bar::from_close(BigDecimal::from(close)));
+            ts_200.insert(day(d), Bar::from_close(BigDecimal::from(100)));
+        }
+
+        let end_value = |spec: &str| {
+            let mut strategy = StrategySpec::from_str(spec).unwrap().build(&BigDecimal::from(1), &NumericPolicy::default());
+            let mut depot = Depot::new(&BigDecimal::from(1000));
+            run(strategy.as_mut(), &ts, &ts_200, Frequency::Daily, None, &mut depot);
+            assert_eq!(depot.shares, BigDecimal::from(0));
+            depot.money
+        };
+        assert_eq!(end_value("buy_and_hold"), BigDecimal::from(1200));
+        assert_eq!(end_value("avg200"), BigDecimal::from(1090));
+        assert_eq!(end_value("avg200_range:1.05"), BigDecimal::from(946));
+
+        let mut depot = Depot::new(&BigDecimal::from(1000));
+        let run = run(&mut Avg200, &ts, &ts_200, Frequency::Daily, None, &mut depot);
+        assert_eq!(run.equity.closes().cloned().collect::<Vec<_>>(), [1000, 1000, 946, 1090].iter().map(|x| BigDecimal::from(*x)).collect::<Vec<_>>());
+        assert_eq!(run.exposure, 0.75);
+        let metrics = Metrics::of(&BigDecimal::from(1000), &run.equity, &ledger::round_trips(&depot.ledger), run.exposure, 0.0);
+        assert!((metrics.total_return - 0.09).abs() < 1e-12);
+        assert!((metrics.max_drawdown - 0.054).abs() < 1e-12);
+        assert_eq!((metrics.trades, metrics.win_rate, metrics.profit_factor), (1, Some(1.0), None));
+
+        // Filled a day later the buy of day 2 pays day 3's close and the sell signal never comes
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_execution(Execution { timing: Timing::NextClose, delay: 0 });
+        super::run(&mut Avg200, &ts, &ts_200, Frequency::Daily, None, &mut depot);
+        assert_eq!(depot.ledger[0].price, BigDecimal::from(104));
+        assert_eq!(depot.money, BigDecimal::from(1144));
+    }
+
+    #[test]
+    fn stops_out_within_the_day() {
+        let day = |d: u32| NaiveDate::from_ymd_opt(2020, 1, d).unwrap();
+        let mut ts = TimeSeries::new("test");
+        let mut ts_200 = TimeSeries::new("test");
+        for (d, close) in [(1, 110), (2, 105), (3, 90), (4, 120)] {
+            ts.insert(day(d), Bar::from_close(BigDecimal::from(close)));
+            ts_200.insert(day(d), Bar::from_close(BigDecimal::from(100)));
+        }
+        ts.insert(day(2), Bar { open: Some(BigDecimal::from(108)), low: Some(BigDecimal::from(95)), ..Bar::from_close(BigDecimal::from(105)) });
+
+        // The stop at 99 sells on day 2, the buy signal of that day is ignored until day 3's sell
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_stops(vec![StopRule::from_str("stop:0.1").unwrap()]);
+        run(&mut Avg200, &ts, &ts_200, Frequency::Daily, None, &mut depot);
+        assert_eq!(depot.ledger.iter().map(|e| e.order).collect::<Vec<_>>(), [OrderType::Signal, OrderType::StopLoss, OrderType::Signal, OrderType::Signal]);
+        assert_eq!(depot.ledger[1].price, BigDecimal::from(99));
+        assert_eq!(depot.money, BigDecimal::from(901));
+        assert!(depot.pending.is_empty());
+
+        let mut depot = Depot::new(&BigDecimal::from(1000));
+        run(&mut Avg200, &ts, &ts_200, Frequency::Daily, None, &mut depot);
+        assert_eq!(depot.money, BigDecimal::from(820));
+    }
+
+    #[test]
+    fn drops_delayed_buys_on_a_stop() {
+        let day = |d: u32| NaiveDate::from_ymd_opt(2020, 1, d).unwrap();
+        let mut ts = TimeSeries::new("test");
+        let mut ts_200 = TimeSeries::new("test");
+        for (d, close) in [(1, 110), (2, 110), (3, 105), (4, 105)] {
+            ts.insert(day(d), Bar::from_close(BigDecimal::from(close)));
+            ts_200.insert(day(d), Bar::from_close(BigDecimal::from(100)));
+        }
+        ts.insert(day(3), Bar { open: Some(BigDecimal::from(104)), low: Some(BigDecimal::from(95)), ..Bar::from_close(BigDecimal::from(105)) });
+
+        // Day 1's buy fills on day 2, day 2's would fill on day 3 after the stop at 99
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_stops(vec![StopRule::from_str("stop:0.1").unwrap()])
+            .with_execution(Execution { timing: Timing::SameClose, delay: 1 });
+        run(&mut Avg200, &ts, &ts_200, Frequency::Daily, None, &mut depot);
+        assert_eq!(depot.ledger.iter().map(|e| e.order).collect::<Vec<_>>(), [OrderType::Signal, OrderType::StopLoss]);
+        assert_eq!(depot.money, BigDecimal::from(901));
+    }
+
+    #[test]
+    fn places_atr_stops_once_the_atr_exists() {
+        let mut depot = Depot::new(&BigDecimal::from(1000)).with_stops(vec![StopRule::from_str("atr:14:2").unwrap(), StopRule::from_str("stop:0.1").unwrap()]);
+        let mut state = RunState::default();
+        depot.full_buy(&BigDecimal::from(100), &mut state);
+        assert_eq!((depot.pending.len(), state.waiting.len()), (1, 1));
+        depot.place_waiting(&mut state);
+        assert_eq!(depot.pending.len(), 1);
+        // Priced from the entry, not from the day the ATR arrives
+        state.atr.insert(14, BigDecimal::from(3));
+        depot.place_waiting(&mut state);
+        assert_eq!(depot.pending.iter().map(|o| o.level.clone()).collect::<Vec<_>>(), [BigDecimal::from(90), BigDecimal::from(94)]);
+        assert!(state.waiting.is_empty());
+
+        state.atr.clear();
+        depot.full_sell(&BigDecimal::from(95), &mut state);
+        depot.full_buy(&BigDecimal::from(95), &mut state);
+        depot.full_sell(&BigDecimal::from(95), &mut state);
+        assert!(depot.pending.is_empty() && state.waiting.is_empty());
+    }
+}
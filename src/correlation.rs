@@ -0,0 +1,245 @@
+//! Correlation and covariance of daily returns across several symbols.
+//!
+//! Returns are taken between the dates all series have in common, so a holiday on one exchange
+//! doesn't pair a two day return with a one day return.
+
+use std::{fmt, io::{BufWriter, Write}, ops::Range};
+
+use mysql::chrono::NaiveDate;
+use plotters::{prelude::{BitMapBackend, ChartBuilder, IntoDrawingArea, IntoSegmentedCoord, Rectangle, SegmentValue, Text}, style::{BLACK, Color, IntoFont, RGBColor, WHITE}};
+
+use crate::{frame::{Fill, Frame, Join}, indicators::to_f64, timeseries::TimeSeries};
+
+/// Daily returns of several series over their common dates, one column per series.
+#[derive(Debug, Clone)]
+pub struct Returns {
+    pub names: Vec<String>,
+    pub dates: Vec<NaiveDate>,
+    pub columns: Vec<Vec<f64>>,
+}
+
+impl Returns {
+    pub fn of(series: &[&TimeSeries]) -> Self {
+        let frame = Frame::align(series, Join::Inner, Fill::None);
+        let rows: Vec<(NaiveDate, Vec<f64>)> = frame.complete_rows().map(|(d, bars)| (*d, bars.iter().map(|b| to_f64(&b.close)).collect())).collect();
+        Returns {
+            names: frame.names,
+            dates: rows.iter().skip(1).map(|x| x.0).collect(),
+            columns: (0..series.len()).map(|i| rows.windows(2).map(|w| w[1].1[i] / w[0].1[i] - 1.0).collect()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dates.is_empty()
+    }
+
+    /// The returns of the days with an index in `range`.
+    pub fn window(&self, range: Range<usize>) -> Returns {
+        Returns {
+            names: self.names.clone(),
+            dates: self.dates[range.clone()].to_vec(),
+            columns: self.columns.iter().map(|c| c[range.clone()].to_vec()).collect(),
+        }
+    }
+}
+
+/// A square matrix with a row and a column per symbol. Entries are NaN where they are undefined,
+/// e.g. the correlation with a series that never moved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    pub names: Vec<String>,
+    pub values: Vec<Vec<f64>>,
+}
+
+impl Matrix {
+    /// Sample covariance of the daily returns.
+    pub fn covariance(returns: &Returns) -> Self {
+        let n = returns.len() as f64;
+        let means: Vec<f64> = returns.columns.iter().map(|c| c.iter().sum::<f64>() / n).collect();
+        let values = (0..returns.columns.len()).map(|i| (0..returns.columns.len()).map(|j| {
+            let (a, b) = (&returns.columns[i], &returns.columns[j]);
+            a.iter().zip(b).map(|(x, y)| (x - means[i]) * (y - means[j])).sum::<f64>() / (n - 1.0)
+        }).collect()).collect();
+        Matrix {
+            names: returns.names.clone(),
+            values,
+        }
+    }
+
+    /// Pearson correlation of the daily returns.
+    pub fn correlation(returns: &Returns) -> Self {
+        let covariance = Matrix::covariance(returns);
+        let values = (0..covariance.values.len()).map(|i| (0..covariance.values.len()).map(|j| {
+            covariance.values[i][j] / (covariance.values[i][i] * covariance.values[j][j]).sqrt()
+        }).collect()).collect();
+        Matrix {
+            names: covariance.names,
+            values,
+        }
+    }
+
+    pub fn write_csv<W: Write>(&self, out: W) {
+        let mut out = BufWriter::new(out);
+        writeln!(out, ",{}", self.names.join(",")).unwrap();
+        for (name, row) in self.names.iter().zip(&self.values) {
+            writeln!(out, "{},{}", name, row.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")).expect("Couldn't write csv row!");
+        }
+        out.flush().unwrap();
+    }
+
+    /// Renders the matrix as a heatmap, red for -1 over white to blue for 1.
+    pub fn plot_heatmap(&self, title: &str, path: &str, size: u32) {
+        plot_heatmap(title, path, size, &self.names, &self.names, &self.values, 1.0);
+    }
+}
+
+impl fmt::Display for Matrix {
+    /// Prints a table, six decimals unless another precision is given like `{:.8}`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let precision = f.precision().unwrap_or(6);
+        write!(f, "{:<8}", "")?;
+        for name in &self.names {
+            write!(f, " {:>12}", name)?;
+        }
+        for (name, row) in self.names.iter().zip(&self.values) {
+            write!(f, "\n{:<8}", name)?;
+            for value in row {
+                write!(f, " {:>12.*}", precision, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Correlation over the `window` returns ending on each day.
+pub fn rolling_correlation(returns: &Returns, window: usize) -> Vec<(NaiveDate, Matrix)> {
+    if window < 2 || returns.len() < window {
+        return Vec::new();
+    }
+    (window..=returns.len()).map(|end| (returns.dates[end - 1], Matrix::correlation(&returns.window(end - window..end)))).collect()
+}
+
+/// Writes rolling matrices in long form, one row per date and pair of symbols.
+pub fn write_rolling_csv<W: Write>(out: W, rolling: &[(NaiveDate, Matrix)]) {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "date,first,second,correlation").unwrap();
+    for (date, matrix) in rolling {
+        for i in 0..matrix.names.len() {
+            for j in i + 1..matrix.names.len() {
+                writeln!(out, "{},{},{},{}", date, matrix.names[i], matrix.names[j], matrix.values[i][j]).expect("Couldn't write csv row!");
+            }
+        }
+    }
+    out.flush().unwrap();
+}
+
+/// Renders a grid of values with a label per row and column, red for `-scale` over white to blue for
+/// `scale`. NaN cells are grey.
+pub fn plot_heatmap(title: &str, path: &str, size: u32, rows: &[String], columns: &[String], values: &[Vec<f64>], scale: f64) {
+    let (n, m) = (rows.len(), columns.len());
+    if n == 0 || m == 0 {
+        return;
+    }
+    let root = BitMapBackend::new(path, (size, size)).into_drawing_area();
+    root.fill(&WHITE).unwrap();
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 30).into_font())
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d((0..m - 1).into_segmented(), (0..n - 1).into_segmented()).unwrap();
+
+    // The first row is the top one, so rows count down on the y axis
+    let x_label = |x: &SegmentValue<usize>| match x {
+        SegmentValue::CenterOf(i) if *i < m => columns[*i].clone(),
+        _ => String::new(),
+    };
+    let y_label = |y: &SegmentValue<usize>| match y {
+        SegmentValue::CenterOf(i) if *i < n => rows[n - 1 - *i].clone(),
+        _ => String::new(),
+    };
+    chart.configure_mesh()
+        .disable_mesh()
+        .x_label_formatter(&x_label)
+        .y_label_formatter(&y_label)
+        .draw().unwrap();
+
+    for (i, row) in values.iter().enumerate() {
+        for (j, cell) in row.iter().enumerate() {
+            let value = cell / scale;
+            let (x, y) = (SegmentValue::Exact(j), SegmentValue::Exact(n - 1 - i));
+            chart.draw_series(std::iter::once(Rectangle::new([(x.clone(), y.clone()), (SegmentValue::Exact(j + 1), SegmentValue::Exact(n - i))], heat_color(value).filled()))).unwrap();
+            chart.draw_series(std::iter::once(Text::new(format!("{:.2}", cell), (SegmentValue::CenterOf(j), SegmentValue::CenterOf(n - 1 - i)), ("sans-serif", 16).into_font().color(if value.abs() > 0.6 { &WHITE } else { &BLACK })))).unwrap();
+        }
+    }
+    root.present().expect("Couldn't write heatmap!");
+}
+
+fn heat_color(value: f64) -> RGBColor {
+    if value.is_nan() {
+        return RGBColor(200, 200, 200);
+    }
+    let v = value.clamp(-1.0, 1.0);
+    let fade = |x: f64| (255.0 * (1.0 - x.abs())) as u8;
+    if v < 0.0 {
+        RGBColor(255, fade(v), fade(v))
+    } else {
+        RGBColor(fade(v), fade(v), 255)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::{Duration, NaiveDate};
+    use mysql_common::bigdecimal::{BigDecimal, FromPrimitive};
+
+    use crate::timeseries::{Bar, TimeSeries};
+
+    use super::{rolling_correlation, write_rolling_csv, Matrix, Returns};
+
+    fn series(name: &str, closes: &[f64], skip: Option<i64>) -> TimeSeries {
+        let mut ts = TimeSeries::new(name);
+        for (i, c) in closes.iter().enumerate() {
+            if Some(i as i64) != skip {
+                ts.insert(NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + Duration::days(i as i64), Bar::from_close(BigDecimal::from_f64(*c).unwrap()));
+            }
+        }
+        ts
+    }
+
+    #[test]
+    fn matrices() {
+        let a = series("a", &[100.0, 110.0, 99.0, 108.9, 108.9], None);
+        let b = series("b", &[50.0, 60.0, 45.0, 54.0, 54.0], None);
+        // c misses day 2, so its only return pairs day 1 and 3 like the others
+        let c = series("c", &[10.0, 9.0, 1.0, 9.9, 9.9], Some(2));
+        let returns = Returns::of(&[&a, &b, &c]);
+        assert_eq!(returns.len(), 3);
+        assert!((returns.columns[0][0] - 0.1).abs() < 1e-9);
+
+        // a: 0.1, -0.01, 0 and b: 0.2, -0.1, 0 move together but not proportionally
+        let covariance = Matrix::covariance(&returns);
+        let mean_a = 0.09 / 3.0;
+        let mean_b = 0.1 / 3.0;
+        let expected = ((0.1 - mean_a) * (0.2 - mean_b) + (-0.01 - mean_a) * (-0.1 - mean_b) + (0.0 - mean_a) * (0.0 - mean_b)) / 2.0;
+        assert!((covariance.values[0][1] - expected).abs() < 1e-12);
+        assert_eq!(covariance.values[0][1], covariance.values[1][0]);
+
+        let correlation = Matrix::correlation(&returns);
+        assert!((correlation.values[0][0] - 1.0).abs() < 1e-12);
+        assert!(correlation.values[0][1] > 0.9 && correlation.values[0][1] < 1.0);
+        // c returns -0.1, 0.1, 0 and moves against a
+        assert!(correlation.values[0][2] < 0.0);
+
+        let rolling = rolling_correlation(&returns, 2);
+        assert_eq!(rolling.len(), 2);
+        assert_eq!(rolling[0].0, returns.dates[1]);
+        let mut csv = Vec::new();
+        write_rolling_csv(&mut csv, &rolling);
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 1 + 2 * 3);
+    }
+}
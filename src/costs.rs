@@ -0,0 +1,172 @@
+//! What an order costs on top of the shares: broker fees and slippage.
+//!
+//! Fees are charged per order on its value and are capped by `fee_min` and `fee_max`. Slippage moves
+//! the fill price against the order, buys fill above and sells below the close, either by a fixed
+//! number of basis points or by a multiple of the bar-to-bar volatility.
+
+use std::str::FromStr;
+
+use mysql_common::bigdecimal::{BigDecimal, FromPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::numeric::{self, Rounding, MONEY_SCALE};
+
+/// Fee of one order, written like `fixed:4.95`, `percent:0.25` or `tiered:1000=4.95:10000=9.95:19.95` in the config.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum FeeModel {
+    #[default]
+    None,
+    Fixed(#[serde(with = "crate::persist::decimal")] BigDecimal),
+    /// Percent of the order value.
+    Percent(#[serde(with = "crate::persist::decimal")] BigDecimal),
+    /// Orders up to the limit of a tier pay its fee, bigger ones pay the last fee.
+    Tiered(Vec<Tier>, #[serde(with = "crate::persist::decimal")] BigDecimal),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tier {
+    #[serde(with = "crate::persist::decimal")]
+    pub limit: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub fee: BigDecimal,
+}
+
+/// Written like `bps:5` or `volatility:0.5:20` in the config.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum Slippage {
+    #[default]
+    None,
+    /// Basis points of the price.
+    Bps(#[serde(with = "crate::persist::decimal")] BigDecimal),
+    /// This multiple of the standard deviation of the returns over the last `window` bars.
+    Volatility(f64, usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Costs {
+    pub fee: FeeModel,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub fee_min: Option<BigDecimal>,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub fee_max: Option<BigDecimal>,
+    pub slippage: Slippage,
+}
+
+impl Costs {
+    /// Fee for an order of `value`, rounded to cents.
+    pub fn fee(&self, value: &BigDecimal) -> BigDecimal {
+        if value.is_zero() {
+            return BigDecimal::zero();
+        }
+        let mut fee = match &self.fee {
+            FeeModel::None => BigDecimal::zero(),
+            FeeModel::Fixed(fee) => fee.clone(),
+            FeeModel::Percent(percent) => value * percent / BigDecimal::from(100),
+            FeeModel::Tiered(tiers, above) => tiers.iter().find(|tier| value <= &tier.limit).map(|tier| &tier.fee).unwrap_or(above).clone(),
+        };
+        if let Some(min) = self.fee_min.as_ref().filter(|min| &fee < min) {
+            fee = min.clone();
+        }
+        if let Some(max) = self.fee_max.as_ref().filter(|max| &fee > max) {
+            fee = max.clone();
+        }
+        numeric::round(&fee, MONEY_SCALE, Rounding::HalfUp)
+    }
+
+    /// Share of the price a fill moves against the order, `volatility` is the standard deviation of
+    /// the returns for [`Slippage::Volatility`].
+    pub fn slippage_rate(&self, volatility: Option<f64>) -> BigDecimal {
+        match &self.slippage {
+            Slippage::None => BigDecimal::zero(),
+            Slippage::Bps(bps) => bps / BigDecimal::from(10000),
+            Slippage::Volatility(factor, _) => match volatility.and_then(|v| BigDecimal::from_f64(factor * v)) {
+                Some(rate) => numeric::round(&rate, 6, Rounding::HalfUp),
+                None => BigDecimal::zero(),
+            },
+        }
+    }
+}
+
+impl FromStr for FeeModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let amount = |p: &str| BigDecimal::from_str(p).map_err(|_| format!("Invalid amount '{}' in fee '{}'", p, s));
+        match (parts[0], &parts[1..]) {
+            ("none", []) => Ok(FeeModel::None),
+            ("fixed", [fee]) => Ok(FeeModel::Fixed(amount(fee)?)),
+            ("percent", [percent]) => Ok(FeeModel::Percent(amount(percent)?)),
+            ("tiered", [tiers @ .., above]) => {
+                let tiers = tiers.iter().map(|tier| match tier.split_once('=') {
+                    Some((limit, fee)) => Ok(Tier { limit: amount(limit)?, fee: amount(fee)? }),
+                    None => Err(format!("Tier '{}' in fee '{}' has to be written like 1000=4.95", tier, s)),
+                }).collect::<Result<Vec<_>, _>>()?;
+                Ok(FeeModel::Tiered(tiers, amount(above)?))
+            }
+            _ => Err(format!("Unknown fee '{}', expected none, fixed:<fee>, percent:<percent> or tiered:<limit>=<fee>:...:<fee>", s)),
+        }
+    }
+}
+
+impl FromStr for Slippage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[..] {
+            ["none"] => Ok(Slippage::None),
+            ["bps", bps] => BigDecimal::from_str(bps).map(Slippage::Bps).map_err(|_| format!("Invalid basis points '{}' in slippage '{}'", bps, s)),
+            ["volatility", factor, ref window @ ..] if window.len() <= 1 => {
+                let factor = factor.parse::<f64>().map_err(|_| format!("Invalid factor '{}' in slippage '{}'", factor, s))?;
+                let window = match window.first() {
+                    Some(w) => w.parse::<usize>().map_err(|_| format!("Invalid window '{}' in slippage '{}'", w, s))?,
+                    None => 20,
+                };
+                Ok(Slippage::Volatility(factor, window))
+            }
+            _ => Err(format!("Unknown slippage '{}', expected none, bps:<bps> or volatility:<factor>[:<window>]", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use super::{Costs, FeeModel, Slippage};
+
+    fn d(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn fees_and_caps() {
+        let mut costs = Costs { fee: FeeModel::from_str("percent:0.25").unwrap(), ..Costs::default() };
+        assert_eq!(costs.fee(&d("1234.56")), d("3.09"));
+        assert_eq!(costs.fee(&d("0")), d("0"));
+        costs.fee_min = Some(d("5"));
+        costs.fee_max = Some(d("50"));
+        assert_eq!(costs.fee(&d("1000")), d("5"));
+        assert_eq!(costs.fee(&d("100000")), d("50"));
+
+        let tiered = Costs { fee: FeeModel::from_str("tiered:1000=4.95:10000=9.95:19.95").unwrap(), ..Costs::default() };
+        assert_eq!(tiered.fee(&d("1000")), d("4.95"));
+        assert_eq!(tiered.fee(&d("1000.01")), d("9.95"));
+        assert_eq!(tiered.fee(&d("20000")), d("19.95"));
+        assert!(FeeModel::from_str("tiered:1000:5").is_err());
+        assert!(FeeModel::from_str("flat:5").is_err());
+    }
+
+    #[test]
+    fn slippage_rates() {
+        let costs = |s: &str| Costs { slippage: Slippage::from_str(s).unwrap(), ..Costs::default() };
+        assert_eq!(costs("bps:5").slippage_rate(None), d("0.0005"));
+        assert_eq!(costs("volatility:0.5").slippage_rate(Some(0.02)), d("0.01"));
+        assert_eq!(costs("volatility:0.5:10").slippage_rate(None), d("0"));
+        assert_eq!(Slippage::from_str("volatility:0.5").unwrap(), Slippage::Volatility(0.5, 20));
+        assert!(Slippage::from_str("bps").is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use std::str::FromStr;
+
+use mysql::chrono::{NaiveDate, NaiveDateTime, Utc};
+use mysql_common::bigdecimal::BigDecimal;
+
+use crate::{alphavantage::DataProvider, config::Config, mysql_db::MysqlDatabase, numeric::{round_half_up, PRICE_SCALE, SPLIT_SCALE}, postgres_db::PostgresDatabase, timeseries::{Bar, TimeSeries}};
+
+/// Bounds that every backend can store in a DATE column, used to read whole tables.
+pub const FIRST_DATE: (i32, u32, u32) = (1000, 1, 1);
+pub const LAST_DATE: (i32, u32, u32) = (9999, 12, 31);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeriesKind {
+    Raw,
+    Adjusted,
+    Avg200,
+}
+
+impl SeriesKind {
+    pub const ALL: [SeriesKind; 3] = [SeriesKind::Raw, SeriesKind::Adjusted, SeriesKind::Avg200];
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            SeriesKind::Raw => "raw",
+            SeriesKind::Adjusted => "adjusted",
+            SeriesKind::Avg200 => "200avg",
+        }
+    }
+
+    pub fn table_name(&self, symbol: &str) -> String {
+        format!("{}_{}", symbol, self.suffix())
+    }
+}
+
+impl FromStr for SeriesKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(SeriesKind::Raw),
+            "adjusted" => Ok(SeriesKind::Adjusted),
+            "200avg" => Ok(SeriesKind::Avg200),
+            other => Err(format!("Unknown series '{}', expected raw, adjusted or 200avg", other)),
+        }
+    }
+}
+
+/// A stored raw value that the provider later reported differently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revision {
+    pub entry_date: NaiveDate,
+    pub old_close: BigDecimal,
+    pub new_close: BigDecimal,
+    pub old_split_coefficient: BigDecimal,
+    pub new_split_coefficient: BigDecimal,
+    pub fetched_at: NaiveDateTime,
+}
+
+/// Storage backend for the `{symbol}_raw`, `{symbol}_adjusted`, `{symbol}_200avg` and `{symbol}_revisions` tables.
+///
+/// Backends only implement the table access; the update logic in [`Database::update`] is shared,
+/// so every backend ends up with the same data.
+pub trait Database {
+    /// Number of already stored days that are fetched again and checked for revisions on every update, 0 disables the check.
+    fn revision_window(&self) -> usize;
+
+    fn create_tables(&mut self, symbol: &str);
+
+    fn drop_tables(&mut self, symbol: &str);
+
+    /// Inserts the entries into the given table, replacing rows with the same date. The 200avg table only keeps the close.
+    fn write_entries(&mut self, symbol: &str, series: SeriesKind, entries: &TimeSeries);
+
+    fn record_revisions(&mut self, symbol: &str, revisions: &[Revision]);
+
+    fn get_revisions(&mut self, symbol: &str) -> Vec<Revision>;
+
+    fn get_timeseries_between(&mut self, symbol: &str, table_name: &str, start_date: NaiveDate, end_date: NaiveDate) -> TimeSeries;
+
+    fn get_timeseries(&mut self, symbol: &str, series: SeriesKind) -> TimeSeries {
+        let start_date = NaiveDate::from_ymd_opt(FIRST_DATE.0, FIRST_DATE.1, FIRST_DATE.2).unwrap();
+        let end_date = NaiveDate::from_ymd_opt(LAST_DATE.0, LAST_DATE.1, LAST_DATE.2).unwrap();
+        self.get_timeseries_between(symbol, &series.table_name(symbol), start_date, end_date)
+    }
+
+    fn update(&mut self, symbol: &str, provider: &dyn DataProvider) {
+        self.create_tables(symbol);
+        let mut raw = self.get_timeseries(symbol, SeriesKind::Raw);
+
+        //Check if raw table is empty, if it is, do a large fetch
+        if raw.is_empty() {
+            let full = provider.fetch_daily(symbol, false);
+            self.write_entries(symbol, SeriesKind::Raw, &full);
+            self.write_derived(symbol, &full, None);
+            return;
+        }
+
+        // With a revision window, the fetch also has to cover the last stored days
+        let max_date = *raw.last().unwrap().0;
+        let check_from = match self.revision_window() {
+            0 => max_date,
+            window => *raw.dates().nth_back(window - 1).unwrap_or_else(|| raw.first().unwrap().0),
+        };
+        let mut fetched = provider.fetch_daily(symbol, true);
+        if fetched.first().is_none_or(|first| check_from < *first.0) {
+            fetched = provider.fetch_daily(symbol, false);
+        }
+
+        let mut changed = TimeSeries::new(symbol);
+        let mut split_changed = false;
+        let mut revisions = Vec::new();
+        if self.revision_window() > 0 {
+            let fetched_at = Utc::now().naive_utc();
+            for (date, old) in raw.slice(check_from..).iter() {
+                let new = match fetched.get(date) {
+                    Some(bar) => bar,
+                    None => continue,
+                };
+                // Tables only keep two decimals, so compare at that scale
+                let split_revised = old.split() != round_half_up(&new.split(), SPLIT_SCALE);
+                if *old.close == round_half_up(new.close, PRICE_SCALE) && !split_revised {
+                    continue;
+                }
+                split_changed |= split_revised;
+                revisions.push(Revision {
+                    entry_date: *date,
+                    old_close: old.close.clone(),
+                    new_close: new.close.clone(),
+                    old_split_coefficient: old.split(),
+                    new_split_coefficient: new.split(),
+                    fetched_at,
+                });
+                changed.insert(*date, new.to_bar());
+            }
+        }
+        for (date, bar) in fetched.slice(max_date..).iter().filter(|x| *x.0 > max_date) {
+            if bar.has_split() {
+                split_changed = true;
+            }
+            changed.insert(*date, bar.to_bar());
+        }
+        if changed.is_empty() {
+            return;
+        }
+
+        for revision in &revisions {
+            println!("{}: revised close on {} from {} to {}", symbol, revision.entry_date, revision.old_close, revision.new_close);
+        }
+        self.record_revisions(symbol, &revisions);
+        let changed_from = *changed.first().unwrap().0;
+        self.write_entries(symbol, SeriesKind::Raw, &changed);
+        raw.extend(changed);
+
+        // A new or revised split changes every earlier adjusted value, anything else only the days from the change on
+        if split_changed {
+            self.write_derived(symbol, &raw, None);
+        } else {
+            self.write_derived(symbol, &raw, Some(changed_from));
+        }
+    }
+
+    /// Recalculates the adjusted and 200avg tables from the full raw history, writing the days from `from` on.
+    fn write_derived(&mut self, symbol: &str, raw: &TimeSeries, from: Option<NaiveDate>) {
+        let mut adjusted = raw.clone();
+        adjusted.correct_splits();
+        adjusted.map_prices(|p| round_half_up(p, PRICE_SCALE));
+        let from = from.unwrap_or_else(|| *adjusted.first().unwrap().0);
+        self.write_entries(symbol, SeriesKind::Adjusted, &adjusted.slice(from..));
+
+        let closes: Vec<&BigDecimal> = adjusted.closes().collect();
+        let mut averages = TimeSeries::new(symbol);
+        for (i, date) in adjusted.dates().enumerate().skip(200).filter(|x| *x.1 >= from) {
+            let sum: BigDecimal = closes[i + 1 - 200..=i].iter().copied().sum();
+            averages.insert(*date, Bar::from_close(round_half_up(&(sum / BigDecimal::from(200)), PRICE_SCALE)));
+        }
+        self.write_entries(symbol, SeriesKind::Avg200, &averages);
+    }
+}
+
+/// Connects to the backend matching the scheme of `database_url`.
+pub fn from_config(config: &Config) -> Box<dyn Database> {
+    if config.database_url.starts_with("postgres://") || config.database_url.starts_with("postgresql://") {
+        Box::new(PostgresDatabase::from_config(config))
+    } else if config.database_url.starts_with("mysql://") {
+        Box::new(MysqlDatabase::from_config(config))
+    } else {
+        panic!("Unsupported database url {}, expected mysql:// or postgres://", config.database_url)
+    }
+}
+
+#[cfg(test)]
+pub mod conformance {
+    use std::{cell::RefCell, collections::BTreeMap, str::FromStr};
+
+    use mysql::chrono::{Duration, NaiveDate};
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::{alphavantage::DataProvider, timeseries::{Bar, TimeSeries}};
+
+    use super::{Database, SeriesKind};
+
+    const SYMBOL: &str = "conformance";
+
+    /// Serves a scripted history; compact fetches return the last 100 days like AlphaVantage.
+    struct ScriptedProvider {
+        entries: RefCell<BTreeMap<NaiveDate, Bar>>,
+    }
+
+    impl DataProvider for ScriptedProvider {
+        fn fetch_daily(&self, symbol: &str, compact: bool) -> TimeSeries {
+            let entries = self.entries.borrow();
+            let skip = if compact { entries.len().saturating_sub(100) } else { 0 };
+            TimeSeries::from_map(symbol, entries.iter().skip(skip).map(|x| (*x.0, x.1.clone())).collect())
+        }
+    }
+
+    fn day(i: i64) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + Duration::days(i)
+    }
+
+    fn dec(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    fn close(db: &mut dyn Database, series: SeriesKind, date: NaiveDate) -> BigDecimal {
+        db.get_timeseries(SYMBOL, series).get(&date).unwrap().close.clone()
+    }
+
+    /// Runs the shared behaviour checks against a backend with a revision window of 10 days.
+    pub fn run(db: &mut dyn Database) {
+        assert_eq!(db.revision_window(), 10);
+        db.drop_tables(SYMBOL);
+
+        // 250 days closing at 100, 101, ... with a 2:1 split on day 100
+        let provider = ScriptedProvider { entries: RefCell::new(BTreeMap::new()) };
+        for i in 0..250 {
+            let split = if i == 100 { 2 } else { 1 };
+            provider.entries.borrow_mut().insert(day(i), Bar::from_close(BigDecimal::from(100 + i)).with_split(split));
+        }
+        provider.entries.borrow_mut().insert(day(0), Bar {
+            open: Some(dec("99")),
+            high: Some(dec("101.5")),
+            low: Some(dec("98.25")),
+            close: dec("100"),
+            adjusted_close: Some(dec("49.12")),
+            volume: Some(1000),
+            dividend: Some(dec("0.5")),
+            split_coefficient: Some(dec("1")),
+        });
+
+        // Schema creation and initial full load
+        db.update(SYMBOL, &provider);
+        assert_eq!(db.get_timeseries(SYMBOL, SeriesKind::Raw).len(), 250);
+        assert_eq!(db.get_timeseries(SYMBOL, SeriesKind::Adjusted).len(), 250);
+        assert_eq!(db.get_timeseries(SYMBOL, SeriesKind::Avg200).len(), 50);
+        assert_eq!(close(db, SeriesKind::Raw, day(99)), dec("199"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(99)), dec("99.5"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(100)), dec("200"));
+        let bar = db.get_timeseries(SYMBOL, SeriesKind::Raw).get(&day(0)).unwrap().to_bar();
+        assert_eq!(bar, provider.entries.borrow().get(&day(0)).unwrap().clone());
+        let bar = db.get_timeseries(SYMBOL, SeriesKind::Adjusted).get(&day(0)).unwrap().to_bar();
+        assert_eq!((bar.open, bar.high, bar.low, bar.close), (Some(dec("49.5")), Some(dec("50.75")), Some(dec("49.13")), dec("50")));
+        assert_eq!((bar.adjusted_close, bar.volume, bar.dividend), (Some(dec("49.12")), Some(2000), Some(dec("0.25"))));
+        let bar = db.get_timeseries(SYMBOL, SeriesKind::Raw).get(&day(1)).unwrap().to_bar();
+        assert_eq!((bar.open, bar.volume, bar.split_coefficient), (None, None, Some(dec("1"))));
+        // Days 1..=200: 99 halved closes (101..199)/2, then 200..300
+        assert_eq!(close(db, SeriesKind::Avg200, day(200)), dec("163.38"));
+        assert!(db.get_revisions(SYMBOL).is_empty());
+
+        // Range queries include both bounds
+        let range = db.get_timeseries_between(SYMBOL, &SeriesKind::Raw.table_name(SYMBOL), day(10), day(20));
+        assert_eq!(range.len(), 11);
+        assert_eq!(range.equity_name, SYMBOL);
+        let range = db.get_timeseries_between(SYMBOL, &SeriesKind::Avg200.table_name(SYMBOL), day(240), day(300));
+        assert_eq!(range.len(), 10);
+        assert_eq!(range.first().unwrap().1.split_coefficient, None);
+
+        // Updating without new data changes nothing
+        db.update(SYMBOL, &provider);
+        assert_eq!(db.get_timeseries(SYMBOL, SeriesKind::Raw).len(), 250);
+
+        // Incremental update only appends the new days
+        for i in 250..255 {
+            provider.entries.borrow_mut().insert(day(i), Bar::from_close(BigDecimal::from(100 + i)).with_split(1));
+        }
+        db.update(SYMBOL, &provider);
+        assert_eq!(db.get_timeseries(SYMBOL, SeriesKind::Raw).len(), 255);
+        assert_eq!(db.get_timeseries(SYMBOL, SeriesKind::Avg200).len(), 55);
+        assert_eq!(close(db, SeriesKind::Adjusted, day(254)), dec("354"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(99)), dec("99.5"));
+        assert_eq!(close(db, SeriesKind::Avg200, day(254)), dec("234.59"));
+
+        // A new split re-adjusts the whole history and the 200avgs
+        provider.entries.borrow_mut().insert(day(255), Bar::from_close(BigDecimal::from(100)).with_split(4));
+        db.update(SYMBOL, &provider);
+        assert_eq!(close(db, SeriesKind::Raw, day(254)), dec("354"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(254)), dec("88.5"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(99)), dec("24.88"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(255)), dec("100"));
+        assert_eq!(close(db, SeriesKind::Avg200, day(254)), dec("58.65"));
+
+        // A revised close inside the window is audited and propagated
+        provider.entries.borrow_mut().insert(day(250), Bar::from_close(BigDecimal::from(250)).with_split(1));
+        db.update(SYMBOL, &provider);
+        let revisions = db.get_revisions(SYMBOL);
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].entry_date, day(250));
+        assert_eq!(revisions[0].old_close, dec("350"));
+        assert_eq!(revisions[0].new_close, dec("250"));
+        assert_eq!(close(db, SeriesKind::Raw, day(250)), dec("250"));
+        assert_eq!(close(db, SeriesKind::Adjusted, day(250)), dec("62.5"));
+        assert_eq!(close(db, SeriesKind::Avg200, day(254)), dec("58.52"));
+
+        // Revisions before the window are not checked
+        provider.entries.borrow_mut().insert(day(200), Bar::from_close(BigDecimal::from(1)).with_split(1));
+        db.update(SYMBOL, &provider);
+        assert_eq!(db.get_revisions(SYMBOL).len(), 1);
+        assert_eq!(close(db, SeriesKind::Raw, day(200)), dec("300"));
+
+        db.drop_tables(SYMBOL);
+    }
+}
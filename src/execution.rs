@@ -0,0 +1,114 @@
+//! When orders are filled relative to their signal.
+//!
+//! Strategies decide on a bar's close, so filling at that same close assumes the order went out
+//! before the close was known. With `next_open` or `next_close` the order is filled on the next
+//! trading day instead, `delay` days later still if set. Days without an open, like the stored
+//! series, fill at the close.
+
+use std::{fmt, str::FromStr};
+
+use mysql_common::bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+
+use crate::timeseries::BarRef;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Timing {
+    /// At the close the signal was computed on, with look-ahead.
+    SameClose,
+    NextOpen,
+    NextClose,
+}
+
+impl Timing {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Timing::SameClose => "close",
+            Timing::NextOpen => "next_open",
+            Timing::NextClose => "next_close",
+        }
+    }
+}
+
+impl FromStr for Timing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match [Timing::SameClose, Timing::NextOpen, Timing::NextClose].iter().find(|t| t.name() == s) {
+            Some(timing) => Ok(*timing),
+            None => Err(format!("Unknown execution '{}', expected close, next_open or next_close", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Execution {
+    pub timing: Timing,
+    /// Trading days the fill is put off by.
+    pub delay: usize,
+}
+
+impl Default for Execution {
+    fn default() -> Self {
+        Execution { timing: Timing::SameClose, delay: 0 }
+    }
+}
+
+impl Execution {
+    /// Trading days from the signal to the fill.
+    pub fn offset(&self) -> usize {
+        match self.timing {
+            Timing::SameClose => self.delay,
+            Timing::NextOpen | Timing::NextClose => self.delay + 1,
+        }
+    }
+
+    /// Whether orders fill at the close their signal was computed on.
+    pub fn looks_ahead(&self) -> bool {
+        self.offset() == 0
+    }
+
+    /// The price an order is filled at on the day of `bar`.
+    pub fn price<'a>(&self, bar: BarRef<'a>) -> &'a BigDecimal {
+        match self.timing {
+            Timing::NextOpen => bar.open_or_close(),
+            Timing::SameClose | Timing::NextClose => bar.close,
+        }
+    }
+}
+
+impl fmt::Display for Execution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.delay {
+            0 => write!(f, "{}", self.timing.name()),
+            delay => write!(f, "{} +{}d", self.timing.name(), delay),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::timeseries::Bar;
+
+    use super::{Execution, Timing};
+
+    #[test]
+    fn fills_after_the_signal() {
+        assert_eq!(Timing::from_str("next_open").unwrap(), Timing::NextOpen);
+        assert!(Timing::from_str("open").is_err());
+        assert!(Execution::default().looks_ahead());
+
+        let next_open = Execution { timing: Timing::NextOpen, delay: 2 };
+        assert_eq!((next_open.offset(), next_open.to_string()), (3, String::from("next_open +2d")));
+        let mut bar = Bar::from_close(BigDecimal::from(10));
+        assert_eq!(next_open.price(bar.view()), &BigDecimal::from(10));
+        bar.open = Some(BigDecimal::from(9));
+        assert_eq!(next_open.price(bar.view()), &BigDecimal::from(9));
+        assert_eq!(Execution { timing: Timing::NextClose, delay: 0 }.price(bar.view()), &BigDecimal::from(10));
+    }
+}
@@ -0,0 +1,272 @@
+//! Export of stored series to CSV, JSON Lines and Parquet.
+//!
+//! Every format uses the same long layout, one row per symbol, series and date,
+//! ordered by symbol (in the requested order), series (raw, adjusted, 200avg) and date:
+//!
+//! | column              | CSV              | JSON Lines             | Parquet                       |
+//! |---------------------|------------------|------------------------|-------------------------------|
+//! | `symbol`            | text             | string                 | `BYTE_ARRAY (UTF8)`           |
+//! | `series`            | text             | string                 | `BYTE_ARRAY (UTF8)`           |
+//! | `date`              | `YYYY-MM-DD`     | `"YYYY-MM-DD"`         | `INT32 (DATE)`                |
+//! | `close`             | decimal          | decimal string         | `BYTE_ARRAY (UTF8)`           |
+//! | `split_coefficient` | decimal or empty | decimal string or null | `BYTE_ARRAY (UTF8)`, optional |
+//!
+//! `series` is one of `raw`, `adjusted` or `200avg`, matching the `{symbol}_{series}` tables.
+//! Decimals are written exactly as stored, in every format the same text, so nothing passes through
+//! a float. `split_coefficient` is empty for `200avg`. Columns are never renamed or reordered;
+//! new columns are only ever appended at the end.
+
+use std::{fs::File, io::{BufWriter, Write}, path::Path, str::FromStr, sync::Arc};
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::BigDecimal;
+use parquet::{basic::Compression, data_type::{ByteArray, ByteArrayType, Int32Type}, file::{properties::WriterProperties, writer::SerializedFileWriter}, schema::parser::parse_message_type};
+
+use crate::{config::Config, database::{Database, SeriesKind}, stock::date_range};
+
+const PARQUET_SCHEMA: &str = "
+    message stock_series {
+        REQUIRED BYTE_ARRAY symbol (UTF8);
+        REQUIRED BYTE_ARRAY series (UTF8);
+        REQUIRED INT32 date (DATE);
+        REQUIRED BYTE_ARRAY close (UTF8);
+        OPTIONAL BYTE_ARRAY split_coefficient (UTF8);
+    }
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    JsonLines,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" | "jsonl" => Ok(ExportFormat::JsonLines),
+            "parquet" => Ok(ExportFormat::Parquet),
+            other => Err(format!("Unknown export format '{}', expected csv, jsonl or parquet", other)),
+        }
+    }
+}
+
+pub struct ExportRow {
+    pub symbol: String,
+    pub series: SeriesKind,
+    pub date: NaiveDate,
+    pub close: BigDecimal,
+    pub split_coefficient: Option<BigDecimal>,
+}
+
+impl ExportRow {
+    /// Splits are written like 5 or 1.5 whatever scale the backend returns them with.
+    fn split(&self) -> Option<String> {
+        self.split_coefficient.as_ref().map(|split| split.normalized().to_string())
+    }
+}
+
+pub struct Exporter {
+    pub symbols: Vec<String>,
+    pub series: Vec<SeriesKind>,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub format: ExportFormat,
+    pub path: String,
+}
+
+impl Exporter {
+    /// Builds an exporter from `export` command line arguments, falling back to the config for anything not given:
+    /// `--symbols tsla,ibm --series raw,adjusted,200avg --from 01-01-2015 --to 31-12-2020 --format csv|jsonl|parquet --out file`
+    pub fn from_args(args: &[String], config: &Config) -> Self {
+        let (start_date, end_date, options) = date_range("export", args, config);
+        let mut symbols = config.stocks.clone();
+        let mut series = SeriesKind::ALL.to_vec();
+        let mut format = ExportFormat::Csv;
+        let mut path = None;
+        for (flag, value) in options {
+            match flag {
+                "--symbols" => symbols = value.split(',').map(String::from).collect(),
+                "--series" => series = value.split(',').map(|s| SeriesKind::from_str(s).unwrap_or_else(|e| panic!("{}", e))).collect(),
+                "--format" => format = ExportFormat::from_str(value).unwrap_or_else(|e| panic!("{}", e)),
+                "--out" => path = Some(value.clone()),
+                other => panic!("Unknown export option: {}", other),
+            }
+        }
+
+        let path = match path {
+            Some(path) => path,
+            None => format!("exports/export_{}_{}.{}", start_date, end_date, format.extension()),
+        };
+
+        Exporter {
+            symbols,
+            series,
+            start_date,
+            end_date,
+            format,
+            path,
+        }
+    }
+
+    pub fn collect_rows(&self, db: &mut dyn Database) -> Vec<ExportRow> {
+        let mut rows = Vec::new();
+        for symbol in &self.symbols {
+            for series in &self.series {
+                let ts = db.get_timeseries_between(symbol, &series.table_name(symbol), self.start_date, self.end_date);
+                for (date, bar) in ts.iter() {
+                    rows.push(ExportRow {
+                        symbol: ts.equity_name.clone(),
+                        series: *series,
+                        date: *date,
+                        close: bar.close.clone(),
+                        split_coefficient: bar.split_coefficient.cloned(),
+                    });
+                }
+            }
+        }
+        rows
+    }
+
+    pub fn export(&self, db: &mut dyn Database) {
+        let rows = self.collect_rows(db);
+        let file = create_file(&self.path);
+        match self.format {
+            ExportFormat::Csv => write_csv(file, &rows),
+            ExportFormat::JsonLines => write_json_lines(file, &rows),
+            ExportFormat::Parquet => write_parquet(file, &rows),
+        }
+        println!("Exported {} rows to {}", rows.len(), self.path);
+    }
+}
+
+/// Creates `path` and the directories leading to it.
+pub fn create_file(path: &str) -> File {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent).unwrap_or_else(|e| panic!("Couldn't create directory {}: {}", parent.display(), e));
+        }
+    }
+    File::create(path).unwrap_or_else(|e| panic!("Couldn't create file {}: {}", path, e))
+}
+
+pub fn write_csv<W: Write>(out: W, rows: &[ExportRow]) {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "symbol,series,date,close,split_coefficient").unwrap();
+    for row in rows {
+        let split = row.split().unwrap_or_default();
+        writeln!(out, "{},{},{},{},{}", row.symbol, row.series.suffix(), row.date, row.close, split).expect("Couldn't write csv row!");
+    }
+    out.flush().unwrap();
+}
+
+pub fn write_json_lines<W: Write>(out: W, rows: &[ExportRow]) {
+    let mut out = BufWriter::new(out);
+    for row in rows {
+        let line = json::object! {
+            "symbol": row.symbol.as_str(),
+            "series": row.series.suffix(),
+            "date": row.date.to_string(),
+            "close": row.close.to_string(),
+            "split_coefficient": row.split(),
+        };
+        writeln!(out, "{}", line.dump()).expect("Couldn't write json line!");
+    }
+    out.flush().unwrap();
+}
+
+pub fn write_parquet(out: File, rows: &[ExportRow]) {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).unwrap());
+    let props = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    let mut writer = SerializedFileWriter::new(out, schema, props).expect("Couldn't create parquet writer!");
+    let mut row_group = writer.next_row_group().unwrap();
+
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let symbols: Vec<ByteArray> = rows.iter().map(|r| ByteArray::from(r.symbol.as_str())).collect();
+    let series: Vec<ByteArray> = rows.iter().map(|r| ByteArray::from(r.series.suffix())).collect();
+    let dates: Vec<i32> = rows.iter().map(|r| (r.date - epoch).num_days() as i32).collect();
+    let closes: Vec<ByteArray> = rows.iter().map(|r| ByteArray::from(r.close.to_string().as_str())).collect();
+    let splits: Vec<ByteArray> = rows.iter().filter_map(|r| r.split().map(|s| ByteArray::from(s.as_str()))).collect();
+    let split_levels: Vec<i16> = rows.iter().map(|r| r.split_coefficient.is_some() as i16).collect();
+
+    let mut column = row_group.next_column().unwrap().unwrap();
+    column.typed::<ByteArrayType>().write_batch(&symbols, None, None).unwrap();
+    column.close().unwrap();
+    let mut column = row_group.next_column().unwrap().unwrap();
+    column.typed::<ByteArrayType>().write_batch(&series, None, None).unwrap();
+    column.close().unwrap();
+    let mut column = row_group.next_column().unwrap().unwrap();
+    column.typed::<Int32Type>().write_batch(&dates, None, None).unwrap();
+    column.close().unwrap();
+    let mut column = row_group.next_column().unwrap().unwrap();
+    column.typed::<ByteArrayType>().write_batch(&closes, None, None).unwrap();
+    column.close().unwrap();
+    let mut column = row_group.next_column().unwrap().unwrap();
+    column.typed::<ByteArrayType>().write_batch(&splits, Some(&split_levels), None).unwrap();
+    column.close().unwrap();
+
+    row_group.close().unwrap();
+    writer.close().expect("Couldn't finish parquet file!");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::database::SeriesKind;
+
+    use super::{ExportRow, write_csv, write_json_lines};
+
+    fn rows() -> Vec<ExportRow> {
+        vec![
+            ExportRow {
+                symbol: String::from("tsla"),
+                series: SeriesKind::Raw,
+                date: NaiveDate::from_ymd_opt(2020, 8, 31).unwrap(),
+                close: BigDecimal::from_str("498.32").unwrap(),
+                split_coefficient: Some(BigDecimal::from_str("5.00").unwrap()),
+            },
+            ExportRow {
+                symbol: String::from("tsla"),
+                series: SeriesKind::Avg200,
+                date: NaiveDate::from_ymd_opt(2020, 8, 31).unwrap(),
+                close: BigDecimal::from_str("270.10").unwrap(),
+                split_coefficient: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_layout() {
+        let mut out = Vec::new();
+        write_csv(&mut out, &rows());
+        assert_eq!(String::from_utf8(out).unwrap(), "symbol,series,date,close,split_coefficient\n\
+            tsla,raw,2020-08-31,498.32,5\n\
+            tsla,200avg,2020-08-31,270.10,\n");
+    }
+
+    #[test]
+    fn json_lines_layout() {
+        let mut out = Vec::new();
+        write_json_lines(&mut out, &rows());
+        assert_eq!(String::from_utf8(out).unwrap(), "{\"symbol\":\"tsla\",\"series\":\"raw\",\"date\":\"2020-08-31\",\"close\":\"498.32\",\"split_coefficient\":\"5\"}\n\
+            {\"symbol\":\"tsla\",\"series\":\"200avg\",\"date\":\"2020-08-31\",\"close\":\"270.10\",\"split_coefficient\":null}\n");
+    }
+}
@@ -0,0 +1,175 @@
+//! Several [`TimeSeries`] lined up on common dates.
+//!
+//! A [`Frame`] has one column per input series and one row per date. With [`Join::Inner`] only dates
+//! every series has are kept, with [`Join::Outer`] all dates are kept and the gaps are left empty or
+//! filled according to the [`Fill`] policy. Filled days only carry a close, nothing was traded on them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::BigDecimal;
+
+use crate::timeseries::{Bar, TimeSeries};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Join {
+    Inner,
+    Outer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    None,
+    /// Repeats the last known close, days before the first value stay empty.
+    ForwardFill,
+    /// Interpolates the close linearly by calendar days between two known values, gaps at the edges stay empty.
+    Interpolate,
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub names: Vec<String>,
+    pub rows: BTreeMap<NaiveDate, Vec<Option<Bar>>>,
+}
+
+impl Frame {
+    pub fn align(series: &[&TimeSeries], join: Join, fill: Fill) -> Self {
+        let dates: BTreeSet<NaiveDate> = match join {
+            Join::Inner => series.iter()
+                .map(|ts| ts.dates().cloned().collect::<BTreeSet<NaiveDate>>())
+                .reduce(|a, b| a.intersection(&b).cloned().collect())
+                .unwrap_or_default(),
+            Join::Outer => series.iter().flat_map(|ts| ts.dates().cloned()).collect(),
+        };
+
+        let mut rows: BTreeMap<NaiveDate, Vec<Option<Bar>>> = dates.iter().map(|d| (*d, Vec::with_capacity(series.len()))).collect();
+        for ts in series {
+            let column: Vec<Option<Bar>> = dates.iter().map(|d| ts.get(d).map(|bar| bar.to_bar())).collect();
+            let column = match fill {
+                Fill::None => column,
+                Fill::ForwardFill => forward_fill(column),
+                Fill::Interpolate => interpolate(&dates.iter().cloned().collect::<Vec<NaiveDate>>(), column),
+            };
+            for (row, value) in rows.values_mut().zip(column) {
+                row.push(value);
+            }
+        }
+
+        Frame {
+            names: series.iter().map(|ts| ts.equity_name.clone()).collect(),
+            rows,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn get(&self, date: &NaiveDate) -> Option<&[Option<Bar>]> {
+        self.rows.get(date).map(|x| x.as_slice())
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&NaiveDate, &Vec<Option<Bar>>)> {
+        self.rows.iter()
+    }
+
+    /// Rows where every column has a value. After an inner join that is every row.
+    pub fn complete_rows(&self) -> impl DoubleEndedIterator<Item = (&NaiveDate, Vec<&Bar>)> {
+        self.rows.iter().filter_map(|(date, row)| row.iter().map(|x| x.as_ref()).collect::<Option<Vec<&Bar>>>().map(|bars| (date, bars)))
+    }
+
+    /// The days of column `index` that have a value.
+    pub fn column(&self, index: usize) -> TimeSeries {
+        let mut ts = TimeSeries::new(&self.names[index]);
+        for (date, row) in &self.rows {
+            if let Some(bar) = &row[index] {
+                ts.insert(*date, bar.clone());
+            }
+        }
+        ts
+    }
+}
+
+fn forward_fill(column: Vec<Option<Bar>>) -> Vec<Option<Bar>> {
+    let mut last: Option<BigDecimal> = None;
+    column.into_iter().map(|value| match value {
+        Some(bar) => {
+            last = Some(bar.close.clone());
+            Some(bar)
+        }
+        None => last.clone().map(Bar::from_close),
+    }).collect()
+}
+
+fn interpolate(dates: &[NaiveDate], mut column: Vec<Option<Bar>>) -> Vec<Option<Bar>> {
+    let known: Vec<usize> = (0..column.len()).filter(|i| column[*i].is_some()).collect();
+    for pair in known.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let start = column[a].as_ref().unwrap().close.clone();
+        let end = column[b].as_ref().unwrap().close.clone();
+        let span = BigDecimal::from((dates[b] - dates[a]).num_days());
+        for i in a + 1..b {
+            let elapsed = BigDecimal::from((dates[i] - dates[a]).num_days());
+            column[i] = Some(Bar::from_close(&start + (&end - &start) * elapsed / &span));
+        }
+    }
+    column
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::timeseries::{Bar, TimeSeries};
+
+    use super::{Fill, Frame, Join};
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 1, d).unwrap()
+    }
+
+    fn series(name: &str, closes: &[(u32, i32)]) -> TimeSeries {
+        let mut ts = TimeSeries::new(name);
+        for (d, c) in closes {
+            ts.insert(day(*d), Bar::from_close(BigDecimal::from(*c)));
+        }
+        ts
+    }
+
+    fn closes(frame: &Frame, column: usize) -> Vec<Option<BigDecimal>> {
+        frame.iter().map(|x| x.1[column].as_ref().map(|b| b.close.clone())).collect()
+    }
+
+    #[test]
+    fn joins_and_fills() {
+        let a = series("a", &[(1, 10), (2, 11), (3, 12), (6, 15)]);
+        let b = series("b", &[(2, 20), (6, 60), (7, 70)]);
+
+        let inner = Frame::align(&[&a, &b], Join::Inner, Fill::None);
+        assert_eq!(inner.iter().map(|x| *x.0).collect::<Vec<_>>(), vec![day(2), day(6)]);
+        assert_eq!(inner.complete_rows().count(), 2);
+        assert_eq!(inner.names, vec!["a", "b"]);
+
+        let outer = Frame::align(&[&a, &b], Join::Outer, Fill::None);
+        assert_eq!(outer.len(), 5);
+        assert_eq!(outer.complete_rows().count(), 2);
+        assert_eq!(outer.column(1).len(), 3);
+        assert!(outer.get(&day(1)).unwrap()[1].is_none());
+
+        let filled = Frame::align(&[&a, &b], Join::Outer, Fill::ForwardFill);
+        let n = |x: i32| Some(BigDecimal::from(x));
+        assert_eq!(closes(&filled, 0), vec![n(10), n(11), n(12), n(15), n(15)]);
+        assert_eq!(closes(&filled, 1), vec![None, n(20), n(20), n(60), n(70)]);
+        assert_eq!(filled.get(&day(7)).unwrap()[0].as_ref().unwrap().volume, None);
+
+        // b goes from 20 on the 2nd to 60 on the 6th, so 10 per calendar day
+        let interpolated = Frame::align(&[&a, &b], Join::Outer, Fill::Interpolate);
+        assert_eq!(closes(&interpolated, 0), vec![n(10), n(11), n(12), n(15), None]);
+        assert_eq!(closes(&interpolated, 1), vec![None, n(20), n(30), n(60), n(70)]);
+    }
+}
@@ -0,0 +1,423 @@
+//! Technical indicators over a [`TimeSeries`].
+//!
+//! Every indicator returns series keyed by the dates of the input, so they line up with the
+//! price series the same way the stored 200avg does. Days before an indicator has enough
+//! history are left out instead of being filled with a guess.
+
+use std::str::FromStr;
+
+use mysql_common::bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+
+use serde::{Deserialize, Serialize};
+
+use crate::timeseries::{Bar, BarRef, TimeSeries};
+
+pub struct Macd {
+    pub macd: TimeSeries,
+    pub signal: TimeSeries,
+    pub histogram: TimeSeries,
+}
+
+pub struct BollingerBands {
+    pub middle: TimeSeries,
+    pub upper: TimeSeries,
+    pub lower: TimeSeries,
+}
+
+pub struct Stochastic {
+    pub k: TimeSeries,
+    pub d: TimeSeries,
+}
+
+/// Simple moving average of the close.
+pub fn sma(ts: &TimeSeries, window: usize) -> TimeSeries {
+    aligned(ts, &sma_values(&closes(ts), window))
+}
+
+/// Exponential moving average of the close, seeded with the simple average of the first `window` days.
+pub fn ema(ts: &TimeSeries, window: usize) -> TimeSeries {
+    aligned(ts, &ema_values(&closes(ts), window))
+}
+
+/// Linearly weighted moving average of the close, the latest day weighs `window`, the oldest 1.
+pub fn wma(ts: &TimeSeries, window: usize) -> TimeSeries {
+    let x = closes(ts);
+    let weights = (window * (window + 1) / 2) as f64;
+    let values = (0..x.len()).map(|i| {
+        if window == 0 || i + 1 < window {
+            return None;
+        }
+        let sum: f64 = x[i + 1 - window..=i].iter().enumerate().map(|(w, v)| (w + 1) as f64 * v).sum();
+        Some(sum / weights)
+    }).collect::<Vec<_>>();
+    aligned(ts, &values)
+}
+
+/// Relative strength index with Wilder's smoothing, between 0 and 100.
+pub fn rsi(ts: &TimeSeries, window: usize) -> TimeSeries {
+    let x = closes(ts);
+    let mut values = vec![None; x.len()];
+    if window == 0 || x.len() <= window {
+        return aligned(ts, &values);
+    }
+    let change = |i: usize| x[i] - x[i - 1];
+    let mut gain = (1..=window).map(|i| change(i).max(0.0)).sum::<f64>() / window as f64;
+    let mut loss = (1..=window).map(|i| (-change(i)).max(0.0)).sum::<f64>() / window as f64;
+    let n = window as f64;
+    for (i, value) in values.iter_mut().enumerate().skip(window) {
+        if i > window {
+            gain = (gain * (n - 1.0) + change(i).max(0.0)) / n;
+            loss = (loss * (n - 1.0) + (-change(i)).max(0.0)) / n;
+        }
+        *value = Some(if loss == 0.0 { 100.0 } else { 100.0 - 100.0 / (1.0 + gain / loss) });
+    }
+    aligned(ts, &values)
+}
+
+/// MACD line (fast EMA minus slow EMA), its signal EMA and the histogram between both.
+pub fn macd(ts: &TimeSeries, fast: usize, slow: usize, signal: usize) -> Macd {
+    let x = closes(ts);
+    let fast = ema_values(&x, fast);
+    let slow = ema_values(&x, slow);
+    let line: Vec<Option<f64>> = fast.iter().zip(&slow).map(|(f, s)| Some((*f)? - (*s)?)).collect();
+    let start = line.iter().position(|v| v.is_some()).unwrap_or(line.len());
+    let defined: Vec<f64> = line[start..].iter().map(|v| v.unwrap()).collect();
+    let mut signal_line = vec![None; start];
+    signal_line.extend(ema_values(&defined, signal));
+    let histogram: Vec<Option<f64>> = line.iter().zip(&signal_line).map(|(m, s)| Some((*m)? - (*s)?)).collect();
+    Macd {
+        macd: aligned(ts, &line),
+        signal: aligned(ts, &signal_line),
+        histogram: aligned(ts, &histogram),
+    }
+}
+
+/// Simple moving average with bands `width` population standard deviations above and below.
+pub fn bollinger_bands(ts: &TimeSeries, window: usize, width: f64) -> BollingerBands {
+    let x = closes(ts);
+    let middle = sma_values(&x, window);
+    let deviation: Vec<Option<f64>> = middle.iter().enumerate().map(|(i, m)| {
+        let m = (*m)?;
+        let variance = x[i + 1 - window..=i].iter().map(|v| (v - m).powi(2)).sum::<f64>() / window as f64;
+        Some(variance.sqrt())
+    }).collect();
+    let band = |sign: f64| middle.iter().zip(&deviation).map(|(m, d)| Some((*m)? + sign * width * (*d)?)).collect::<Vec<_>>();
+    BollingerBands {
+        upper: aligned(ts, &band(1.0)),
+        lower: aligned(ts, &band(-1.0)),
+        middle: aligned(ts, &middle),
+    }
+}
+
+/// Average true range with Wilder's smoothing. Days without high and low count with their close.
+pub fn atr(ts: &TimeSeries, window: usize) -> TimeSeries {
+    let bars: Vec<BarRef> = ts.iter().map(|x| x.1).collect();
+    let mut values = vec![None; bars.len()];
+    if window == 0 || bars.len() <= window {
+        return aligned(ts, &values);
+    }
+    let true_range = |i: usize| {
+        let high = to_f64(bars[i].high_or_close());
+        let low = to_f64(bars[i].low_or_close());
+        let previous = to_f64(bars[i - 1].close);
+        (high - low).max((high - previous).abs()).max((low - previous).abs())
+    };
+    let n = window as f64;
+    let mut average = (1..=window).map(true_range).sum::<f64>() / n;
+    for (i, value) in values.iter_mut().enumerate().skip(window) {
+        if i > window {
+            average = (average * (n - 1.0) + true_range(i)) / n;
+        }
+        *value = Some(average);
+    }
+    aligned(ts, &values)
+}
+
+/// Stochastic oscillator: %K is where the close lies within the last `k_window` days' range, %D its `d_window` day average.
+pub fn stochastic(ts: &TimeSeries, k_window: usize, d_window: usize) -> Stochastic {
+    let bars: Vec<BarRef> = ts.iter().map(|x| x.1).collect();
+    let k: Vec<Option<f64>> = (0..bars.len()).map(|i| {
+        if k_window == 0 || i + 1 < k_window {
+            return None;
+        }
+        let window = &bars[i + 1 - k_window..=i];
+        let highest = window.iter().map(|b| to_f64(b.high_or_close())).fold(f64::MIN, f64::max);
+        let lowest = window.iter().map(|b| to_f64(b.low_or_close())).fold(f64::MAX, f64::min);
+        if highest == lowest {
+            return Some(50.0);
+        }
+        Some(100.0 * (to_f64(bars[i].close) - lowest) / (highest - lowest))
+    }).collect();
+    let start = k.iter().position(|v| v.is_some()).unwrap_or(k.len());
+    let mut d = vec![None; start];
+    d.extend(sma_values(&k[start..].iter().map(|v| v.unwrap()).collect::<Vec<_>>(), d_window));
+    Stochastic {
+        k: aligned(ts, &k),
+        d: aligned(ts, &d),
+    }
+}
+
+/// Change of the close against `window` days earlier, in percent.
+pub fn rate_of_change(ts: &TimeSeries, window: usize) -> TimeSeries {
+    let x = closes(ts);
+    let values: Vec<Option<f64>> = (0..x.len()).map(|i| {
+        if window == 0 || i < window || x[i - window] == 0.0 {
+            return None;
+        }
+        Some(100.0 * (x[i] - x[i - window]) / x[i - window])
+    }).collect();
+    aligned(ts, &values)
+}
+
+/// Running sum of the volume, added on up days and subtracted on down days. Missing volume counts as 0.
+pub fn on_balance_volume(ts: &TimeSeries) -> TimeSeries {
+    let bars: Vec<BarRef> = ts.iter().map(|x| x.1).collect();
+    let mut total = 0.0;
+    let values: Vec<Option<f64>> = (0..bars.len()).map(|i| {
+        if i > 0 {
+            let volume = bars[i].volume.unwrap_or(0) as f64;
+            if bars[i].close > bars[i - 1].close {
+                total += volume;
+            } else if bars[i].close < bars[i - 1].close {
+                total -= volume;
+            }
+        }
+        Some(total)
+    }).collect();
+    aligned(ts, &values)
+}
+
+/// An indicator with its parameters, written like `sma:50`, `bollinger:20:2` or `macd:12:26:9` in the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Indicator {
+    Sma(usize),
+    Ema(usize),
+    Wma(usize),
+    Rsi(usize),
+    Macd(usize, usize, usize),
+    Bollinger(usize, f64),
+    Atr(usize),
+    Stochastic(usize, usize),
+    RateOfChange(usize),
+    OnBalanceVolume,
+}
+
+impl Indicator {
+    /// Whether the indicator is on the price scale and can be drawn over the price.
+    pub fn is_overlay(&self) -> bool {
+        matches!(self, Indicator::Sma(_) | Indicator::Ema(_) | Indicator::Wma(_) | Indicator::Bollinger(_, _))
+    }
+
+    /// Computes all lines of the indicator, each with a label.
+    pub fn compute(&self, ts: &TimeSeries) -> Vec<(String, TimeSeries)> {
+        match *self {
+            Indicator::Sma(n) => vec![(format!("SMA {}", n), sma(ts, n))],
+            Indicator::Ema(n) => vec![(format!("EMA {}", n), ema(ts, n))],
+            Indicator::Wma(n) => vec![(format!("WMA {}", n), wma(ts, n))],
+            Indicator::Rsi(n) => vec![(format!("RSI {}", n), rsi(ts, n))],
+            Indicator::Macd(fast, slow, signal) => {
+                let m = macd(ts, fast, slow, signal);
+                vec![(format!("MACD {}/{}", fast, slow), m.macd), (format!("Signal {}", signal), m.signal), (String::from("Histogram"), m.histogram)]
+            }
+            Indicator::Bollinger(n, width) => {
+                let b = bollinger_bands(ts, n, width);
+                vec![(format!("Bollinger {} upper", n), b.upper), (format!("Bollinger {} middle", n), b.middle), (format!("Bollinger {} lower", n), b.lower)]
+            }
+            Indicator::Atr(n) => vec![(format!("ATR {}", n), atr(ts, n))],
+            Indicator::Stochastic(k, d) => {
+                let s = stochastic(ts, k, d);
+                vec![(format!("%K {}", k), s.k), (format!("%D {}", d), s.d)]
+            }
+            Indicator::RateOfChange(n) => vec![(format!("ROC {}", n), rate_of_change(ts, n))],
+            Indicator::OnBalanceVolume => vec![(String::from("OBV"), on_balance_volume(ts))],
+        }
+    }
+}
+
+impl FromStr for Indicator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |i: usize, default: usize| match parts.get(i) {
+            Some(p) => p.parse::<usize>().map_err(|_| format!("Invalid number '{}' in indicator '{}'", p, s)),
+            None => Ok(default),
+        };
+        match parts[0] {
+            "sma" => Ok(Indicator::Sma(number(1, 200)?)),
+            "ema" => Ok(Indicator::Ema(number(1, 20)?)),
+            "wma" => Ok(Indicator::Wma(number(1, 20)?)),
+            "rsi" => Ok(Indicator::Rsi(number(1, 14)?)),
+            "macd" => Ok(Indicator::Macd(number(1, 12)?, number(2, 26)?, number(3, 9)?)),
+            "bollinger" => {
+                let width = match parts.get(2) {
+                    Some(p) => p.parse::<f64>().map_err(|_| format!("Invalid band width '{}' in indicator '{}'", p, s))?,
+                    None => 2.0,
+                };
+                Ok(Indicator::Bollinger(number(1, 20)?, width))
+            }
+            "atr" => Ok(Indicator::Atr(number(1, 14)?)),
+            "stochastic" => Ok(Indicator::Stochastic(number(1, 14)?, number(2, 3)?)),
+            "roc" => Ok(Indicator::RateOfChange(number(1, 12)?)),
+            "obv" => Ok(Indicator::OnBalanceVolume),
+            other => Err(format!("Unknown indicator '{}', expected one of sma, ema, wma, rsi, macd, bollinger, atr, stochastic, roc, obv", other)),
+        }
+    }
+}
+
+pub(crate) fn to_f64(value: &BigDecimal) -> f64 {
+    value.to_f64().unwrap()
+}
+
+pub(crate) fn closes(ts: &TimeSeries) -> Vec<f64> {
+    ts.closes().map(to_f64).collect()
+}
+
+fn sma_values(x: &[f64], window: usize) -> Vec<Option<f64>> {
+    let mut sum = 0.0;
+    (0..x.len()).map(|i| {
+        sum += x[i];
+        if window == 0 || i + 1 < window {
+            return None;
+        }
+        if i >= window {
+            sum -= x[i - window];
+        }
+        Some(sum / window as f64)
+    }).collect()
+}
+
+fn ema_values(x: &[f64], window: usize) -> Vec<Option<f64>> {
+    let mut values = vec![None; x.len()];
+    if window == 0 || x.len() < window {
+        return values;
+    }
+    let k = 2.0 / (window as f64 + 1.0);
+    let mut average = x[..window].iter().sum::<f64>() / window as f64;
+    values[window - 1] = Some(average);
+    for i in window..x.len() {
+        average += (x[i] - average) * k;
+        values[i] = Some(average);
+    }
+    values
+}
+
+/// Puts the defined values back onto the dates of `ts`.
+pub(crate) fn aligned(ts: &TimeSeries, values: &[Option<f64>]) -> TimeSeries {
+    let mut result = TimeSeries::new(&ts.equity_name);
+    for (date, value) in ts.dates().zip(values) {
+        if let Some(value) = value.filter(|v| v.is_finite()) {
+            result.insert(*date, Bar::from_close(to_decimal(value)));
+        }
+    }
+    result
+}
+
+fn to_decimal(value: f64) -> BigDecimal {
+    BigDecimal::from_f64(value).unwrap().normalized()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql::chrono::{Duration, NaiveDate};
+    use mysql_common::bigdecimal::{BigDecimal, ToPrimitive};
+
+    use crate::timeseries::{Bar, TimeSeries};
+
+    use super::*;
+
+    fn series(bars: Vec<Bar>) -> TimeSeries {
+        let mut ts = TimeSeries::new("test");
+        for (i, bar) in bars.into_iter().enumerate() {
+            ts.insert(NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + Duration::days(i as i64), bar);
+        }
+        ts
+    }
+
+    fn from_closes(closes: &[f64]) -> TimeSeries {
+        series(closes.iter().map(|c| Bar::from_close(BigDecimal::from_str(&c.to_string()).unwrap())).collect())
+    }
+
+    fn hlc(bars: &[(i32, i32, i32)]) -> TimeSeries {
+        series(bars.iter().map(|(h, l, c)| Bar { high: Some(BigDecimal::from(*h)), low: Some(BigDecimal::from(*l)), ..Bar::from_close(BigDecimal::from(*c)) }).collect())
+    }
+
+    fn values(ts: &TimeSeries) -> Vec<f64> {
+        ts.closes().map(|c| c.to_f64().unwrap()).collect()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64], tolerance: f64) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn moving_averages() {
+        let ts = from_closes(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let sma3 = sma(&ts, 3);
+        assert_eq!(sma3.first().unwrap().0, ts.nth(2).unwrap().0);
+        assert_close(&values(&sma3), &[2.0, 3.0, 4.0, 5.0], 1e-9);
+        assert_close(&values(&ema(&ts, 3)), &[2.0, 3.0, 4.0, 5.0], 1e-9);
+        assert_close(&values(&wma(&ts, 3)), &[14.0 / 6.0, 20.0 / 6.0, 26.0 / 6.0, 32.0 / 6.0], 1e-9);
+        assert!(sma(&ts, 7).is_empty());
+    }
+
+    #[test]
+    fn rsi_matches_wilder_reference() {
+        // Closes from the StockCharts RSI worksheet. The worksheet rounds its averages to two decimals,
+        // the expected values here keep full precision, which puts them up to 0.07 below the published ones.
+        let ts = from_closes(&[44.34, 44.09, 44.15, 43.61, 44.33, 44.83, 45.10, 45.42, 45.84, 46.08, 45.89, 46.03, 45.61, 46.28, 46.28,
+            46.00, 46.03, 46.41, 46.22, 45.64, 46.21, 46.25, 45.71, 46.45, 45.78, 45.35, 44.03, 44.18, 44.22, 44.57, 43.42, 42.66, 43.13]);
+        assert_close(&values(&rsi(&ts, 14)), &[70.46, 66.25, 66.48, 69.35, 66.29, 57.92, 62.88, 63.21, 56.01, 62.34, 54.67, 50.39,
+            40.02, 41.49, 41.90, 45.50, 37.32, 33.09, 37.79], 0.006);
+    }
+
+    #[test]
+    fn macd_and_bollinger() {
+        let ts = from_closes(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let m = macd(&ts, 2, 3, 2);
+        assert_close(&values(&m.macd), &[0.5, 0.5, 0.5, 0.5], 1e-9);
+        assert_close(&values(&m.signal), &[0.5, 0.5, 0.5], 1e-9);
+        assert_close(&values(&m.histogram), &[0.0, 0.0, 0.0], 1e-9);
+
+        let b = bollinger_bands(&from_closes(&[1.0, 2.0, 3.0]), 3, 2.0);
+        assert_close(&values(&b.middle), &[2.0], 1e-9);
+        assert_close(&values(&b.upper), &[3.632993], 1e-6);
+        assert_close(&values(&b.lower), &[0.367007], 1e-6);
+    }
+
+    #[test]
+    fn range_based_indicators() {
+        let ts = hlc(&[(10, 8, 9), (11, 9, 10), (12, 10, 11), (11, 8, 9)]);
+        assert_close(&values(&atr(&ts, 2)), &[2.0, 2.5], 1e-9);
+        let s = stochastic(&ts, 3, 2);
+        assert_close(&values(&s.k), &[75.0, 25.0], 1e-9);
+        assert_close(&values(&s.d), &[50.0], 1e-9);
+    }
+
+    #[test]
+    fn momentum_and_volume() {
+        let ts = from_closes(&[1.0, 2.0, 3.0, 6.0]);
+        assert_close(&values(&rate_of_change(&ts, 2)), &[200.0, 200.0], 1e-9);
+
+        let volumes = [100, 200, 300, 400, 500];
+        let ts = series([10.0, 11.0, 10.5, 10.5, 12.0].iter().zip(volumes.iter())
+            .map(|(c, v)| Bar { volume: Some(*v), ..Bar::from_close(BigDecimal::from_str(&c.to_string()).unwrap()) }).collect());
+        assert_close(&values(&on_balance_volume(&ts)), &[0.0, 200.0, -100.0, -100.0, 400.0], 1e-9);
+    }
+
+    #[test]
+    fn parses_config_names() {
+        assert_eq!(Indicator::from_str("sma:50"), Ok(Indicator::Sma(50)));
+        assert_eq!(Indicator::from_str("bollinger:20:2.5"), Ok(Indicator::Bollinger(20, 2.5)));
+        assert_eq!(Indicator::from_str("macd"), Ok(Indicator::Macd(12, 26, 9)));
+        assert!(Indicator::from_str("sma:x").is_err());
+        assert!(Indicator::from_str("vwap").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Record of every fill in a depot and the round trips made of them.
+//!
+//! A round trip starts with the first buy into an empty depot and ends with the sell that empties it
+//! again, buys and sells in between belong to it. Its P&L is what the sells brought minus what the
+//! buys cost and all fees, before taxes.
+
+use std::io::{BufWriter, Write};
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::sizing::Sizing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+/// What placed an order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OrderType {
+    /// The strategy or a rebalance.
+    Signal,
+    StopLoss,
+    TrailingStop,
+    TakeProfit,
+}
+
+impl OrderType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OrderType::Signal => "signal",
+            OrderType::StopLoss => "stop_loss",
+            OrderType::TrailingStop => "trailing_stop",
+            OrderType::TakeProfit => "take_profit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub date: NaiveDate,
+    pub side: Side,
+    #[serde(with = "crate::persist::decimal")]
+    pub shares: BigDecimal,
+    /// The price the order was placed at.
+    #[serde(with = "crate::persist::decimal")]
+    pub price: BigDecimal,
+    /// What the shares were filled for, after slippage.
+    #[serde(with = "crate::persist::decimal")]
+    pub value: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub fee: BigDecimal,
+    /// Tax paid on the sale, negative for a refund.
+    #[serde(with = "crate::persist::decimal")]
+    pub tax: BigDecimal,
+    /// Money in the depot after the fill.
+    #[serde(with = "crate::persist::decimal")]
+    pub cash: BigDecimal,
+    /// How the order was sized.
+    pub sizing: Sizing,
+    pub order: OrderType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundTrip {
+    pub entry_date: NaiveDate,
+    pub exit_date: NaiveDate,
+    /// Most shares held at once.
+    pub shares: BigDecimal,
+    pub bought: BigDecimal,
+    pub sold: BigDecimal,
+    pub fees: BigDecimal,
+    pub taxes: BigDecimal,
+    pub pnl: BigDecimal,
+}
+
+impl RoundTrip {
+    pub fn holding_days(&self) -> i64 {
+        (self.exit_date - self.entry_date).num_days()
+    }
+
+    /// P&L relative to what the buys cost, `None` if they cost nothing.
+    pub fn return_rate(&self) -> Option<f64> {
+        if self.bought.is_zero() {
+            None
+        } else {
+            (&self.pnl / &self.bought).to_f64()
+        }
+    }
+}
+
+/// The closed round trips of a ledger, a position still open at the end is left out.
+pub fn round_trips(ledger: &[LedgerEntry]) -> Vec<RoundTrip> {
+    let mut trips = Vec::new();
+    let mut open: Option<RoundTrip> = None;
+    let mut held = BigDecimal::zero();
+    for entry in ledger {
+        let trip = open.get_or_insert_with(|| RoundTrip {
+            entry_date: entry.date,
+            exit_date: entry.date,
+            shares: BigDecimal::zero(),
+            bought: BigDecimal::zero(),
+            sold: BigDecimal::zero(),
+            fees: BigDecimal::zero(),
+            taxes: BigDecimal::zero(),
+            pnl: BigDecimal::zero(),
+        });
+        match entry.side {
+            Side::Buy => {
+                held += &entry.shares;
+                trip.bought += &entry.value;
+            }
+            Side::Sell => {
+                held -= &entry.shares;
+                trip.sold += &entry.value;
+            }
+        }
+        if held > trip.shares {
+            trip.shares = held.clone();
+        }
+        trip.fees += &entry.fee;
+        trip.taxes += &entry.tax;
+        if held.is_zero() {
+            let mut trip = open.take().unwrap();
+            trip.exit_date = entry.date;
+            trip.pnl = &trip.sold - &trip.bought - &trip.fees;
+            trips.push(trip);
+        }
+    }
+    trips
+}
+
+pub fn write_ledger_csv<W: Write>(out: W, ledger: &[LedgerEntry]) {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "date,side,shares,price,value,fee,tax,cash,sizing,order").unwrap();
+    for e in ledger {
+        writeln!(out, "{},{},{},{},{},{},{},{},{},{}", e.date, e.side.name(), e.shares, e.price, e.value, e.fee, e.tax, e.cash, e.sizing, e.order.name()).expect("Couldn't write csv row!");
+    }
+    out.flush().unwrap();
+}
+
+pub fn write_trades_csv<W: Write>(out: W, trips: &[RoundTrip]) {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "entry_date,exit_date,holding_days,shares,bought,sold,fees,taxes,pnl,return").unwrap();
+    for t in trips {
+        let return_rate = t.return_rate().map_or(String::new(), |x| format!("{:.6}", x));
+        writeln!(out, "{},{},{},{},{},{},{},{},{},{}", t.entry_date, t.exit_date, t.holding_days(), t.shares, t.bought, t.sold, t.fees, t.taxes, t.pnl, return_rate).expect("Couldn't write csv row!");
+    }
+    out.flush().unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::sizing::Sizing;
+
+    use super::{round_trips, write_trades_csv, LedgerEntry, OrderType, Side};
+
+    fn entry(day: u32, side: Side, shares: u32, value: i32) -> LedgerEntry {
+        LedgerEntry {
+            date: NaiveDate::from_ymd_opt(2020, 1, day).unwrap(),
+            side,
+            shares: BigDecimal::from(shares),
+            price: BigDecimal::from(value) / BigDecimal::from(shares),
+            value: BigDecimal::from(value),
+            fee: BigDecimal::from(1),
+            tax: BigDecimal::from(0),
+            cash: BigDecimal::from(0),
+            sizing: Sizing::default(),
+            order: OrderType::Signal,
+        }
+    }
+
+    #[test]
+    fn pairs_fills_into_round_trips() {
+        let ledger = vec![
+            entry(2, Side::Buy, 10, 1000),
+            entry(3, Side::Buy, 5, 600),
+            entry(6, Side::Sell, 15, 1800),
+            entry(8, Side::Buy, 10, 1000),
+            entry(9, Side::Sell, 10, 900),
+            entry(10, Side::Buy, 10, 900),
+        ];
+        let trips = round_trips(&ledger);
+        assert_eq!(trips.len(), 2);
+        assert_eq!((trips[0].shares.clone(), trips[0].holding_days()), (BigDecimal::from(15), 4));
+        assert_eq!(trips[0].pnl, BigDecimal::from(197));
+        assert_eq!(trips[1].pnl, BigDecimal::from(-102));
+        assert!((trips[1].return_rate().unwrap() + 0.102).abs() < 1e-12);
+
+        let mut csv = Vec::new();
+        write_trades_csv(&mut csv, &trips);
+        assert_eq!(String::from_utf8(csv).unwrap().lines().nth(1).unwrap(), "2020-01-02,2020-01-06,4,15,1600,1800,3,0,197,0.123125");
+    }
+
+    #[test]
+    fn has_no_return_for_free_shares() {
+        let trips = round_trips(&[entry(2, Side::Buy, 10, 0), entry(3, Side::Sell, 10, 100)]);
+        assert_eq!(trips[0].return_rate(), None);
+        let mut csv = Vec::new();
+        write_trades_csv(&mut csv, &trips);
+        assert!(String::from_utf8(csv).unwrap().lines().nth(1).unwrap().ends_with(",98,"));
+    }
+}
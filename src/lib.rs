@@ -0,0 +1,28 @@
+pub mod config;
+pub mod stockplotter;
+pub mod numeric;
+pub mod timeseries;
+pub mod indicators;
+pub mod analytics;
+pub mod frame;
+pub mod correlation;
+pub mod benchmark;
+pub mod database;
+pub mod mysql_db;
+pub mod postgres_db;
+pub mod alphavantage;
+pub mod stock;
+pub mod strategy;
+pub mod costs;
+pub mod execution;
+pub mod tax;
+pub mod ledger;
+pub mod sizing;
+pub mod stops;
+pub mod buy;
+pub mod portfolio;
+pub mod sweep;
+pub mod walkforward;
+pub mod savings;
+pub mod export;
+pub mod persist;
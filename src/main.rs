@@ -1,19 +1,25 @@
-use config::Config;
-use crate::stock::StockRS;
-
-mod config;
-mod stockplotter;
-mod timeseries;
-mod mysql_db;
-mod alphavantage;
-mod stock;
-mod buy;
+use rusty_stocks::{config::Config, stock::StockRS};
 
 fn main() {
     let config = Config::read_config();
     let mut stocks = StockRS::from_config(&config);
-    stocks.update_db();
-    stocks.plot();
-    stocks.backtest();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        None => {
+            stocks.update_db();
+            stocks.plot();
+            stocks.backtest();
+        }
+        Some("export") => stocks.export(&args[1..], &config),
+        Some("stats") => stocks.print_stats(&args[1..], &config),
+        Some("correlation") => stocks.correlation(&args[1..], &config),
+        Some("backtest") => stocks.backtest_command(&args[1..]),
+        Some("portfolio") => stocks.portfolio(&args[1..], &config),
+        Some("sweep") => stocks.sweep(&args[1..], &config),
+        Some("walkforward") => stocks.walk_forward(&args[1..], &config),
+        Some("savings") => stocks.savings(&args[1..], &config),
+        Some("save") => stocks.save_series(&args[1..]),
+        Some(other) => panic!("Unknown command: {}. Available commands: export, stats, correlation, backtest, portfolio, sweep, walkforward, savings, save", other),
+    }
 }
 
@@ -1,18 +1,23 @@
-use std::{collections::BTreeMap, str::FromStr};
+use std::str::FromStr;
 
-use mysql::{Pool, PooledConn, chrono::NaiveDate, params, prelude::Queryable};
+use mysql::{Pool, PooledConn, chrono::{NaiveDate, NaiveDateTime}, params, prelude::Queryable};
 use mysql_common::bigdecimal::BigDecimal;
 
-use crate::{alphavantage::AlphaVantage, config::Config, timeseries::TimeSeries};
+use crate::{config::Config, database::{Database, Revision, SeriesKind}, timeseries::{Bar, TimeSeries}};
 
-pub struct Database {
+pub struct MysqlDatabase {
     pub pool: Pool,
     pub conn: PooledConn,
+    pub revision_window: usize,
 }
 
-impl Database {
+impl MysqlDatabase {
     pub fn from_config(config: &Config) -> Self {
-        let pool = match Pool::new(&config.mysql_url) {
+        MysqlDatabase::connect(&config.database_url, config.revision_window)
+    }
+
+    pub fn connect(url: &str, revision_window: usize) -> Self {
+        let pool = match Pool::new(url) {
             Ok(pool) => pool,
             Err(error) => panic!("Unable to create mysql pool: {}", error)
         };
@@ -21,155 +26,179 @@ impl Database {
             Err(error) => panic!("Unable to create connection from pool: {}", error)
         };
 
-        Database {
+        MysqlDatabase {
             pool,
             conn,
+            revision_window,
+        }
+    }
+
+    /// Adds the bar columns to tables created before they existed.
+    fn add_missing_columns(&mut self, table_name: &str) {
+        for (column, definition) in BAR_COLUMNS {
+            let count: i32 = self.conn.exec_first("SELECT COUNT(*) FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = :table_name AND column_name = :column",
+                params! { "table_name" => table_name, "column" => column }).unwrap().unwrap();
+            if count == 0 {
+                self.conn.query_drop(format!("ALTER TABLE {} ADD COLUMN {} {}", table_name, column, definition)).expect("Couldn't add missing column!");
+            }
         }
     }
+}
+
+/// Nullable columns besides close and split, added after the original layout.
+const BAR_COLUMNS: [(&str, &str); 6] = [
+    ("open_value", "decimal(11, 2) null"),
+    ("high_value", "decimal(11, 2) null"),
+    ("low_value", "decimal(11, 2) null"),
+    ("adjusted_close", "decimal(11, 2) null"),
+    ("volume", "bigint unsigned null"),
+    ("dividend", "decimal(11, 4) null"),
+];
+
+impl Database for MysqlDatabase {
+    fn revision_window(&self) -> usize {
+        self.revision_window
+    }
 
-    pub fn update(&mut self, symbol: &str, alphavantage: &AlphaVantage) {
+    fn create_tables(&mut self, symbol: &str) {
         // Create raw and adjusted table if not exists
         self.conn.query_drop(format!("
             create table if not exists {}_raw (
             entry_date DATE not null primary key,
             close_value decimal(11, 2) not null,
-            split_coefficient decimal(4, 2) not null)", 
+            split_coefficient decimal(4, 2) not null)",
             symbol)).unwrap();
-        
+        self.add_missing_columns(&format!("{}_raw", symbol));
+
         self.conn.query_drop(format!("
             create table if not exists {}_adjusted (
             entry_date DATE not null primary key,
             close_value decimal(11, 2) not null,
-            split_coefficient decimal(4, 2) not null)", 
+            split_coefficient decimal(4, 2) not null)",
+            symbol)).unwrap();
+        self.add_missing_columns(&format!("{}_adjusted", symbol));
+
+        self.conn.query_drop(format!("
+            create table if not exists {}_revisions (
+            revision_id int not null auto_increment primary key,
+            entry_date DATE not null,
+            old_close decimal(11, 2) not null,
+            new_close decimal(11, 2) not null,
+            old_split_coefficient decimal(4, 2) not null,
+            new_split_coefficient decimal(4, 2) not null,
+            fetched_at DATETIME not null)",
             symbol)).unwrap();
 
         self.conn.query_drop(format!("
             create table if not exists {}_200avg (
             entry_date DATE not null primary key,
-            close_value decimal(11, 2) not null)", 
+            close_value decimal(11, 2) not null)",
             symbol)).unwrap();
-            
-        //Check if raw table is empty, if it is, do a large fetch
-        let size: i32 = self.conn.query_first(format!("SELECT COUNT(*) FROM {}_raw", symbol)).expect("Couldn't query count(*) from table!").unwrap();
-        if size == 0 {
-            // Insert raw data into raw table
-            let mut full = alphavantage.fetch_daily(symbol, false);
-            let stmt = self.conn.prep(format!("INSERT INTO {}_raw (entry_date, close_value, split_coefficient) VALUES (:entry_date, :close_value, :split_coefficient)", symbol)).unwrap();
-            self.conn.exec_batch(stmt, full.entries.iter().map( |p| params! {
-                "entry_date" => p.0,
-                "close_value" => p.1.0.to_string(),
-                "split_coefficient" => p.1.1,
-            })).expect("Couldn't insert entries into database!");
+    }
 
-            // Reset then Insert adjusted data into adjusted table
-            self.conn.query_drop(format!("DELETE FROM {}_adjusted", symbol)).unwrap();
-            full.correct_splits();
-            let stmt = self.conn.prep(format!("INSERT INTO {}_adjusted (entry_date, close_value, split_coefficient) VALUES (:entry_date, :close_value, :split_coefficient)", symbol)).unwrap();
-            self.conn.exec_batch(stmt, full.entries.iter().map( |p| params! {
+    fn drop_tables(&mut self, symbol: &str) {
+        self.conn.query_drop(format!("DROP TABLE IF EXISTS {}_raw, {}_adjusted, {}_200avg, {}_revisions", symbol, symbol, symbol, symbol)).expect("Couldn't drop tables!");
+    }
+
+    fn write_entries(&mut self, symbol: &str, series: SeriesKind, entries: &TimeSeries) {
+        if series == SeriesKind::Avg200 {
+            let stmt = self.conn.prep(format!("REPLACE INTO {} (entry_date, close_value) VALUES (:entry_date, :close_value)", series.table_name(symbol))).unwrap();
+            self.conn.exec_batch(stmt, entries.iter().map( |p| params! {
                 "entry_date" => p.0,
-                "close_value" => p.1.0.to_string(),
-                "split_coefficient" => p.1.1,
+                "close_value" => p.1.close.to_string(),
             })).expect("Couldn't insert entries into database!");
-
-            //Calculate all 200avgs
-            for i in 200..full.entries.len() {
-                self.conn.query_drop(format!("Insert into {}_200avg (entry_date, close_value) values('{}', (with temp as ( select close_value from {}_adjusted where entry_date <= '{}' order by entry_date desc limit 200) select avg(close_value) from temp))", symbol, full.nth(i).0, symbol, full.nth(i).0)).expect("Couldnt calculate 200avg!");
-            }
-            
         } else {
-            // Else insert only new data
-            let mut timeseries = alphavantage.fetch_daily(symbol, true);
-            // Check if compact covers the new data
-            let max_date: NaiveDate = self.conn.query(format!("SELECT MAX(entry_date) FROM {}_raw", symbol)).unwrap()[0];
-            if max_date < timeseries.nth(0).0 {
-                timeseries = alphavantage.fetch_daily(symbol, false);
-            }
-            // Insert new data
-            let stmt = self.conn.prep(format!("INSERT INTO {}_raw (entry_date, close_value, split_coefficient) VALUES (:entry_date, :close_value, :split_coefficient)", symbol)).unwrap();
-            let mut new_count_entries = 0;
-            for i in (0..timeseries.entries.len()).rev() {
-                if max_date < timeseries.nth(i).0 {
-                    self.conn.exec_drop(&stmt, params! {
-                        "entry_date" => timeseries.nth(i).0,
-                        "close_value" => timeseries.nth(i).1.0.to_string(),
-                        "split_coefficient" => timeseries.nth(i).1.1,
-                    }).expect("Couldn't insert new data!");
-                } else {
-                    new_count_entries = timeseries.entries.len() - i - 1;
-                    break
-                }
-            }
-            // Insert into adjusted and adjust whole table in case of a new split
-            let actually_new_entries = &timeseries.between(timeseries.entries.len()-new_count_entries, timeseries.entries.len());
-            let stmt = self.conn.prep(format!("REPLACE INTO {}_adjusted (entry_date, close_value, split_coefficient) VALUES (:entry_date, :close_value, :split_coefficient)", symbol)).unwrap();
-            let mut new_splits: BTreeMap<NaiveDate, f32> = BTreeMap::new();
-            // Insert actually new entries into adjusted and 200avg
-            actually_new_entries.iter().for_each(|x| {
-                if x.1.1.unwrap() != 1.0 {
-                    new_splits.insert(x.0, x.1.1.unwrap());
-                }
-                self.conn.exec_drop(&stmt, params! {
-                    "entry_date" => x.0,
-                    "close_value" => x.1.0.to_string(),
-                    "split_coefficient" => x.1.1,
-                }).expect("Couldn't insert new data!");
-                self.conn.query_drop(format!("Insert into {}_200avg (entry_date, close_value) values('{}', (with temp as ( select close_value from tsla_adjusted where entry_date <= '{}' order by entry_date desc limit 200) select avg(close_value) from temp))", symbol, x.0, x.0)).expect("Couldnt calculate 200avg!");
-            });
-            //If a split occurs, updat adjusted for all of them
-            for d in new_splits.keys() {
-                let date: &str = &d.to_string();
-                self.conn.query_drop(format!("UPDATE {}_adjusted SET close_value = close_value/{} where entry_date <= '{}'", symbol, new_splits.get(d).unwrap().to_string(), date)).expect("Couldn't execute update query!");
-            }
-            //If splits occured, update the 200 avgs
-            if !new_splits.is_empty() {
-                let result: Vec<NaiveDate> = self.conn.query(format!("SELECT entry_date from {}_adjusted", symbol)).unwrap();
-                for d in result {
-                    self.conn.query_drop(format!("update {}_200avg set close_value = (with temp as ( select close_value from tsla_adjusted where entry_date <= '{}' order by entry_date desc limit 200) select avg(close_value) from temp) where entry_date <= '{}'", symbol, d.to_string(), d.to_string())).expect("Couldn't update 200avgs!")
-                }
-            }
+            let stmt = self.conn.prep(format!("REPLACE INTO {} (entry_date, close_value, split_coefficient, open_value, high_value, low_value, adjusted_close, volume, dividend)
+                VALUES (:entry_date, :close_value, :split_coefficient, :open_value, :high_value, :low_value, :adjusted_close, :volume, :dividend)", series.table_name(symbol))).unwrap();
+            self.conn.exec_batch(stmt, entries.iter().map( |p| params! {
+                "entry_date" => p.0,
+                "close_value" => p.1.close.to_string(),
+                "split_coefficient" => p.1.split().to_string(),
+                "open_value" => p.1.open.as_ref().map(|x| x.to_string()),
+                "high_value" => p.1.high.as_ref().map(|x| x.to_string()),
+                "low_value" => p.1.low.as_ref().map(|x| x.to_string()),
+                "adjusted_close" => p.1.adjusted_close.as_ref().map(|x| x.to_string()),
+                "volume" => p.1.volume,
+                "dividend" => p.1.dividend.as_ref().map(|x| x.to_string()),
+            })).expect("Couldn't insert entries into database!");
         }
     }
 
-    pub fn get_timeseries_between(&mut self, symbol: &str, table_name: &str, start_date: NaiveDate, end_date: NaiveDate) -> TimeSeries {
+    fn record_revisions(&mut self, symbol: &str, revisions: &[Revision]) {
+        let stmt = self.conn.prep(format!("INSERT INTO {}_revisions (entry_date, old_close, new_close, old_split_coefficient, new_split_coefficient, fetched_at) VALUES (:entry_date, :old_close, :new_close, :old_split_coefficient, :new_split_coefficient, :fetched_at)", symbol)).unwrap();
+        self.conn.exec_batch(stmt, revisions.iter().map(|r| params! {
+            "entry_date" => r.entry_date,
+            "old_close" => r.old_close.to_string(),
+            "new_close" => r.new_close.to_string(),
+            "old_split_coefficient" => r.old_split_coefficient.to_string(),
+            "new_split_coefficient" => r.new_split_coefficient.to_string(),
+            "fetched_at" => r.fetched_at,
+        })).expect("Couldn't record revisions!");
+    }
 
-        
-        if table_name.contains("200avg") {
-            let mut entries: BTreeMap<NaiveDate, (BigDecimal, Option<f32>)> = BTreeMap::new();
+    fn get_revisions(&mut self, symbol: &str) -> Vec<Revision> {
+        let result: Vec<(NaiveDate, String, String, String, String, NaiveDateTime)> = match self.conn.query(format!("SELECT entry_date, old_close, new_close, old_split_coefficient, new_split_coefficient, fetched_at FROM {}_revisions ORDER BY revision_id", symbol)) {
+            Ok(result) => result,
+            Err(e) => panic!("Couldn't query the revisions from database: {}", e),
+        };
+        result.into_iter().map(|r| Revision {
+            entry_date: r.0,
+            old_close: BigDecimal::from_str(&r.1).unwrap(),
+            new_close: BigDecimal::from_str(&r.2).unwrap(),
+            old_split_coefficient: BigDecimal::from_str(&r.3).unwrap(),
+            new_split_coefficient: BigDecimal::from_str(&r.4).unwrap(),
+            fetched_at: r.5,
+        }).collect()
+    }
+
+    fn get_timeseries_between(&mut self, symbol: &str, table_name: &str, start_date: NaiveDate, end_date: NaiveDate) -> TimeSeries {
+        let mut entries = TimeSeries::new(symbol);
 
-            let result: Vec<(NaiveDate, String)> = match self.conn.exec(format!("SELECT * FROM {} WHERE entry_date >= :start_date and entry_date <= :end_date", table_name), params! { "start_date" => start_date, "end_date" => end_date }) {
+        if table_name.contains("200avg") {
+            let result: Vec<(NaiveDate, String)> = match self.conn.exec(format!("SELECT entry_date, close_value FROM {} WHERE entry_date >= :start_date and entry_date <= :end_date ORDER BY entry_date", table_name), params! { "start_date" => start_date, "end_date" => end_date }) {
                 Ok(result) => result,
                 Err(e) => panic!("Couldn't query the timeseries form database: {}", e),
             };
-    
+
             for i in result {
-                entries.insert(i.0, (BigDecimal::from_str(&i.1).unwrap(), None));
+                entries.insert(i.0, Bar::from_close(BigDecimal::from_str(&i.1).unwrap()));
             }
-    
-            TimeSeries {
-                equity_name: String::from_str(symbol).unwrap(),
-                entries,
-            }
-
-            
         } else {
-            let mut entries: BTreeMap<NaiveDate, (BigDecimal, Option<f32>)> = BTreeMap::new();
-
-            let result: Vec<(NaiveDate, String, Option<f32>)> = match self.conn.exec(format!("SELECT * FROM {} WHERE entry_date >= :start_date and entry_date <= :end_date", table_name), params! { "start_date" => start_date, "end_date" => end_date }) {
+            type Row = (NaiveDate, String, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>, Option<u64>, Option<String>);
+            let result: Vec<Row> = match self.conn.exec(format!("SELECT entry_date, close_value, split_coefficient, open_value, high_value, low_value, adjusted_close, volume, dividend FROM {} WHERE entry_date >= :start_date and entry_date <= :end_date ORDER BY entry_date", table_name), params! { "start_date" => start_date, "end_date" => end_date }) {
                 Ok(result) => result,
                 Err(e) => panic!("Couldn't query the timeseries form database: {}", e),
             };
-    
+
+            let decimal = |x: Option<String>| x.map(|x| BigDecimal::from_str(&x).unwrap());
             for i in result {
-                entries.insert(i.0, (BigDecimal::from_str(&i.1).unwrap(), i.2));
-            }
-    
-            TimeSeries {
-                equity_name: String::from_str(symbol).unwrap(),
-                entries,
+                entries.insert(i.0, Bar {
+                    open: decimal(i.3),
+                    high: decimal(i.4),
+                    low: decimal(i.5),
+                    close: BigDecimal::from_str(&i.1).unwrap(),
+                    adjusted_close: decimal(i.6),
+                    volume: i.7,
+                    dividend: decimal(i.8),
+                    split_coefficient: decimal(i.2),
+                });
             }
         }
-        
+
+        entries
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::conformance;
+
+    use super::MysqlDatabase;
 
-    
-}
\ No newline at end of file
+    #[test]
+    #[ignore = "needs a mysql server, run with STOCKS_TEST_MYSQL_URL set and --ignored"]
+    fn conformance() {
+        let url = std::env::var("STOCKS_TEST_MYSQL_URL").expect("STOCKS_TEST_MYSQL_URL has to point to a test database");
+        conformance::run(&mut MysqlDatabase::connect(&url, 10));
+    }
+}
@@ -0,0 +1,135 @@
+//! How numbers are represented and rounded across the crate.
+//!
+//! Prices, money, split coefficients and ratios that decide trades are `BigDecimal`, never floats.
+//! Rounding is always explicit: the stored tables round half up to their column scale like the
+//! databases do on insert, everything the backtest computes follows the [`NumericPolicy`] from the
+//! config. Statistics like indicators, analytics and correlations are not money and use `f64`.
+
+use std::str::FromStr;
+
+use mysql_common::bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+
+/// Scale of the price columns, `decimal(11, 2)`.
+pub const PRICE_SCALE: i64 = 2;
+/// Scale of the split coefficient columns, `decimal(4, 2)`.
+pub const SPLIT_SCALE: i64 = 2;
+/// Scale of the dividend columns, `decimal(11, 4)`.
+pub const DIVIDEND_SCALE: i64 = 4;
+/// Money is kept in cents.
+pub const MONEY_SCALE: i64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Rounding {
+    /// Half away from zero, 2.345 -> 2.35 and -2.345 -> -2.35.
+    HalfUp,
+    /// Half to the even neighbour, 2.345 -> 2.34 and 2.355 -> 2.36.
+    HalfEven,
+    /// Towards zero, 2.349 -> 2.34.
+    Down,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half_up" => Ok(Rounding::HalfUp),
+            "half_even" => Ok(Rounding::HalfEven),
+            "down" => Ok(Rounding::Down),
+            other => Err(format!("Unknown rounding mode '{}', expected half_up, half_even or down", other)),
+        }
+    }
+}
+
+/// Rounding of computed values, configured with `rounding` and `precision`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NumericPolicy {
+    pub rounding: Rounding,
+    /// Decimal places of ratios like close / 200avg before they are compared.
+    pub precision: i64,
+}
+
+impl Default for NumericPolicy {
+    fn default() -> Self {
+        NumericPolicy {
+            rounding: Rounding::HalfUp,
+            precision: 6,
+        }
+    }
+}
+
+impl NumericPolicy {
+    pub fn ratio(&self, value: &BigDecimal) -> BigDecimal {
+        round(value, self.precision, self.rounding)
+    }
+
+    pub fn money(&self, value: &BigDecimal) -> BigDecimal {
+        round(value, MONEY_SCALE, self.rounding)
+    }
+
+    pub fn price(&self, value: &BigDecimal) -> BigDecimal {
+        round(value, PRICE_SCALE, self.rounding)
+    }
+}
+
+pub fn round(value: &BigDecimal, scale: i64, rounding: Rounding) -> BigDecimal {
+    // with_scale cuts off towards zero
+    let truncated = value.with_scale(scale);
+    let step = BigDecimal::new(1.into(), scale);
+    let twice_remainder = (value - &truncated).abs() * BigDecimal::from(2);
+    let away = match rounding {
+        Rounding::Down => false,
+        Rounding::HalfUp => twice_remainder >= step,
+        Rounding::HalfEven => twice_remainder > step || (twice_remainder == step && is_odd(&truncated, scale)),
+    };
+    match (away, value < &BigDecimal::from(0)) {
+        (false, _) => truncated,
+        (true, true) => truncated - step,
+        (true, false) => truncated + step,
+    }
+}
+
+/// Rounds half away from zero, like a DECIMAL column does on insert.
+pub fn round_half_up(value: &BigDecimal, scale: i64) -> BigDecimal {
+    round(value, scale, Rounding::HalfUp)
+}
+
+fn is_odd(truncated: &BigDecimal, scale: i64) -> bool {
+    let last_digit = (truncated * BigDecimal::new(1.into(), -scale)) % BigDecimal::from(2);
+    last_digit != BigDecimal::from(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use super::{round, Rounding};
+
+    fn d(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let cases = [
+            ("2.345", "2.35", "2.34", "2.34"),
+            ("2.355", "2.36", "2.36", "2.35"),
+            ("2.3451", "2.35", "2.35", "2.34"),
+            ("-2.345", "-2.35", "-2.34", "-2.34"),
+            ("-2.349", "-2.35", "-2.35", "-2.34"),
+            ("2.3", "2.30", "2.30", "2.30"),
+        ];
+        for (value, half_up, half_even, down) in cases.iter() {
+            assert_eq!(round(&d(value), 2, Rounding::HalfUp), d(half_up), "half up of {}", value);
+            assert_eq!(round(&d(value), 2, Rounding::HalfEven), d(half_even), "half even of {}", value);
+            assert_eq!(round(&d(value), 2, Rounding::Down), d(down), "down of {}", value);
+        }
+        assert_eq!(round(&d("12.5"), 0, Rounding::HalfEven), d("12"));
+        assert_eq!(round(&d("13.5"), 0, Rounding::HalfEven), d("14"));
+        // 1.03 from a float would not be exactly 1.03
+        assert_eq!(round(&(d("103") / d("100")), 6, Rounding::HalfUp), d("1.03"));
+    }
+}
@@ -0,0 +1,412 @@
+//! Backtests of several stocks sharing one cash balance.
+//!
+//! Every [`Asset`] has a target weight of the portfolio's value, weights adding up to less than 1 leave
+//! the rest in cash. An asset can have a timing strategy like the 200 day filter, its signal scales the
+//! weight: flat moves that part to cash until the strategy goes long again. A timed asset stays in cash
+//! until its strategy first decides. Strategies see the bars at the configured frequency.
+//!
+//! The portfolio is brought back to the targets on the first day, whenever a timing signal changes and
+//! then on the [`Rebalance`] rule, filled like single backtests with the configured execution. Sells go
+//! first so the buys can use their money. Each asset trades through its own [`Depot`] with the
+//! configured costs, taxes and order rules, the cash and the tax years are shared, so a loss on one
+//! asset offsets gains on another. Only days every asset was traded on are used.
+
+use std::{collections::{BTreeMap, VecDeque}, fmt, mem, str::FromStr};
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::{analytics::{self, TRADING_DAYS}, benchmark::Comparison, buy::{Backtest, Depot, Metrics, Run, RunState}, costs::Slippage, database::Database, execution::Execution, frame::{Fill, Frame, Join}, ledger::{self, LedgerEntry, Side}, persist::Persist, strategy::{Context, Signal, Strategy, StrategySpec}, tax::TaxYear, timeseries::{Bar, Frequency, TimeSeries}};
+
+/// Written like `monthly`, `quarterly` or `drift:0.05` in the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Rebalance {
+    /// On the first day of every period.
+    Schedule(Frequency),
+    /// When an asset's share of the value is further than this from its target.
+    Drift(#[serde(with = "crate::persist::decimal")] BigDecimal),
+}
+
+impl FromStr for Rebalance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<&str>>()[..] {
+            ["drift", threshold] => match BigDecimal::from_str(threshold) {
+                Ok(x) if x > BigDecimal::zero() => Ok(Rebalance::Drift(x)),
+                _ => Err(format!("Invalid threshold '{}' in rebalance '{}'", threshold, s)),
+            },
+            [name] => Frequency::from_str(name).map(Rebalance::Schedule).map_err(|_| format!("Unknown rebalance '{}', expected a frequency like monthly or drift:<threshold>", s)),
+            _ => Err(format!("Unknown rebalance '{}', expected a frequency like monthly or drift:<threshold>", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Asset {
+    pub symbol: String,
+    /// Share of the portfolio's value.
+    #[serde(with = "crate::persist::decimal")]
+    pub weight: BigDecimal,
+    /// Strategy whose signal scales the weight, always fully weighted without one.
+    pub timing: Option<StrategySpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioConfig {
+    pub assets: Vec<Asset>,
+    pub rebalance: Rebalance,
+}
+
+/// The cash and one depot per asset. The money of the depots is unused, they trade with the cash.
+pub struct Portfolio {
+    pub cash: BigDecimal,
+    pub depots: Vec<Depot>,
+    pub tax_years: BTreeMap<i32, TaxYear>,
+    /// When rebalances are filled, after the close they were decided on.
+    pub execution: Execution,
+}
+
+impl Portfolio {
+    pub fn new(cash: &BigDecimal, depots: Vec<Depot>) -> Self {
+        // Stops are left to single backtests, a stopped out asset would sit in cash until the next rebalance
+        let depots = depots.into_iter().map(|depot| Depot { money: BigDecimal::zero(), stops: Vec::new(), ..depot }).collect();
+        Portfolio { cash: cash.clone(), depots, tax_years: BTreeMap::new(), execution: Execution::default() }
+    }
+
+    pub fn with_execution(mut self, execution: Execution) -> Self {
+        self.execution = execution;
+        self
+    }
+
+    /// Cash plus all shares at `prices`, one per depot.
+    pub fn value(&self, prices: &[&BigDecimal]) -> BigDecimal {
+        self.depots.iter().zip(prices).fold(self.cash.clone(), |sum, (depot, price)| sum + &depot.shares * *price)
+    }
+
+    /// Largest distance of an asset's share of the value from its target.
+    pub fn drift(&self, targets: &[BigDecimal], prices: &[&BigDecimal]) -> BigDecimal {
+        let total = self.value(prices);
+        if total <= BigDecimal::zero() {
+            return BigDecimal::zero();
+        }
+        self.depots.iter().zip(targets).zip(prices)
+            .map(|((depot, target), price)| (&depot.shares * *price / &total - target).abs())
+            .fold(BigDecimal::zero(), |max, x| if x > max { x } else { max })
+    }
+
+    /// Trades every asset to about its target share of the value, sells first. Buys are capped by
+    /// what the cash pays for. `states` has one run state per depot.
+    pub fn rebalance(&mut self, targets: &[BigDecimal], prices: &[&BigDecimal], states: &mut [RunState]) {
+        let total = self.value(prices);
+        let wanted: Vec<BigDecimal> = self.depots.iter().zip(targets).zip(prices)
+            .map(|((depot, target), price)| depot.orders.sizing.round_down(&(&total * target / *price)))
+            .collect();
+        for (index, price) in prices.iter().enumerate() {
+            if wanted[index] < self.depots[index].shares {
+                let amount = &self.depots[index].shares - &wanted[index];
+                self.trade(index, |depot| depot.sell(&amount, price, &mut states[index]));
+            }
+        }
+        for (index, price) in prices.iter().enumerate() {
+            if wanted[index] > self.depots[index].shares {
+                self.trade(index, |depot| {
+                    let amount = (&wanted[index] - &depot.shares).min(depot.affordable(price, &states[index]));
+                    depot.buy(&amount, price, &mut states[index]);
+                });
+            }
+        }
+    }
+
+    /// Hands the cash and the tax years to the depot at `index` for one trade.
+    pub fn trade<F: FnOnce(&mut Depot)>(&mut self, index: usize, f: F) {
+        let depot = &mut self.depots[index];
+        depot.money = mem::replace(&mut self.cash, BigDecimal::zero());
+        mem::swap(&mut depot.tax_years, &mut self.tax_years);
+        f(depot);
+        mem::swap(&mut depot.tax_years, &mut self.tax_years);
+        self.cash = mem::replace(&mut depot.money, BigDecimal::zero());
+    }
+}
+
+/// Trades the portfolio over the common days of `series`, one series and one timing per asset of the
+/// config. The timings get the bars at `frequency`, from the close of the bar's last day on. The
+/// portfolio is valued at every day's close. Whatever is held after the last day is sold.
+pub fn run(config: &PortfolioConfig, timings: &mut [Option<Box<dyn Strategy>>], series: &[TimeSeries], averages: &[TimeSeries], frequency: Frequency, portfolio: &mut Portfolio) -> Run {
+    let frame = Frame::align(&series.iter().collect::<Vec<&TimeSeries>>(), Join::Inner, Fill::None);
+    let columns: Vec<TimeSeries> = (0..series.len()).map(|i| frame.column(i).resample(frequency)).collect();
+    let averages: Vec<TimeSeries> = averages.iter().map(|ts| ts.resample(frequency)).collect();
+    let indicators: Vec<Vec<(String, TimeSeries)>> = timings.iter().zip(&columns)
+        .map(|(timing, ts)| timing.as_ref().map_or(Vec::new(), |t| t.indicators().iter().flat_map(|x| x.compute(ts)).collect()))
+        .collect();
+    let volatility: Vec<TimeSeries> = portfolio.depots.iter().zip(&columns).map(|(depot, ts)| match depot.costs.slippage {
+        Slippage::Volatility(_, window) => analytics::rolling_volatility(ts, window),
+        _ => TimeSeries::default(),
+    }).collect();
+
+    let mut gates: Vec<BigDecimal> = timings.iter().map(|t| BigDecimal::from(if t.is_some() { 0 } else { 1 })).collect();
+    let mut last_rebalance: Option<NaiveDate> = None;
+    let mut equity = TimeSeries::new("portfolio");
+    let mut invested_days = 0;
+    let mut orders = VecDeque::new();
+    let mut states = vec![RunState::default(); portfolio.depots.len()];
+    for (index, (date, bars)) in frame.complete_rows().enumerate() {
+        let prices: Vec<&BigDecimal> = bars.iter().map(|bar| &bar.close).collect();
+        let mut changed = false;
+        for (i, depot) in portfolio.depots.iter().enumerate() {
+            states[i].date = *date;
+            let bar_volatility = volatility[i].get(date).and_then(|x| x.close.to_f64()).map(|x| x / TRADING_DAYS.sqrt());
+            states[i].slippage_rate = depot.costs.slippage_rate(bar_volatility);
+        }
+        fill_rebalances(portfolio, &mut orders, index, &bars, &mut states);
+        for (i, timing) in timings.iter_mut().enumerate() {
+            // Resampled bars carry the date of their last day
+            if let (Some(strategy), Ok(position)) = (timing, columns[i].position(date)) {
+                let context = Context {
+                    date: *date,
+                    bar: columns[i].bar(position),
+                    avg200: averages[i].get(date),
+                    shares: portfolio.depots[i].shares.clone(),
+                    index: position,
+                    series: &columns[i],
+                    indicators: &indicators[i],
+                };
+                if let Signal::Target(fraction) = strategy.next(&context) {
+                    changed |= fraction != gates[i];
+                    gates[i] = fraction;
+                }
+            }
+        }
+        let targets: Vec<BigDecimal> = config.assets.iter().zip(&gates).map(|(asset, gate)| &asset.weight * gate).collect();
+        let due = match &config.rebalance {
+            Rebalance::Schedule(frequency) => last_rebalance.is_none_or(|last| !frequency.same_period(&last, date)),
+            Rebalance::Drift(threshold) => last_rebalance.is_none() || &portfolio.drift(&targets, &prices) > threshold,
+        };
+        if due || changed {
+            orders.push_back((index + portfolio.execution.offset(), targets));
+            last_rebalance = Some(*date);
+        }
+        fill_rebalances(portfolio, &mut orders, index, &bars, &mut states);
+        if portfolio.depots.iter().any(|depot| depot.shares > BigDecimal::zero()) {
+            invested_days += 1;
+        }
+        equity.insert(*date, Bar::from_close(portfolio.value(&prices)));
+    }
+    if let Some((date, bars)) = frame.complete_rows().next_back() {
+        for (index, bar) in bars.iter().enumerate() {
+            portfolio.trade(index, |depot| depot.full_sell(&bar.close, &mut states[index]));
+        }
+        equity.insert(*date, Bar::from_close(portfolio.cash.clone()));
+    }
+    Run {
+        exposure: if equity.is_empty() { 0.0 } else { invested_days as f64 / equity.len() as f64 },
+        equity,
+        flows: Vec::new(),
+    }
+}
+
+/// Rebalances to the targets decided for day `index`, at the execution's price of that day.
+fn fill_rebalances(portfolio: &mut Portfolio, orders: &mut VecDeque<(usize, Vec<BigDecimal>)>, index: usize, bars: &[&Bar], states: &mut [RunState]) {
+    while orders.front().is_some_and(|(day, _)| *day == index) {
+        let (_, targets) = orders.pop_front().unwrap();
+        let prices: Vec<&BigDecimal> = bars.iter().map(|bar| portfolio.execution.price(bar.view())).collect();
+        portfolio.rebalance(&targets, &prices, states);
+    }
+}
+
+/// Runs the configured portfolio on the adjusted series of its assets.
+pub fn backtest(backtest: &Backtest, config: &PortfolioConfig, db: &mut dyn Database, start_date: NaiveDate, end_date: NaiveDate) -> PortfolioResult {
+    for asset in &config.assets {
+        db.create_tables(&asset.symbol);
+    }
+    let series: Vec<TimeSeries> = config.assets.iter().map(|a| db.get_timeseries_between(&a.symbol, &format!("{}_adjusted", a.symbol), start_date, end_date)).collect();
+    let averages: Vec<TimeSeries> = config.assets.iter().map(|a| db.get_timeseries_between(&a.symbol, &format!("{}_200avg", a.symbol), start_date, end_date)).collect();
+    let mut timings: Vec<Option<Box<dyn Strategy>>> = config.assets.iter().map(|a| a.timing.as_ref().map(|spec| spec.build(&backtest.avg200_range, &backtest.numeric))).collect();
+    let mut portfolio = Portfolio::new(&backtest.start_depot, config.assets.iter().map(|a| backtest.depot(&a.symbol)).collect()).with_execution(backtest.execution);
+    let run = run(config, &mut timings, &series, &averages, backtest.frequency, &mut portfolio);
+    let mut result = PortfolioResult::of(config, &backtest.start_depot, run, portfolio, backtest.risk_free_rate, start_date, end_date);
+    result.benchmark = backtest.configured_benchmark(db, start_date, end_date).map(|(name, benchmark)| Comparison::of(&name, &result.equity, &benchmark, backtest.risk_free_rate));
+    result
+}
+
+/// What one asset added to the portfolio.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attribution {
+    pub symbol: String,
+    #[serde(with = "crate::persist::decimal")]
+    pub weight: BigDecimal,
+    /// What the sells brought minus what the buys cost, fees and taxes.
+    #[serde(with = "crate::persist::decimal")]
+    pub pnl: BigDecimal,
+    /// P&L as share of the start money.
+    pub contribution: f64,
+    #[serde(with = "crate::persist::decimal")]
+    pub fees: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub slippage: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub taxes: BigDecimal,
+    /// Closed round trips.
+    pub trades: usize,
+    pub ledger: Vec<LedgerEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioResult {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    #[serde(with = "crate::persist::decimal")]
+    pub start_money: BigDecimal,
+    /// Cash after the last day, everything sold.
+    #[serde(with = "crate::persist::decimal")]
+    pub money: BigDecimal,
+    /// Value of the portfolio at every day's close, the last day after everything is sold.
+    pub equity: TimeSeries,
+    /// Over the whole portfolio, the trades of all assets together.
+    pub metrics: Metrics,
+    /// Only compared to a configured benchmark, a portfolio has no buy-and-hold of its own.
+    pub benchmark: Option<Comparison>,
+    pub assets: Vec<Attribution>,
+}
+
+impl PortfolioResult {
+    pub fn of(config: &PortfolioConfig, start_money: &BigDecimal, run: Run, portfolio: Portfolio, risk_free_rate: f64, start_date: NaiveDate, end_date: NaiveDate) -> Self {
+        let trips: Vec<ledger::RoundTrip> = portfolio.depots.iter().flat_map(|depot| ledger::round_trips(&depot.ledger)).collect();
+        let assets = config.assets.iter().zip(portfolio.depots).map(|(asset, depot)| {
+            let pnl = depot.ledger.iter().fold(BigDecimal::zero(), |sum, e| match e.side {
+                Side::Buy => sum - &e.value - &e.fee,
+                Side::Sell => sum + &e.value - &e.fee - &e.tax,
+            });
+            Attribution {
+                symbol: asset.symbol.clone(),
+                weight: asset.weight.clone(),
+                contribution: (&pnl / start_money).to_f64().unwrap(),
+                pnl,
+                fees: depot.fees,
+                slippage: depot.slippage,
+                taxes: depot.taxes,
+                trades: ledger::round_trips(&depot.ledger).len(),
+                ledger: depot.ledger,
+            }
+        }).collect();
+        PortfolioResult {
+            start_date,
+            end_date,
+            start_money: start_money.clone(),
+            money: portfolio.cash,
+            metrics: Metrics::of(start_money, &run.equity, &trips, run.exposure, risk_free_rate),
+            equity: run.equity,
+            benchmark: None,
+            assets,
+        }
+    }
+}
+
+impl fmt::Display for PortfolioResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sum = |x: fn(&Attribution) -> &BigDecimal| self.assets.iter().map(|a| x(a).clone()).sum::<BigDecimal>();
+        writeln!(f, "Portfolio Endvalue: {}€ (fees {}€, slippage {}€, taxes {}€)", self.money, sum(|a| &a.fees), sum(|a| &a.slippage), sum(|a| &a.taxes))?;
+        write!(f, "{}", self.metrics)?;
+        if let Some(benchmark) = &self.benchmark {
+            write!(f, "\n{}", benchmark)?;
+        }
+        for a in &self.assets {
+            write!(f, "\n    {:<8} weight {:>6.2}%, P&L {}€, contribution {:.2}%, {} trades, fees {}€, taxes {}€",
+                a.symbol, a.weight.to_f64().unwrap() * 100.0, a.pnl, a.contribution * 100.0, a.trades, a.fees, a.taxes)?;
+        }
+        Ok(())
+    }
+}
+
+impl Persist for PortfolioResult {
+    const KIND: &'static str = "portfolio_result";
+    const SINCE: u32 = 16;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::{buy::Depot, execution::{Execution, Timing}, strategy::{Avg200, Strategy}, timeseries::{Bar, Frequency, TimeSeries}};
+
+    use super::{run, Asset, Portfolio, PortfolioConfig, PortfolioResult, Rebalance};
+
+    fn series(closes: &[i32]) -> TimeSeries {
+        let mut ts = TimeSeries::new("test");
+        for (i, close) in closes.iter().enumerate() {
+            ts.insert(NaiveDate::from_ymd_opt(2020, 1, 1 + i as u32).unwrap(), Bar::from_close(BigDecimal::from(*close)));
+        }
+        ts
+    }
+
+    fn config(weights: &[&str], rebalance: &str) -> PortfolioConfig {
+        PortfolioConfig {
+            assets: weights.iter().enumerate().map(|(i, w)| Asset { symbol: format!("s{}", i), weight: BigDecimal::from_str(w).unwrap(), timing: None }).collect(),
+            rebalance: Rebalance::from_str(rebalance).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_rebalance() {
+        assert_eq!(Rebalance::from_str("quarterly").unwrap(), Rebalance::Schedule(Frequency::Quarterly));
+        assert_eq!(Rebalance::from_str("drift:0.05").unwrap(), Rebalance::Drift(BigDecimal::from_str("0.05").unwrap()));
+        assert!(Rebalance::from_str("drift:-1").is_err());
+        assert!(Rebalance::from_str("sometimes").is_err());
+    }
+
+    #[test]
+    fn rebalances_on_drift() {
+        let config = config(&["0.5", "0.5"], "drift:0.1");
+        let closes = [series(&[100, 200, 300]), series(&[100, 100, 100])];
+        let mut portfolio = Portfolio::new(&BigDecimal::from(1000), vec![Depot::new(&BigDecimal::from(0)), Depot::new(&BigDecimal::from(0))]);
+        let run = run(&config, &mut [None, None], &closes, &[TimeSeries::default(), TimeSeries::default()], Frequency::Daily, &mut portfolio);
+        // 5 and 5 shares, then the first is 2/3 of the value and is sold down to 3 shares
+        assert_eq!(run.equity.closes().cloned().collect::<Vec<_>>(), [1000, 1500, 1800].iter().map(|x| BigDecimal::from(*x)).collect::<Vec<_>>());
+        assert_eq!(portfolio.depots[0].ledger.len(), 3);
+        assert_eq!(portfolio.depots[0].ledger[1].shares, BigDecimal::from(2));
+
+        // Filled at the next close the first buy pays 200
+        let mut portfolio = Portfolio::new(&BigDecimal::from(1000), vec![Depot::new(&BigDecimal::from(0)), Depot::new(&BigDecimal::from(0))])
+            .with_execution(Execution { timing: Timing::NextClose, delay: 0 });
+        let run = super::run(&config, &mut [None, None], &closes, &[TimeSeries::default(), TimeSeries::default()], Frequency::Daily, &mut portfolio);
+        assert_eq!(run.equity.closes().cloned().collect::<Vec<_>>(), [1000, 1000, 1200].iter().map(|x| BigDecimal::from(*x)).collect::<Vec<_>>());
+        assert_eq!(portfolio.depots[0].ledger[0].price, BigDecimal::from(200));
+    }
+
+    #[test]
+    fn times_assets_and_attributes_the_result() {
+        let config = config(&["0.5", "0.4"], "yearly");
+        let closes = [series(&[100, 110, 90, 120]), series(&[50, 60, 70, 80])];
+        let averages = [series(&[100, 100, 100, 100]), TimeSeries::default()];
+        let mut timings: Vec<Option<Box<dyn Strategy>>> = vec![Some(Box::new(Avg200)), None];
+        let mut portfolio = Portfolio::new(&BigDecimal::from(1000), vec![Depot::new(&BigDecimal::from(0)), Depot::new(&BigDecimal::from(0))]);
+        let run = run(&config, &mut timings, &closes, &averages, Frequency::Daily, &mut portfolio);
+        // The first asset waits for its filter, sells below the average and buys back above it
+        assert_eq!(portfolio.depots[0].ledger.iter().map(|e| e.date.format("%d").to_string()).collect::<Vec<_>>(), ["02", "03", "04", "04"]);
+        // Every change of the filter rebalances the other asset too
+        assert_eq!(portfolio.depots[1].ledger.len(), 5);
+
+        let result = PortfolioResult::of(&config, &BigDecimal::from(1000), run, portfolio, 0.0, NaiveDate::from_ymd_opt(2020, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2020, 1, 4).unwrap());
+        let pnl: BigDecimal = result.assets.iter().map(|a| a.pnl.clone()).sum();
+        assert_eq!(&result.money - &result.start_money, pnl);
+        assert_eq!(result.assets[1].pnl, BigDecimal::from(210));
+        assert_eq!(result.metrics.trades, 3);
+    }
+
+    #[test]
+    fn times_assets_at_the_frequency() {
+        let config = config(&["0.5", "0.4"], "yearly");
+        // Jan 1 2020 is a Wednesday, the weekly bars end on the 5th and the 8th
+        let closes = [series(&[110, 110, 110, 110, 110, 90, 90, 90]), series(&[50; 8])];
+        let averages = [series(&[100; 8]), TimeSeries::default()];
+        let mut timings: Vec<Option<Box<dyn Strategy>>> = vec![Some(Box::new(Avg200)), None];
+        let mut portfolio = Portfolio::new(&BigDecimal::from(1000), vec![Depot::new(&BigDecimal::from(0)), Depot::new(&BigDecimal::from(0))]);
+        run(&config, &mut timings, &closes, &averages, Frequency::Weekly, &mut portfolio);
+        assert_eq!(portfolio.depots[0].ledger.iter().map(|e| e.date.format("%d").to_string()).collect::<Vec<_>>(), ["05", "08"]);
+    }
+}
@@ -0,0 +1,172 @@
+use std::str::FromStr;
+
+use mysql::chrono::{NaiveDate, NaiveDateTime};
+use mysql_common::bigdecimal::BigDecimal;
+use postgres::{Client, NoTls};
+
+use crate::{config::Config, database::{Database, Revision, SeriesKind}, timeseries::{Bar, TimeSeries}};
+
+pub struct PostgresDatabase {
+    pub client: Client,
+    pub revision_window: usize,
+}
+
+impl PostgresDatabase {
+    pub fn from_config(config: &Config) -> Self {
+        PostgresDatabase::connect(&config.database_url, config.revision_window)
+    }
+
+    pub fn connect(url: &str, revision_window: usize) -> Self {
+        let client = match Client::connect(url, NoTls) {
+            Ok(client) => client,
+            Err(error) => panic!("Unable to connect to postgres: {}", error)
+        };
+
+        PostgresDatabase {
+            client,
+            revision_window,
+        }
+    }
+}
+
+/// Nullable columns besides close and split, added after the original layout.
+const BAR_COLUMNS: &str = "
+    add column if not exists open_value numeric(11, 2),
+    add column if not exists high_value numeric(11, 2),
+    add column if not exists low_value numeric(11, 2),
+    add column if not exists adjusted_close numeric(11, 2),
+    add column if not exists volume bigint,
+    add column if not exists dividend numeric(11, 4)";
+
+// Decimals are sent as text and read back as text, so no value ever passes through a float.
+impl Database for PostgresDatabase {
+    fn revision_window(&self) -> usize {
+        self.revision_window
+    }
+
+    fn create_tables(&mut self, symbol: &str) {
+        self.client.batch_execute(&format!("
+            create table if not exists {symbol}_raw (
+            entry_date DATE not null primary key,
+            close_value numeric(11, 2) not null,
+            split_coefficient numeric(4, 2) not null);
+            alter table {symbol}_raw {bar_columns};
+
+            create table if not exists {symbol}_adjusted (
+            entry_date DATE not null primary key,
+            close_value numeric(11, 2) not null,
+            split_coefficient numeric(4, 2) not null);
+            alter table {symbol}_adjusted {bar_columns};
+
+            create table if not exists {symbol}_revisions (
+            revision_id serial primary key,
+            entry_date DATE not null,
+            old_close numeric(11, 2) not null,
+            new_close numeric(11, 2) not null,
+            old_split_coefficient numeric(4, 2) not null,
+            new_split_coefficient numeric(4, 2) not null,
+            fetched_at TIMESTAMP not null);
+
+            create table if not exists {symbol}_200avg (
+            entry_date DATE not null primary key,
+            close_value numeric(11, 2) not null);",
+            symbol = symbol, bar_columns = BAR_COLUMNS)).unwrap();
+    }
+
+    fn drop_tables(&mut self, symbol: &str) {
+        self.client.batch_execute(&format!("DROP TABLE IF EXISTS {}_raw, {}_adjusted, {}_200avg, {}_revisions", symbol, symbol, symbol, symbol)).expect("Couldn't drop tables!");
+    }
+
+    fn write_entries(&mut self, symbol: &str, series: SeriesKind, entries: &TimeSeries) {
+        let mut transaction = self.client.transaction().unwrap();
+        if series == SeriesKind::Avg200 {
+            let stmt = transaction.prepare(&format!("INSERT INTO {} (entry_date, close_value) VALUES ($1, $2::text::numeric)
+                ON CONFLICT (entry_date) DO UPDATE SET close_value = EXCLUDED.close_value", series.table_name(symbol))).unwrap();
+            for p in entries.iter() {
+                transaction.execute(&stmt, &[p.0, &p.1.close.to_string()]).expect("Couldn't insert entries into database!");
+            }
+        } else {
+            let stmt = transaction.prepare(&format!("INSERT INTO {} (entry_date, close_value, split_coefficient, open_value, high_value, low_value, adjusted_close, volume, dividend)
+                VALUES ($1, $2::text::numeric, $3::text::numeric, $4::text::numeric, $5::text::numeric, $6::text::numeric, $7::text::numeric, $8, $9::text::numeric)
+                ON CONFLICT (entry_date) DO UPDATE SET close_value = EXCLUDED.close_value, split_coefficient = EXCLUDED.split_coefficient,
+                open_value = EXCLUDED.open_value, high_value = EXCLUDED.high_value, low_value = EXCLUDED.low_value,
+                adjusted_close = EXCLUDED.adjusted_close, volume = EXCLUDED.volume, dividend = EXCLUDED.dividend", series.table_name(symbol))).unwrap();
+            let text = |x: Option<&BigDecimal>| x.map(|x| x.to_string());
+            for p in entries.iter() {
+                let bar = p.1;
+                transaction.execute(&stmt, &[p.0, &bar.close.to_string(), &bar.split().to_string(), &text(bar.open), &text(bar.high), &text(bar.low),
+                    &text(bar.adjusted_close), &bar.volume.map(|v| v as i64), &text(bar.dividend)]).expect("Couldn't insert entries into database!");
+            }
+        }
+        transaction.commit().expect("Couldn't commit entries!");
+    }
+
+    fn record_revisions(&mut self, symbol: &str, revisions: &[Revision]) {
+        let mut transaction = self.client.transaction().unwrap();
+        let stmt = transaction.prepare(&format!("INSERT INTO {}_revisions (entry_date, old_close, new_close, old_split_coefficient, new_split_coefficient, fetched_at)
+            VALUES ($1, $2::text::numeric, $3::text::numeric, $4::text::numeric, $5::text::numeric, $6)", symbol)).unwrap();
+        for r in revisions {
+            transaction.execute(&stmt, &[&r.entry_date, &r.old_close.to_string(), &r.new_close.to_string(),
+                &r.old_split_coefficient.to_string(), &r.new_split_coefficient.to_string(), &r.fetched_at]).expect("Couldn't record revisions!");
+        }
+        transaction.commit().expect("Couldn't commit revisions!");
+    }
+
+    fn get_revisions(&mut self, symbol: &str) -> Vec<Revision> {
+        let rows = match self.client.query(format!("SELECT entry_date, old_close::text, new_close::text, old_split_coefficient::text, new_split_coefficient::text, fetched_at FROM {}_revisions ORDER BY revision_id", symbol).as_str(), &[]) {
+            Ok(rows) => rows,
+            Err(e) => panic!("Couldn't query the revisions from database: {}", e),
+        };
+        rows.iter().map(|r| Revision {
+            entry_date: r.get::<_, NaiveDate>(0),
+            old_close: BigDecimal::from_str(r.get(1)).unwrap(),
+            new_close: BigDecimal::from_str(r.get(2)).unwrap(),
+            old_split_coefficient: BigDecimal::from_str(r.get(3)).unwrap(),
+            new_split_coefficient: BigDecimal::from_str(r.get(4)).unwrap(),
+            fetched_at: r.get::<_, NaiveDateTime>(5),
+        }).collect()
+    }
+
+    fn get_timeseries_between(&mut self, symbol: &str, table_name: &str, start_date: NaiveDate, end_date: NaiveDate) -> TimeSeries {
+        let columns = if table_name.contains("200avg") {
+            "close_value::text, NULL::text, NULL::text, NULL::text, NULL::text, NULL::text, NULL::bigint, NULL::text"
+        } else {
+            "close_value::text, split_coefficient::text, open_value::text, high_value::text, low_value::text, adjusted_close::text, volume, dividend::text"
+        };
+        let rows = match self.client.query(format!("SELECT entry_date, {} FROM {} WHERE entry_date >= $1 and entry_date <= $2 ORDER BY entry_date", columns, table_name).as_str(), &[&start_date, &end_date]) {
+            Ok(rows) => rows,
+            Err(e) => panic!("Couldn't query the timeseries form database: {}", e),
+        };
+
+        let mut entries = TimeSeries::new(symbol);
+        for row in rows {
+            let decimal = |i: usize| row.get::<_, Option<&str>>(i).map(|x| BigDecimal::from_str(x).unwrap());
+            entries.insert(row.get::<_, NaiveDate>(0), Bar {
+                open: decimal(3),
+                high: decimal(4),
+                low: decimal(5),
+                close: BigDecimal::from_str(row.get(1)).unwrap(),
+                adjusted_close: decimal(6),
+                volume: row.get::<_, Option<i64>>(7).map(|v| v as u64),
+                dividend: decimal(8),
+                split_coefficient: decimal(2),
+            });
+        }
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::conformance;
+
+    use super::PostgresDatabase;
+
+    #[test]
+    #[ignore = "needs a postgres server, run with STOCKS_TEST_POSTGRES_URL set and --ignored"]
+    fn conformance() {
+        let url = std::env::var("STOCKS_TEST_POSTGRES_URL").expect("STOCKS_TEST_POSTGRES_URL has to point to a test database");
+        conformance::run(&mut PostgresDatabase::connect(&url, 10));
+    }
+}
@@ -0,0 +1,205 @@
+//! Savings plans, a fixed amount paid into the depot every period.
+//!
+//! The contribution arrives on the first trading day of each period on or after the plan's day of
+//! the month, on the period's last trading day if the data ends before it. It is invested by an
+//! order filled like the strategy's, with the depot's execution timing and delay, while the last
+//! filled order was a buy. So `buy_and_hold` invests every contribution and a timing strategy like
+//! `avg200` keeps it as cash while the close is below the average. A contribution whose fill falls
+//! after the last day stays cash. Cash in the depot earns the money-market rate, untaxed.
+//!
+//! With money coming in the end value says little, so two returns are reported. The time-weighted
+//! return chains the daily returns with the contributions taken out and is what the strategy made.
+//! The money-weighted return is the IRR of the contributions and the end value, what the saver made.
+//! The end value is after selling everything on the last day and paying the tax on it. The benchmark
+//! is compared to the equity with the contributions taken out, the curve the time-weighted return
+//! chains.
+
+use std::fmt;
+
+use mysql::chrono::{Datelike, NaiveDate};
+use mysql_common::bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::{analytics::{self, TRADING_DAYS}, benchmark::Comparison, buy::{self, Backtest, Depot}, database::Database, ledger, persist::Persist, timeseries::{Frequency, TimeSeries}};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavingsPlan {
+    /// Paid in every period.
+    #[serde(with = "crate::persist::decimal")]
+    pub amount: BigDecimal,
+    pub frequency: Frequency,
+    /// Day of the month, for monthly and longer periods.
+    pub day: u32,
+    /// In the depot before the first contribution.
+    #[serde(with = "crate::persist::decimal")]
+    pub initial: BigDecimal,
+    /// Yearly interest on cash, like 0.02.
+    #[serde(with = "crate::persist::decimal")]
+    pub cash_rate: BigDecimal,
+}
+
+impl SavingsPlan {
+    pub fn new(amount: &BigDecimal) -> Self {
+        SavingsPlan {
+            amount: amount.clone(),
+            frequency: Frequency::Monthly,
+            day: 1,
+            initial: BigDecimal::zero(),
+            cash_rate: BigDecimal::zero(),
+        }
+    }
+
+    /// The trading days of `ts` the contributions arrive on.
+    pub fn dates(&self, ts: &TimeSeries) -> Vec<NaiveDate> {
+        let dates: Vec<NaiveDate> = ts.dates().cloned().collect();
+        let by_day = !matches!(self.frequency, Frequency::Daily | Frequency::Weekly);
+        dates.chunk_by(|a, b| self.frequency.same_period(a, b))
+            .map(|period| *period.iter().find(|d| !by_day || d.day() >= self.day).unwrap_or(&period[period.len() - 1]))
+            .collect()
+    }
+
+    /// A day's interest on `cash`.
+    pub fn interest(&self, cash: &BigDecimal) -> BigDecimal {
+        cash * &self.cash_rate / BigDecimal::from(TRADING_DAYS as u32)
+    }
+}
+
+/// Outcome of a savings plan with one strategy on one symbol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavingsResult {
+    pub symbol: String,
+    pub strategy: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    /// The initial money and all contributions.
+    #[serde(with = "crate::persist::decimal")]
+    pub paid_in: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub interest: BigDecimal,
+    /// Over the whole plan, not annualized.
+    pub time_weighted: Option<f64>,
+    /// Yearly, with everything sold and taxed on the last day.
+    pub money_weighted: Option<f64>,
+    /// Value of the depot at every day's close, contributions included.
+    pub equity: TimeSeries,
+    /// Of the equity without the contributions, `None` when the configured benchmark has no data.
+    pub benchmark: Option<Comparison>,
+    /// The depot after the last day, everything sold.
+    pub depot: Depot,
+}
+
+impl SavingsResult {
+    /// The time-weighted return as a yearly rate.
+    pub fn time_weighted_annual(&self) -> Option<f64> {
+        let days = self.equity.len().checked_sub(1).filter(|days| *days > 0)?;
+        self.time_weighted.map(|r| (1.0 + r).powf(TRADING_DAYS / days as f64) - 1.0)
+    }
+}
+
+impl fmt::Display for SavingsResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}%", x * 100.0));
+        writeln!(f, "{} Endvalue: {}€ from {}€ paid in (interest {}€, fees {}€, taxes {}€)", self.strategy, self.depot.money, self.paid_in, self.interest, self.depot.fees, self.depot.taxes)?;
+        write!(f, "    time-weighted {} ({} a year), money-weighted {} a year, {} trades", percent(self.time_weighted), percent(self.time_weighted_annual()),
+            percent(self.money_weighted), ledger::round_trips(&self.depot.ledger).len())?;
+        if let Some(benchmark) = &self.benchmark {
+            write!(f, "\n{}", benchmark)?;
+        }
+        Ok(())
+    }
+}
+
+impl Persist for Vec<SavingsResult> {
+    const KIND: &'static str = "savings_results";
+    const SINCE: u32 = 16;
+}
+
+/// Runs the plan with every strategy configured for `symbol`.
+pub fn backtest(backtest: &Backtest, plan: &SavingsPlan, db: &mut dyn Database, symbol: &str, start_date: NaiveDate, end_date: NaiveDate) -> Vec<SavingsResult> {
+    let ts = db.get_timeseries_between(symbol, &format!("{}_adjusted", symbol), start_date, end_date);
+    let ts_200 = db.get_timeseries_between(symbol, &format!("{}_200avg", symbol), start_date, end_date);
+    let benchmark = backtest.benchmark_for(db, symbol, &ts, start_date, end_date);
+    backtest.strategies_for(symbol).iter().map(|spec| {
+        let mut strategy = spec.build(&backtest.avg200_range, &backtest.numeric);
+        let mut depot = Depot { money: plan.initial.clone(), ..backtest.depot(symbol) };
+        let run = buy::run(strategy.as_mut(), &ts, &ts_200, backtest.frequency, Some(plan), &mut depot);
+
+        let paid_in = run.flows.iter().fold(plan.initial.clone(), |sum, flow| sum + &flow.1);
+        let flows: Vec<(NaiveDate, f64)> = run.flows.iter().map(|(date, x)| (*date, x.to_f64().unwrap())).collect();
+        // The saver's view, money paid in negative and the end value taken out
+        let mut cash_flows = Vec::new();
+        if let (Some((first, _)), Some((last, _))) = (ts.first(), ts.last()) {
+            cash_flows.push((*first, -plan.initial.to_f64().unwrap()));
+            cash_flows.extend(flows.iter().map(|(date, x)| (*date, -x)));
+            cash_flows.push((*last, depot.money.to_f64().unwrap()));
+        }
+        SavingsResult {
+            symbol: String::from(symbol),
+            strategy: strategy.name(),
+            start_date,
+            end_date,
+            paid_in,
+            interest: depot.interest.clone(),
+            time_weighted: analytics::time_weighted_return(&run.equity, &flows),
+            money_weighted: analytics::irr(&cash_flows),
+            benchmark: benchmark.as_ref().map(|(name, benchmark)| Comparison::of(name, &analytics::flow_adjusted(&run.equity, &flows), benchmark, backtest.risk_free_rate)),
+            equity: run.equity,
+            depot,
+        }
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::{analytics, buy::{run, Depot}, execution::{Execution, Timing}, numeric::NumericPolicy, strategy::StrategySpec, timeseries::{Bar, Frequency, TimeSeries}};
+
+    use super::SavingsPlan;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, month, day).unwrap()
+    }
+
+    #[test]
+    fn pays_in_and_invests_while_long() {
+        let mut ts = TimeSeries::new("test");
+        let mut ts_200 = TimeSeries::new("test");
+        for (month, day, close) in [(1, 2, 10), (1, 16, 10), (2, 3, 20), (2, 20, 20), (3, 2, 10)] {
+            ts.insert(date(month, day), Bar::from_close(BigDecimal::from(close)));
+            ts_200.insert(date(month, day), Bar::from_close(BigDecimal::from(15)));
+        }
+        let mut plan = SavingsPlan { day: 15, ..SavingsPlan::new(&BigDecimal::from(100)) };
+        // March has no day on or after the 15th in the data
+        assert_eq!(plan.dates(&ts), [date(1, 16), date(2, 20), date(3, 2)]);
+        assert_eq!(SavingsPlan { frequency: Frequency::Weekly, ..plan.clone() }.dates(&ts).len(), 5);
+
+        let save = |spec: &str, plan: &SavingsPlan, execution: Execution| {
+            let mut strategy = StrategySpec::from_str(spec).unwrap().build(&BigDecimal::from(1), &NumericPolicy::default());
+            let mut depot = Depot::new(&BigDecimal::from(0)).with_execution(execution);
+            let run = run(strategy.as_mut(), &ts, &ts_200, Frequency::Daily, Some(plan), &mut depot);
+            (run, depot)
+        };
+        // Every contribution bought at the close, the price ends where it started
+        let (run, depot) = save("buy_and_hold", &plan, Execution::default());
+        assert_eq!(run.flows.len(), 3);
+        assert_eq!(depot.money, BigDecimal::from(250));
+        let flows: Vec<(NaiveDate, f64)> = run.flows.iter().map(|(date, _)| (*date, 100.0)).collect();
+        assert!(analytics::time_weighted_return(&run.equity, &flows).unwrap().abs() < 1e-12);
+
+        // At the next open, the last contribution comes too late
+        let (_, depot) = save("buy_and_hold", &plan, Execution { timing: Timing::NextOpen, delay: 0 });
+        assert_eq!(depot.ledger.iter().map(|e| e.price.clone()).collect::<Vec<_>>(), [20, 10, 10].iter().map(|x| BigDecimal::from(*x)).collect::<Vec<_>>());
+        assert_eq!((depot.ledger[0].date, depot.money.clone()), (date(2, 3), BigDecimal::from(250)));
+
+        // Cash waits below the average and earns 0.1% a day
+        plan.cash_rate = BigDecimal::from_str("0.252").unwrap();
+        let (_, depot) = save("avg200", &plan, Execution::default());
+        assert_eq!(depot.ledger.len(), 3);
+        assert_eq!((depot.money, depot.interest), (BigDecimal::from_str("200.10").unwrap(), BigDecimal::from_str("0.10").unwrap()));
+    }
+}
@@ -0,0 +1,118 @@
+//! How many shares an order can be for.
+//!
+//! Brokers either sell whole shares, fractions of a share down to some decimal place, or only whole
+//! lots of a fixed number of shares on exchanges that trade in lots. Orders are rounded down to what
+//! the [`Sizing`] allows and dropped when they are worth less than the minimum order value.
+
+use std::{fmt, str::FromStr};
+
+use mysql_common::bigdecimal::{BigDecimal, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::numeric::{self, Rounding};
+
+/// Written like `whole`, `fractional:4` or `lots:100` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Sizing {
+    /// Shares with this many decimal places.
+    Fractional(i64),
+    /// Multiples of this many shares, `Lots(1)` are whole shares.
+    Lots(u32),
+}
+
+impl Default for Sizing {
+    fn default() -> Self {
+        Sizing::Lots(1)
+    }
+}
+
+impl Sizing {
+    /// Rounds an amount of shares down to one that can be ordered.
+    pub fn round_down(&self, shares: &BigDecimal) -> BigDecimal {
+        match self {
+            Sizing::Fractional(precision) => numeric::round(shares, *precision, Rounding::Down),
+            Sizing::Lots(size) => {
+                let size = BigDecimal::from(*size);
+                numeric::round(&(shares / &size), 0, Rounding::Down) * size
+            }
+        }
+    }
+
+    /// The smallest amount that can be ordered.
+    pub fn step(&self) -> BigDecimal {
+        match self {
+            Sizing::Fractional(precision) => BigDecimal::new(1.into(), *precision),
+            Sizing::Lots(size) => BigDecimal::from(*size),
+        }
+    }
+}
+
+impl fmt::Display for Sizing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sizing::Lots(1) => write!(f, "whole"),
+            Sizing::Lots(size) => write!(f, "lots:{}", size),
+            Sizing::Fractional(precision) => write!(f, "fractional:{}", precision),
+        }
+    }
+}
+
+impl FromStr for Sizing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[..] {
+            ["whole"] => Ok(Sizing::Lots(1)),
+            ["fractional", precision] => match precision.parse::<i64>() {
+                Ok(p) if (0..=12).contains(&p) => Ok(Sizing::Fractional(p)),
+                _ => Err(format!("Invalid decimal places '{}' in shares '{}', expected 0 to 12", precision, s)),
+            },
+            ["lots", size] => match size.parse::<u32>() {
+                Ok(size) if size > 0 => Ok(Sizing::Lots(size)),
+                _ => Err(format!("Invalid lot size '{}' in shares '{}'", size, s)),
+            },
+            _ => Err(format!("Unknown shares '{}', expected whole, fractional:<decimal places> or lots:<size>", s)),
+        }
+    }
+}
+
+/// What an order has to look like to be filled.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct OrderRules {
+    pub sizing: Sizing,
+    /// Buys and partial sells worth less are not placed. Selling everything is always possible.
+    #[serde(with = "crate::persist::option_decimal")]
+    pub min_value: Option<BigDecimal>,
+}
+
+impl OrderRules {
+    pub fn allows(&self, value: &BigDecimal) -> bool {
+        !value.is_zero() && self.min_value.as_ref().is_none_or(|min| value >= min)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use super::Sizing;
+
+    fn d(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn rounds_to_the_sizing() {
+        assert_eq!(Sizing::from_str("whole").unwrap().round_down(&d("12.999")), d("12"));
+        assert_eq!(Sizing::from_str("fractional:3").unwrap().round_down(&d("12.99999")), d("12.999"));
+        assert_eq!(Sizing::from_str("lots:100").unwrap().round_down(&d("1299.5")), d("1200"));
+        assert_eq!(Sizing::Fractional(3).step(), d("0.001"));
+        assert_eq!(Sizing::Lots(100).to_string(), "lots:100");
+        assert!(Sizing::from_str("lots:0").is_err());
+        assert!(Sizing::from_str("fractional").is_err());
+    }
+}
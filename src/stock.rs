@@ -1,17 +1,21 @@
-use crate::{alphavantage::AlphaVantage, buy::Backtest, config::Config, mysql_db::{Database}, stockplotter::StockPlotter};
+use mysql::chrono::NaiveDate;
+
+use crate::{alphavantage::AlphaVantage, analytics::Stats, buy::{self, Backtest, BacktestResult}, config::Config, correlation::{self, Matrix, Returns}, database::{self, Database, SeriesKind}, export::{self, Exporter}, ledger, persist, portfolio, savings, stockplotter::StockPlotter, sweep, timeseries::TimeSeries, walkforward};
 
 pub struct StockRS {
     pub alphavantage: AlphaVantage,
-    pub database: Database,
+    pub database: Box<dyn Database>,
     pub stockplotter: StockPlotter,
     pub stocks: Vec<String>,
+    /// Symbols of the configured portfolio.
+    pub assets: Vec<String>,
     pub backtest: Backtest,
 }
 
 impl StockRS {
     pub fn from_config(config: &Config) -> Self {
         let alphavantage = AlphaVantage::with_key(&config.key);
-        let database = Database::from_config(config);
+        let database = database::from_config(config);
         let stockplotter = StockPlotter::from_config(config);
         let backtest = Backtest::from_config(config);
 
@@ -20,27 +24,310 @@ impl StockRS {
             database,
             stockplotter,
             stocks: config.stocks.clone(),
+            assets: config.portfolio.iter().flat_map(|p| p.assets.iter().map(|a| a.symbol.clone())).collect(),
             backtest,
         }
     }
 
+    /// Updates the configured stocks, the assets of the portfolio and the benchmark.
     pub fn update_db(&mut self) {
-        let stocks = &self.stocks;
-        for i in stocks {
-            self.database.update(&i, &self.alphavantage);
+        let mut symbols: Vec<&String> = Vec::new();
+        for s in self.stocks.iter().chain(&self.assets).chain(&self.backtest.benchmark) {
+            if !symbols.contains(&s) {
+                symbols.push(s);
+            }
+        }
+        for i in symbols {
+            self.database.update(i, &self.alphavantage);
         }
     }
 
     pub fn plot(&mut self) {
         for i in &self.stocks {
-            self.stockplotter.plot_timeseries(i, &mut self.database);
+            self.stockplotter.plot_timeseries(i, self.database.as_mut());
         }
     }
 
-    pub fn backtest(&mut self) {
+    pub fn backtest(&mut self) -> Vec<BacktestResult> {
         let stocks = &self.stocks;
+        let mut results = Vec::new();
         for s in stocks {
-            self.backtest.full_test(&mut self.database, s, self.stockplotter.start_date, self.stockplotter.end_date);
+            println!("Backtesting {}", s);
+            for result in self.backtest.full_test(self.database.as_mut(), s, self.stockplotter.start_date, self.stockplotter.end_date) {
+                println!("{}", result);
+                results.push(result);
+            }
+            println!();
+        }
+        results
+    }
+
+    /// Runs the backtest, with --save the results are written to a .json or binary file, with
+    /// --ledger the fills and round trips of every strategy to exports/.
+    pub fn backtest_command(&mut self, args: &[String]) {
+        let mut save = None;
+        let mut write_ledger = false;
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--save" => save = Some(args.next().expect("Usage: backtest [--save <path>] [--ledger]")),
+                "--ledger" => write_ledger = true,
+                _ => panic!("Usage: backtest [--save <path>] [--ledger]"),
+            }
+        }
+
+        let results = self.backtest();
+        if let Some(path) = save {
+            persist::save(&results, path);
+            println!("Saved {} results to {}", results.len(), path);
+        }
+        if write_ledger {
+            for result in &results {
+                let name = format!("{}_{}", result.symbol, result.strategy);
+                ledger::write_ledger_csv(export::create_file(&format!("exports/ledger_{}.csv", name)), &result.depot.ledger);
+                ledger::write_trades_csv(export::create_file(&format!("exports/trades_{}.csv", name)), &ledger::round_trips(&result.depot.ledger));
+            }
+            println!("Wrote the ledgers and trades of {} results to exports/", results.len());
+        }
+    }
+
+    /// Saves a stored series of one symbol, by default the adjusted one to snapshots/<symbol>_adjusted.json.
+    pub fn save_series(&mut self, args: &[String]) {
+        let symbol = args.first().expect("Usage: save <symbol> [--series raw|adjusted|200avg] [--out <path>]");
+        let mut series = SeriesKind::Adjusted;
+        let mut path = None;
+        let mut args = args[1..].iter();
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => panic!("Missing value for save option {}", flag),
+            };
+            match flag.as_str() {
+                "--series" => series = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+                "--out" => path = Some(value.clone()),
+                other => panic!("Unknown save option: {}", other),
+            }
+        }
+        let path = path.unwrap_or_else(|| format!("snapshots/{}_{}.json", symbol, series.suffix()));
+
+        self.database.create_tables(symbol);
+        let ts = self.database.get_timeseries(symbol, series);
+        persist::save(&ts, &path);
+        println!("Saved {} days of {} to {}", ts.len(), series.table_name(symbol), path);
+    }
+
+    pub fn export(&mut self, args: &[String], config: &Config) {
+        let exporter = Exporter::from_args(args, config);
+        exporter.export(self.database.as_mut());
+    }
+
+    /// Prints return and risk metrics of all configured stocks, over the plot range unless --from or --to are given.
+    pub fn print_stats(&mut self, args: &[String], config: &Config) {
+        let (start_date, end_date, options) = date_range("stats", args, config);
+        if let Some((flag, _)) = options.first() {
+            panic!("Unknown stats option: {}", flag);
+        }
+
+        let ratio = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}", x));
+        println!("{:<8} {:<10} {:<10} {:>9} {:>9} {:>9} {:>7} {:>7} {:>9} {:>10}", "symbol", "from", "to", "return", "annual", "vol", "sharpe", "sortino", "max dd", "dd days");
+        for s in &self.stocks {
+            self.database.create_tables(s);
+            let ts = self.database.get_timeseries_between(s, &SeriesKind::Adjusted.table_name(s), start_date, end_date);
+            match Stats::of(&ts, config.risk_free_rate) {
+                Some(stats) => println!("{:<8} {:<10} {:<10} {:>8.2}% {:>8.2}% {:>8} {:>7} {:>7} {:>8.2}% {:>10}", s, stats.start_date, stats.end_date,
+                    stats.total_return * 100.0, stats.annualized_return * 100.0, stats.volatility.map_or(String::from("-"), |x| format!("{:.2}%", x * 100.0)),
+                    ratio(stats.sharpe), ratio(stats.sortino), stats.drawdown.max_drawdown * 100.0, stats.drawdown.longest_duration),
+                None => println!("{:<8} not enough data between {} and {}", s, start_date, end_date),
+            }
+        }
+    }
+
+    /// Prints correlation and covariance of the daily returns of all configured stocks and writes them
+    /// to exports/ as csv and to charts/ as heatmap. With --window the rolling correlation is exported too.
+    pub fn correlation(&mut self, args: &[String], config: &Config) {
+        let (start_date, end_date, options) = date_range("correlation", args, config);
+        let mut window = None;
+        for (flag, value) in options {
+            match flag {
+                "--window" => window = Some(value.parse::<usize>().expect("--window needs a number of days like 60")),
+                other => panic!("Unknown correlation option: {}", other),
+            }
+        }
+
+        let database = &mut self.database;
+        let series: Vec<TimeSeries> = self.stocks.iter().map(|s| {
+            database.create_tables(s);
+            database.get_timeseries_between(s, &SeriesKind::Adjusted.table_name(s), start_date, end_date)
+        }).collect();
+        let returns = Returns::of(&series.iter().collect::<Vec<&TimeSeries>>());
+        if returns.len() < 2 {
+            println!("Not enough common days between {} and {} to correlate {}", start_date, end_date, self.stocks.join(", "));
+            return;
+        }
+
+        let correlation = Matrix::correlation(&returns);
+        let covariance = Matrix::covariance(&returns);
+        println!("Correlation of daily returns, {} to {} ({} days)\n{}\n", start_date, end_date, returns.len(), correlation);
+        println!("Covariance of daily returns\n{:.8}\n", covariance);
+
+        let name = format!("{}_{}", start_date, end_date);
+        correlation.write_csv(export::create_file(&format!("exports/correlation_{}.csv", name)));
+        covariance.write_csv(export::create_file(&format!("exports/covariance_{}.csv", name)));
+        std::fs::create_dir_all("charts").unwrap_or_else(|e| panic!("Couldn't create directory: {}", e));
+        correlation.plot_heatmap(&format!("Correlation {} to {}", start_date, end_date), &format!("charts/correlation_{}.png", name), 800);
+        println!("Wrote exports/correlation_{}.csv, exports/covariance_{}.csv and charts/correlation_{}.png", name, name, name);
+
+        if let Some(window) = window {
+            let path = format!("exports/rolling_correlation_{}_{}.csv", window, name);
+            correlation::write_rolling_csv(export::create_file(&path), &correlation::rolling_correlation(&returns, window));
+            println!("Wrote {}", path);
+        }
+    }
+
+    /// Backtests the configured portfolio and writes its equity curve to exports/portfolio_equity.csv,
+    /// with --save the result is written to a .json or binary file.
+    pub fn portfolio(&mut self, args: &[String], config: &Config) {
+        let portfolio = config.portfolio.as_ref().expect("You need to specify a portfolio in your config.toml! Example: [portfolio.weights] tsla = \"0.6\"");
+        let save = match args {
+            [] => None,
+            [flag, path] if flag == "--save" => Some(path),
+            _ => panic!("Usage: portfolio [--save <path>]"),
+        };
+
+        let result = portfolio::backtest(&self.backtest, portfolio, self.database.as_mut(), self.stockplotter.start_date, self.stockplotter.end_date);
+        println!("{}", result);
+        buy::write_equity_csv(export::create_file("exports/portfolio_equity.csv"), &result.equity);
+        println!("Wrote exports/portfolio_equity.csv");
+        if let Some(path) = save {
+            persist::save(&result, path);
+            println!("Saved the portfolio result to {}", path);
+        }
+    }
+
+    /// Sweeps the 200 day average strategy over the configured windows and bands for all configured
+    /// stocks, ranked by the configured metric unless --metric is given. Writes exports/sweep.csv and
+    /// a heatmap per stock to charts/.
+    pub fn sweep(&mut self, args: &[String], config: &Config) {
+        let mut grid = config.sweep.clone();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => panic!("Missing value for sweep option {}", flag),
+            };
+            match flag.as_str() {
+                "--metric" => grid.metric = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+                other => panic!("Unknown sweep option: {}", other),
+            }
+        }
+
+        let database = &mut self.database;
+        let series: Vec<(String, TimeSeries)> = self.stocks.iter().map(|s| {
+            database.create_tables(s);
+            (s.clone(), database.get_timeseries(s, SeriesKind::Adjusted))
+        }).collect();
+        let mut runs = sweep::sweep(&self.backtest, &grid, &series, self.stockplotter.start_date, self.stockplotter.end_date);
+        sweep::rank(&mut runs, grid.metric);
+
+        println!("Best of {} runs by {}", runs.len(), grid.metric.name());
+        for run in runs.iter().take(5) {
+            println!("{}", run);
+        }
+        sweep::write_sweep_csv(export::create_file("exports/sweep.csv"), &runs);
+        std::fs::create_dir_all("charts").unwrap_or_else(|e| panic!("Couldn't create directory: {}", e));
+        for s in &self.stocks {
+            sweep::plot_heatmap(&runs, s, &grid, &format!("charts/sweep_{}_{}.png", s, grid.metric.name()));
+        }
+        println!("Wrote exports/sweep.csv and the heatmaps to charts/");
+    }
+
+    /// Walks the strategies of all configured stocks forward, tuned on the sweep grid by the sweep
+    /// metric unless --metric is given. Writes the folds and the out-of-sample equity of every strategy
+    /// to exports/, with --save the results go to a .json or binary file.
+    pub fn walk_forward(&mut self, args: &[String], config: &Config) {
+        let mut grid = config.sweep.clone();
+        let mut save = None;
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => panic!("Missing value for walkforward option {}", flag),
+            };
+            match flag.as_str() {
+                "--metric" => grid.metric = value.parse().unwrap_or_else(|e| panic!("{}", e)),
+                "--save" => save = Some(value),
+                other => panic!("Unknown walkforward option: {}", other),
+            }
+        }
+
+        let mut results = Vec::new();
+        for s in &self.stocks {
+            self.database.create_tables(s);
+            let ts = self.database.get_timeseries(s, SeriesKind::Adjusted);
+            let benchmark = self.backtest.benchmark_for(self.database.as_mut(), s, &ts, self.stockplotter.start_date, self.stockplotter.end_date);
+            println!("Walking {} forward, {} months in sample and {} out of sample", s, config.walk_forward.in_sample, config.walk_forward.out_of_sample);
+            for result in walkforward::walk_forward(&self.backtest, &config.walk_forward, &grid, s, &ts, benchmark.as_ref(), self.stockplotter.start_date, self.stockplotter.end_date) {
+                println!("{}", result);
+                let name = format!("{}_{}", result.symbol, result.strategy);
+                walkforward::write_folds_csv(export::create_file(&format!("exports/walkforward_{}.csv", name)), &result);
+                buy::write_equity_csv(export::create_file(&format!("exports/walkforward_equity_{}.csv", name)), &result.equity);
+                results.push(result);
+            }
+            println!();
+        }
+        println!("Wrote the folds and equity curves of {} results to exports/", results.len());
+        if let Some(path) = save {
+            persist::save(&results, path);
+            println!("Saved {} results to {}", results.len(), path);
+        }
+    }
+
+    /// Runs the configured savings plan with the strategies of all configured stocks. Writes the
+    /// equity of every strategy to exports/, with --save the results go to a .json or binary file.
+    pub fn savings(&mut self, args: &[String], config: &Config) {
+        let plan = config.savings_plan.as_ref().expect("You need to specify a savings plan in your config.toml! Example: [savings_plan] amount = \"500\"");
+        let save = match args {
+            [] => None,
+            [flag, path] if flag == "--save" => Some(path),
+            _ => panic!("Usage: savings [--save <path>]"),
+        };
+
+        let mut results = Vec::new();
+        for s in &self.stocks {
+            self.database.create_tables(s);
+            println!("Saving {}€ {} in {}", plan.amount, plan.frequency.name(), s);
+            for result in savings::backtest(&self.backtest, plan, self.database.as_mut(), s, self.stockplotter.start_date, self.stockplotter.end_date) {
+                println!("{}", result);
+                buy::write_equity_csv(export::create_file(&format!("exports/savings_{}_{}.csv", result.symbol, result.strategy)), &result.equity);
+                results.push(result);
+            }
+            println!();
+        }
+        println!("Wrote the equity curves of {} results to exports/", results.len());
+        if let Some(path) = save {
+            persist::save(&results, path);
+            println!("Saved {} results to {}", results.len(), path);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Splits a command's `--flag value` pairs into the date range of `--from` and `--to`, by default
+/// the configured one, and the other options in their order.
+pub(crate) fn date_range<'a>(command: &str, args: &'a [String], config: &Config) -> (NaiveDate, NaiveDate, Vec<(&'a str, &'a String)>) {
+    let mut start_date = config.start_date;
+    let mut end_date = config.end_date;
+    let mut options = Vec::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => panic!("Missing value for {} option {}", command, flag),
+        };
+        match flag.as_str() {
+            "--from" => start_date = NaiveDate::parse_from_str(value, "%d-%m-%Y").expect("--from needs a date like 01-01-2015"),
+            "--to" => end_date = NaiveDate::parse_from_str(value, "%d-%m-%Y").expect("--to needs a date like 31-12-2020"),
+            other => options.push((other, value)),
+        }
+    }
+    (start_date, end_date, options)
+}
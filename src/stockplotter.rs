@@ -1,34 +1,43 @@
 use mysql::chrono::NaiveDate;
-use plotters::{prelude::{BitMapBackend, ChartBuilder, IntoDrawingArea, LineSeries}, style::{BLACK, GREEN, IntoFont, RED, WHITE}};
+use plotters::{prelude::{BitMapBackend, ChartBuilder, DrawingArea, IntoDrawingArea, LineSeries, PathElement}, coord::Shift, style::{BLACK, BLUE, CYAN, Color, GREEN, IntoFont, MAGENTA, RED, RGBColor, WHITE, YELLOW}};
 use mysql_common::bigdecimal::ToPrimitive;
 
-use crate::{config::Config, mysql_db::Database};
+use crate::{config::Config, database::{Database, SeriesKind}, indicators::Indicator, timeseries::{Frequency, TimeSeries}};
+
+const INDICATOR_COLORS: [RGBColor; 4] = [BLUE, MAGENTA, CYAN, YELLOW];
 
 pub struct StockPlotter {
     img_width: i32,
     img_height: i32,
     pub start_date: NaiveDate,
     pub end_date: NaiveDate,
+    pub indicators: Vec<Indicator>,
+    pub frequency: Frequency,
 }
 
 impl StockPlotter {
-    pub fn new(img_width: i32, img_height: i32, start_date: NaiveDate, end_date: NaiveDate) -> Self {
+    pub fn new(img_width: i32, img_height: i32, start_date: NaiveDate, end_date: NaiveDate, indicators: Vec<Indicator>, frequency: Frequency) -> Self {
         StockPlotter {
             img_width,
             img_height,
             start_date,
-            end_date
+            end_date,
+            indicators,
+            frequency,
         }
     }
 
     pub fn from_config(config: &Config) -> Self {
-        StockPlotter::new(config.img_width, config.img_height, config.start_date, config.end_date)
+        StockPlotter::new(config.img_width, config.img_height, config.start_date, config.end_date, config.indicators.clone(), config.frequency)
     } 
 
-    pub fn plot_timeseries(&self, symbol: &str, database: &mut Database)  {
-        let ts = database.get_timeseries_between(symbol, &format!("{}_adjusted", symbol), self.start_date, self.end_date);
-        let ts2 = database.get_timeseries_between(symbol, &format!("{}_200avg", symbol), self.start_date, self.end_date);
-        match std::fs::create_dir(format!("charts")) {
+    pub fn plot_timeseries(&self, symbol: &str, database: &mut dyn Database)  {
+        // Indicators are computed on the whole history, so they are warmed up at the start of the chart.
+        // Their windows count bars of the chart's frequency.
+        let full = database.get_timeseries(symbol, SeriesKind::Adjusted).resample(self.frequency);
+        let ts = full.slice(self.start_date..=self.end_date);
+        let ts2 = database.get_timeseries_between(symbol, &format!("{}_200avg", symbol), self.start_date, self.end_date).resample(self.frequency);
+        match std::fs::create_dir("charts") {
             Ok(_) => {}
             Err(e) => match e.kind() {
                 std::io::ErrorKind::AlreadyExists => {}
@@ -42,30 +51,91 @@ impl StockPlotter {
                 _ => panic!("Couldn't create directory: {}", e),
             }
         }
-        let path = &format!("./charts/{}/{}_{}_{}.png", symbol, symbol, self.start_date, self.end_date);
+        let path = &match self.frequency {
+            Frequency::Daily => format!("./charts/{}/{}_{}_{}.png", symbol, symbol, self.start_date, self.end_date),
+            frequency => format!("./charts/{}/{}_{}_{}_{}.png", symbol, symbol, self.start_date, self.end_date, frequency.name()),
+        };
         
         let root = BitMapBackend::new(&path, (self.img_width as u32, self.img_height as u32)).into_drawing_area();
-        if ts.entries.iter().last() < ts2.entries.iter().last() {
+        if ts.last().map(|x| x.1.close) < ts2.last().map(|x| x.1.close) {
             root.fill(&RED).unwrap();
         } else {
             root.fill(&GREEN).unwrap();
         }
 
-        let mut chart = ChartBuilder::on(&root)
+        let (overlays, oscillators): (Vec<&Indicator>, Vec<&Indicator>) = self.indicators.iter().partition(|x| x.is_overlay());
+        let (price_area, oscillator_area) = if oscillators.is_empty() {
+            (root.clone(), None)
+        } else {
+            let (upper, lower) = root.split_vertically(self.img_height as u32 * 3 / 5);
+            (upper, Some(lower))
+        };
+
+        let mut chart = ChartBuilder::on(&price_area)
             .caption(symbol, ("sans-serif", 50).into_font())
             .x_label_area_size(70)
             .y_label_area_size(70)
             .margin_right(70)
-            .build_cartesian_2d(self.start_date..self.end_date, 0 as f32..ts.get_max_close().to_f32().unwrap()).unwrap();
+            .build_cartesian_2d(self.start_date..self.end_date, 0 as f32..ts.get_max_close().expect("No adjusted data to plot!").to_f32().unwrap()).unwrap();
 
         chart.configure_mesh().draw().unwrap();
         chart.draw_series(LineSeries::new(
-            ts.entries.iter().map(|x| (*x.0, x.1.0.to_f32().unwrap())),
+            ts.iter().map(|x| (*x.0, x.1.close.to_f32().unwrap())),
             &BLACK,
         )).unwrap();
         chart.draw_series(LineSeries::new(
-            ts2.entries.iter().map(|x| (*x.0, x.1.0.to_f32().unwrap())),
+            ts2.iter().map(|x| (*x.0, x.1.close.to_f32().unwrap())),
             &WHITE,
         )).unwrap();
+
+        let overlay_lines: Vec<(String, TimeSeries)> = overlays.iter().flat_map(|x| x.compute(&full)).collect();
+        for (i, (label, line)) in overlay_lines.iter().enumerate() {
+            let color = INDICATOR_COLORS[i % INDICATOR_COLORS.len()];
+            chart.draw_series(LineSeries::new(
+                line.slice(self.start_date..=self.end_date).iter().map(|x| (*x.0, x.1.close.to_f32().unwrap())),
+                &color,
+            )).unwrap()
+                .label(label.as_str())
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+        }
+        if !overlay_lines.is_empty() {
+            chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw().unwrap();
+        }
+
+        if let Some(area) = oscillator_area {
+            let panels = area.split_evenly((oscillators.len(), 1));
+            for (indicator, panel) in oscillators.iter().zip(panels.iter()) {
+                self.plot_oscillator(panel, &indicator.compute(&full));
+            }
+        }
     }
-}
\ No newline at end of file
+
+    /// Draws the lines of one indicator that has its own scale into a panel below the price.
+    fn plot_oscillator(&self, area: &DrawingArea<BitMapBackend, Shift>, lines: &[(String, TimeSeries)]) {
+        let lines: Vec<(&String, Vec<(NaiveDate, f32)>)> = lines.iter()
+            .map(|x| (&x.0, x.1.slice(self.start_date..=self.end_date).iter().map(|x| (*x.0, x.1.close.to_f32().unwrap())).collect()))
+            .collect();
+        let values = lines.iter().flat_map(|x| x.1.iter().map(|x| x.1));
+        let min = values.clone().fold(f32::INFINITY, f32::min);
+        let max = values.fold(f32::NEG_INFINITY, f32::max);
+        if min > max {
+            return;
+        }
+        let margin = ((max - min) * 0.05).max(f32::EPSILON);
+
+        let mut chart = ChartBuilder::on(area)
+            .x_label_area_size(30)
+            .y_label_area_size(70)
+            .margin_right(70)
+            .build_cartesian_2d(self.start_date..self.end_date, (min - margin)..(max + margin)).unwrap();
+
+        chart.configure_mesh().draw().unwrap();
+        for (i, (label, points)) in lines.into_iter().enumerate() {
+            let color = INDICATOR_COLORS[i % INDICATOR_COLORS.len()];
+            chart.draw_series(LineSeries::new(points, &color)).unwrap()
+                .label(label.as_str())
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+        }
+        chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw().unwrap();
+    }
+}
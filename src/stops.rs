@@ -0,0 +1,154 @@
+//! Risk orders placed with every new position.
+//!
+//! When a buy opens a position the depot places a pending sell order for each configured
+//! [`StopRule`], priced from the buy. An ATR stop of a position opened before its ATR exists is placed
+//! on the first day that has one, still priced from the buy. The backtest checks them every day before the strategy, against
+//! the day's low and high where the series has them and against the close otherwise. An order already
+//! crossed at the open fills at the open, otherwise at its level. A day that crosses a stop and a
+//! take-profit fills the stop, the worse of the two. A filled order sells the whole position and
+//! cancels the others, the strategy can only buy again after it went flat.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use mysql_common::bigdecimal::{BigDecimal, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::{ledger::OrderType, timeseries::BarRef};
+
+/// Written like `stop:0.08`, `atr:14:3`, `trailing:0.15` or `take_profit:0.3` in the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StopRule {
+    /// Sells when the price falls this share below the entry.
+    Loss(#[serde(with = "crate::persist::decimal")] BigDecimal),
+    /// Sells when the price falls this many ATRs of `window` days below the entry.
+    Atr(usize, #[serde(with = "crate::persist::decimal")] BigDecimal),
+    /// Sells when the price falls this share below its highest since the entry.
+    Trailing(#[serde(with = "crate::persist::decimal")] BigDecimal),
+    /// Sells when the price rises this share above the entry.
+    TakeProfit(#[serde(with = "crate::persist::decimal")] BigDecimal),
+}
+
+impl StopRule {
+    /// The order for a position entered at `entry`. ATR stops need the ATR of their window in `atr`,
+    /// `None` without it.
+    pub fn place(&self, entry: &BigDecimal, atr: &BTreeMap<usize, BigDecimal>) -> Option<StopOrder> {
+        let one = BigDecimal::from(1);
+        let (order, level, trail) = match self {
+            StopRule::Loss(share) => (OrderType::StopLoss, entry * (&one - share), None),
+            StopRule::Atr(window, multiple) => (OrderType::StopLoss, entry - multiple * atr.get(window)?, None),
+            StopRule::Trailing(share) => (OrderType::TrailingStop, entry * (&one - share), Some(share.clone())),
+            StopRule::TakeProfit(share) => (OrderType::TakeProfit, entry * (&one + share), None),
+        };
+        Some(StopOrder { order, level, trail, peak: entry.clone() })
+    }
+}
+
+impl FromStr for StopRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let share = |p: &str| match BigDecimal::from_str(p) {
+            Ok(x) if x > BigDecimal::zero() && x < BigDecimal::from(1) => Ok(x),
+            _ => Err(format!("Invalid share '{}' in stop '{}', expected a number between 0 and 1", p, s)),
+        };
+        match parts[..] {
+            ["stop", p] => share(p).map(StopRule::Loss),
+            ["trailing", p] => share(p).map(StopRule::Trailing),
+            ["take_profit", p] => match BigDecimal::from_str(p) {
+                Ok(x) if x > BigDecimal::zero() => Ok(StopRule::TakeProfit(x)),
+                _ => Err(format!("Invalid share '{}' in stop '{}'", p, s)),
+            },
+            ["atr", window, multiple] => match (window.parse::<usize>(), BigDecimal::from_str(multiple)) {
+                (Ok(w), Ok(m)) if w > 0 && m > BigDecimal::zero() => Ok(StopRule::Atr(w, m)),
+                _ => Err(format!("Invalid window or multiple in stop '{}'", s)),
+            },
+            _ => Err(format!("Unknown stop '{}', expected stop:<share>, atr:<window>:<multiple>, trailing:<share> or take_profit:<share>", s)),
+        }
+    }
+}
+
+/// A pending sell of the whole position.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopOrder {
+    pub order: OrderType,
+    #[serde(with = "crate::persist::decimal")]
+    pub level: BigDecimal,
+    /// Share below the peak a trailing stop follows at.
+    #[serde(with = "crate::persist::option_decimal")]
+    pub trail: Option<BigDecimal>,
+    /// Highest price since the entry.
+    #[serde(with = "crate::persist::decimal")]
+    pub peak: BigDecimal,
+}
+
+impl StopOrder {
+    /// The price the order fills at on the day of `bar`, `None` if it isn't reached.
+    pub fn trigger(&self, bar: BarRef) -> Option<BigDecimal> {
+        let open = bar.open_or_close();
+        match self.order {
+            OrderType::TakeProfit if open >= &self.level => Some(open.clone()),
+            OrderType::TakeProfit if bar.high_or_close() >= &self.level => Some(self.level.clone()),
+            OrderType::TakeProfit => None,
+            _ if open <= &self.level => Some(open.clone()),
+            _ if bar.low_or_close() <= &self.level => Some(self.level.clone()),
+            _ => None,
+        }
+    }
+
+    /// Moves a trailing stop up after the day of `bar`.
+    pub fn trail(&mut self, bar: BarRef) {
+        if let Some(share) = &self.trail {
+            if bar.high_or_close() > &self.peak {
+                self.peak = bar.high_or_close().clone();
+                self.level = &self.peak * (BigDecimal::from(1) - share);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::{ledger::OrderType, timeseries::Bar};
+
+    use super::StopRule;
+
+    fn d(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    fn bar(open: &str, high: &str, low: &str, close: &str) -> Bar {
+        Bar { open: Some(d(open)), high: Some(d(high)), low: Some(d(low)), ..Bar::from_close(d(close)) }
+    }
+
+    #[test]
+    fn triggers_within_the_day() {
+        let atr = BTreeMap::new();
+        let stop = StopRule::from_str("stop:0.1").unwrap().place(&d("100"), &atr).unwrap();
+        assert_eq!(stop.trigger(bar("98", "99", "91", "95").view()), None);
+        assert_eq!(stop.trigger(bar("98", "99", "85", "95").view()), Some(d("90.0")));
+        // Gapped below the stop, filled at the open
+        assert_eq!(stop.trigger(bar("80", "85", "78", "84").view()), Some(d("80")));
+        // Only a close
+        assert_eq!(stop.trigger(Bar::from_close(d("89")).view()), Some(d("89")));
+
+        let take_profit = StopRule::from_str("take_profit:0.2").unwrap().place(&d("100"), &atr).unwrap();
+        assert_eq!((take_profit.order, take_profit.trigger(bar("110", "125", "108", "115").view())), (OrderType::TakeProfit, Some(d("120.0"))));
+
+        let mut trailing = StopRule::from_str("trailing:0.1").unwrap().place(&d("100"), &atr).unwrap();
+        trailing.trail(bar("100", "130", "100", "120").view());
+        assert_eq!(trailing.level, d("117.0"));
+        trailing.trail(bar("120", "125", "118", "119").view());
+        assert_eq!(trailing.trigger(bar("119", "120", "110", "112").view()), Some(d("117.0")));
+
+        assert!(StopRule::from_str("atr:14:3").unwrap().place(&d("100"), &atr).is_none());
+        let atr = vec![(14, d("2.5"))].into_iter().collect();
+        assert_eq!(StopRule::from_str("atr:14:3").unwrap().place(&d("100"), &atr).unwrap().level, d("92.5"));
+        assert!(StopRule::from_str("stop:1.5").is_err());
+    }
+}
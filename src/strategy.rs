@@ -0,0 +1,217 @@
+//! Trading strategies for the backtest.
+//!
+//! A [`Strategy`] is handed one [`Context`] per bar, oldest first, and answers with a [`Signal`]:
+//! keep the position or move the depot to a target share of its value. The backtest owns the depot
+//! and executes the signals, strategies only decide. To add one, implement the trait and give it a
+//! [`StrategySpec`] variant so it can be chosen in the config.
+
+use std::str::FromStr;
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{indicators::Indicator, numeric::NumericPolicy, timeseries::{BarRef, TimeSeries}};
+
+pub enum Signal {
+    /// Keep the current position.
+    Hold,
+    /// Hold this share of the depot's value in the stock, 1 is fully invested and 0 all cash.
+    Target(BigDecimal),
+}
+
+impl Signal {
+    pub fn long() -> Self {
+        Signal::Target(BigDecimal::from(1))
+    }
+
+    pub fn flat() -> Self {
+        Signal::Target(BigDecimal::from(0))
+    }
+}
+
+/// What a strategy sees of one bar.
+pub struct Context<'a> {
+    pub date: NaiveDate,
+    pub bar: BarRef<'a>,
+    /// The stored 200 day average on this date, if there is one.
+    pub avg200: Option<BarRef<'a>>,
+    /// Shares held before this bar's signal is executed.
+    pub shares: BigDecimal,
+    /// Position of the bar in `series`.
+    pub index: usize,
+    pub series: &'a TimeSeries,
+    /// Lines of the strategy's [`Strategy::indicators`], labelled like [`Indicator::compute`] does.
+    pub indicators: &'a [(String, TimeSeries)],
+}
+
+impl<'a> Context<'a> {
+    /// The closes up to and including this one.
+    pub fn history(&self) -> &'a [BigDecimal] {
+        &self.series.close_column()[..=self.index]
+    }
+
+    /// Value of the indicator line `label` on this date, e.g. `SMA 50` or `MACD 12/26`.
+    pub fn indicator(&self, label: &str) -> Option<&'a BigDecimal> {
+        let (_, ts) = self.indicators.iter().find(|(l, _)| l == label)?;
+        ts.get(&self.date).map(|x| x.close)
+    }
+}
+
+pub trait Strategy {
+    /// Name the results are stored under.
+    fn name(&self) -> String;
+
+    /// Indicators computed over the whole series before the first bar.
+    fn indicators(&self) -> Vec<Indicator> {
+        Vec::new()
+    }
+
+    fn next(&mut self, context: &Context) -> Signal;
+}
+
+/// Buys on the first bar and holds until the end.
+pub struct BuyAndHold;
+
+impl Strategy for BuyAndHold {
+    fn name(&self) -> String {
+        String::from("buy_and_hold")
+    }
+
+    fn next(&mut self, context: &Context) -> Signal {
+        if context.index == 0 {
+            Signal::long()
+        } else {
+            Signal::Hold
+        }
+    }
+}
+
+/// Invested while the close is above the 200 day average.
+pub struct Avg200;
+
+impl Strategy for Avg200 {
+    fn name(&self) -> String {
+        String::from("avg200")
+    }
+
+    fn next(&mut self, context: &Context) -> Signal {
+        match context.avg200 {
+            Some(avg) if context.bar.close > avg.close => Signal::long(),
+            Some(_) => Signal::flat(),
+            None => Signal::Hold,
+        }
+    }
+}
+
+/// Buys when close / 200avg rises above `range` and sells when it falls below, in between the
+/// position is kept.
+pub struct Avg200Range {
+    pub range: BigDecimal,
+    pub numeric: NumericPolicy,
+}
+
+impl Strategy for Avg200Range {
+    /// With the range, so runs with different bands are stored apart.
+    fn name(&self) -> String {
+        format!("avg200_range:{}", self.range)
+    }
+
+    fn next(&mut self, context: &Context) -> Signal {
+        let avg = match context.avg200 {
+            Some(avg) => avg,
+            None => return Signal::Hold,
+        };
+        let ratio = self.numeric.ratio(&(context.bar.close / avg.close));
+        if ratio > self.range {
+            Signal::long()
+        } else if ratio < self.range {
+            Signal::flat()
+        } else {
+            Signal::Hold
+        }
+    }
+}
+
+/// A strategy with its parameters, written like `buy_and_hold` or `avg200_range:1.03` in the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StrategySpec {
+    BuyAndHold,
+    Avg200,
+    /// Without a range the config's `avg200_range` is used.
+    Avg200Range(#[serde(with = "crate::persist::option_decimal")] Option<BigDecimal>),
+}
+
+impl StrategySpec {
+    /// The strategies run when the config names none.
+    pub fn defaults() -> Vec<StrategySpec> {
+        vec![StrategySpec::BuyAndHold, StrategySpec::Avg200, StrategySpec::Avg200Range(None)]
+    }
+
+    /// Name of the strategy without its parameters.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StrategySpec::BuyAndHold => "buy_and_hold",
+            StrategySpec::Avg200 => "avg200",
+            StrategySpec::Avg200Range(_) => "avg200_range",
+        }
+    }
+
+    pub fn build(&self, avg200_range: &BigDecimal, numeric: &NumericPolicy) -> Box<dyn Strategy> {
+        match self {
+            StrategySpec::BuyAndHold => Box::new(BuyAndHold),
+            StrategySpec::Avg200 => Box::new(Avg200),
+            StrategySpec::Avg200Range(range) => Box::new(Avg200Range {
+                range: range.clone().unwrap_or_else(|| avg200_range.clone()),
+                numeric: numeric.clone(),
+            }),
+        }
+    }
+}
+
+impl FromStr for StrategySpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[0] {
+            "buy_and_hold" => Ok(StrategySpec::BuyAndHold),
+            "avg200" => Ok(StrategySpec::Avg200),
+            "avg200_range" => match parts.get(1) {
+                Some(p) => BigDecimal::from_str(p).map(|x| StrategySpec::Avg200Range(Some(x))).map_err(|_| format!("Invalid range '{}' in strategy '{}'", p, s)),
+                None => Ok(StrategySpec::Avg200Range(None)),
+            },
+            other => Err(format!("Unknown strategy '{}', expected one of buy_and_hold, avg200, avg200_range", other)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::numeric::NumericPolicy;
+
+    use super::StrategySpec;
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(StrategySpec::from_str("avg200").unwrap(), StrategySpec::Avg200);
+        assert_eq!(StrategySpec::from_str("avg200_range").unwrap(), StrategySpec::Avg200Range(None));
+        assert_eq!(StrategySpec::from_str("avg200_range:1.05").unwrap(), StrategySpec::Avg200Range(Some(BigDecimal::from_str("1.05").unwrap())));
+        assert!(StrategySpec::from_str("avg200_range:abc").is_err());
+        assert!(StrategySpec::from_str("momentum").is_err());
+    }
+
+    #[test]
+    fn names_include_the_range() {
+        let range = BigDecimal::from_str("1.03").unwrap();
+        let name = |spec: &str| StrategySpec::from_str(spec).unwrap().build(&range, &NumericPolicy::default()).name();
+        assert_eq!(name("avg200"), "avg200");
+        assert_eq!(name("avg200_range"), "avg200_range:1.03");
+        assert_eq!(name("avg200_range:1.05"), "avg200_range:1.05");
+    }
+}
@@ -0,0 +1,233 @@
+//! Parameter sweep of the 200 day average strategy.
+//!
+//! Runs `avg200_range` for every symbol, average window and band in the grid, with the configured
+//! costs, taxes and order rules. The average is computed from the adjusted series instead of read
+//! from the stored 200 day average, over the whole history so it is there from the first day. Each
+//! series is loaded once and the runs are spread over all cores. Runs aren't compared to the
+//! benchmark, walk-forward results compare the parameters they choose.
+
+use std::{fmt, io::{BufWriter, Write}, str::FromStr};
+
+use mysql::chrono::NaiveDate;
+use mysql_common::bigdecimal::BigDecimal;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{buy::{self, Backtest, Metrics}, correlation, indicators, ledger, strategy::StrategySpec, timeseries::TimeSeries};
+
+/// What runs are ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    TotalReturn,
+    Cagr,
+    MaxDrawdown,
+    Sharpe,
+    Sortino,
+    Calmar,
+    WinRate,
+    ProfitFactor,
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::TotalReturn => "total_return",
+            Metric::Cagr => "cagr",
+            Metric::MaxDrawdown => "max_drawdown",
+            Metric::Sharpe => "sharpe",
+            Metric::Sortino => "sortino",
+            Metric::Calmar => "calmar",
+            Metric::WinRate => "win_rate",
+            Metric::ProfitFactor => "profit_factor",
+        }
+    }
+
+    pub fn value(&self, m: &Metrics) -> Option<f64> {
+        match self {
+            Metric::TotalReturn => Some(m.total_return),
+            Metric::Cagr => Some(m.cagr),
+            Metric::MaxDrawdown => Some(m.max_drawdown),
+            Metric::Sharpe => m.sharpe,
+            Metric::Sortino => m.sortino,
+            Metric::Calmar => m.calmar,
+            Metric::WinRate => m.win_rate,
+            Metric::ProfitFactor => m.profit_factor,
+        }
+    }
+
+    /// The value to sort by, higher is better. Drawdowns are better small.
+    pub fn score(&self, m: &Metrics) -> Option<f64> {
+        let value = self.value(m).filter(|x| !x.is_nan())?;
+        Some(if *self == Metric::MaxDrawdown { -value } else { value })
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let all = [Metric::TotalReturn, Metric::Cagr, Metric::MaxDrawdown, Metric::Sharpe, Metric::Sortino, Metric::Calmar, Metric::WinRate, Metric::ProfitFactor];
+        match all.iter().find(|m| m.name() == s) {
+            Some(metric) => Ok(*metric),
+            None => Err(format!("Unknown metric '{}', expected one of {}", s, all.iter().map(|m| m.name()).collect::<Vec<_>>().join(", "))),
+        }
+    }
+}
+
+/// The grid of a sweep, every window is run with every band.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SweepConfig {
+    /// Days of the moving average.
+    pub windows: Vec<usize>,
+    /// Bands like `avg200_range`, close / average to buy above and sell below.
+    #[serde(with = "crate::persist::decimal_vec")]
+    pub ranges: Vec<BigDecimal>,
+    pub metric: Metric,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        SweepConfig {
+            windows: vec![50, 100, 150, 200],
+            ranges: (0..=5).map(|i| BigDecimal::from(100 + i) / BigDecimal::from(100)).collect(),
+            metric: Metric::Sharpe,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepRun {
+    pub symbol: String,
+    pub window: usize,
+    pub range: BigDecimal,
+    pub end_money: BigDecimal,
+    pub metrics: Metrics,
+}
+
+impl fmt::Display for SweepRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} window {}, range {}: Endvalue {}€", self.symbol, self.window, self.range, self.end_money)?;
+        write!(f, "{}", self.metrics)
+    }
+}
+
+/// Runs the grid on `series`, the full adjusted history of each symbol, between the dates.
+pub fn sweep(backtest: &Backtest, config: &SweepConfig, series: &[(String, TimeSeries)], start_date: NaiveDate, end_date: NaiveDate) -> Vec<SweepRun> {
+    let averages: Vec<(&str, usize, TimeSeries, TimeSeries)> = series.par_iter()
+        .flat_map(|(symbol, ts)| config.windows.par_iter().map(move |window| (symbol.as_str(), *window, ts.slice(start_date..=end_date), indicators::sma(ts, *window).slice(start_date..=end_date))))
+        .collect();
+    averages.par_iter()
+        .flat_map(|average| config.ranges.par_iter().map(move |range| (average, range)))
+        .map(|((symbol, window, ts, average), range)| {
+            let mut strategy = StrategySpec::Avg200Range(Some(range.clone())).build(&backtest.avg200_range, &backtest.numeric);
+            let mut depot = backtest.depot(symbol);
+            let run = buy::run(strategy.as_mut(), ts, average, backtest.frequency, None, &mut depot);
+            SweepRun {
+                symbol: symbol.to_string(),
+                window: *window,
+                range: range.clone(),
+                metrics: Metrics::of(&backtest.start_depot, &run.equity, &ledger::round_trips(&depot.ledger), run.exposure, backtest.risk_free_rate),
+                end_money: depot.money,
+            }
+        })
+        .collect()
+}
+
+/// Sorts the runs best first, runs without a value for the metric last.
+pub fn rank(runs: &mut [SweepRun], metric: Metric) {
+    runs.sort_by(|a, b| {
+        let (a, b) = (metric.score(&a.metrics), metric.score(&b.metrics));
+        b.partial_cmp(&a).unwrap()
+    });
+}
+
+pub fn write_sweep_csv<W: Write>(out: W, runs: &[SweepRun]) {
+    let mut out = BufWriter::new(out);
+    let option = |x: Option<f64>| x.map_or(String::new(), |x| x.to_string());
+    writeln!(out, "rank,symbol,window,range,end_money,total_return,cagr,volatility,max_drawdown,sharpe,sortino,calmar,exposure,trades,win_rate,profit_factor").unwrap();
+    for (i, r) in runs.iter().enumerate() {
+        let m = &r.metrics;
+        writeln!(out, "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}", i + 1, r.symbol, r.window, r.range, r.end_money, m.total_return, m.cagr, option(m.volatility),
+            m.max_drawdown, option(m.sharpe), option(m.sortino), option(m.calmar), m.exposure, m.trades, option(m.win_rate), option(m.profit_factor)).expect("Couldn't write csv row!");
+    }
+    out.flush().unwrap();
+}
+
+/// The metric of one symbol's runs with a row per window and a column per band, NaN where a run has
+/// no value.
+pub fn grid(runs: &[SweepRun], symbol: &str, config: &SweepConfig) -> Vec<Vec<f64>> {
+    config.windows.iter().map(|window| config.ranges.iter().map(|range| {
+        runs.iter().find(|r| r.symbol == symbol && r.window == *window && &r.range == range)
+            .and_then(|r| config.metric.value(&r.metrics))
+            .unwrap_or(f64::NAN)
+    }).collect()).collect()
+}
+
+/// Heatmap of [`grid`], the colors scaled to the largest value.
+pub fn plot_heatmap(runs: &[SweepRun], symbol: &str, config: &SweepConfig, path: &str) {
+    let values = grid(runs, symbol, config);
+    let scale = values.iter().flatten().filter(|x| !x.is_nan()).fold(0.0, |max: f64, x| max.max(x.abs()));
+    let rows: Vec<String> = config.windows.iter().map(|w| w.to_string()).collect();
+    let columns: Vec<String> = config.ranges.iter().map(|r| r.to_string()).collect();
+    let title = format!("{} {} by window and range", symbol, config.metric.name());
+    correlation::plot_heatmap(&title, path, 800, &rows, &columns, &values, if scale > 0.0 { scale } else { 1.0 });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::{buy::{Backtest, Metrics}, timeseries::{Bar, TimeSeries}};
+
+    use super::{grid, rank, sweep, Metric, SweepConfig, SweepRun};
+
+    fn run(window: usize, range: &str, sharpe: Option<f64>, max_drawdown: f64) -> SweepRun {
+        SweepRun {
+            symbol: String::from("test"),
+            window,
+            range: BigDecimal::from_str(range).unwrap(),
+            end_money: BigDecimal::from(0),
+            metrics: Metrics { sharpe, max_drawdown, ..Metrics::default() },
+        }
+    }
+
+    #[test]
+    fn ranks_by_the_metric() {
+        let mut runs = vec![run(50, "1", Some(0.5), 0.3), run(50, "1.02", None, 0.1), run(200, "1", Some(1.5), 0.2), run(200, "1.02", Some(-0.2), 0.4)];
+        rank(&mut runs, Metric::Sharpe);
+        assert_eq!(runs.iter().map(|r| r.metrics.sharpe).collect::<Vec<_>>(), [Some(1.5), Some(0.5), Some(-0.2), None]);
+        rank(&mut runs, Metric::MaxDrawdown);
+        assert_eq!(runs.iter().map(|r| r.metrics.max_drawdown).collect::<Vec<_>>(), [0.1, 0.2, 0.3, 0.4]);
+
+        let config = SweepConfig { windows: vec![50, 200], ranges: vec![BigDecimal::from(1), BigDecimal::from_str("1.02").unwrap()], metric: Metric::Sharpe };
+        let values = grid(&runs, "test", &config);
+        assert_eq!(values[1], [1.5, -0.2]);
+        assert!(values[0][1].is_nan());
+        assert!(Metric::from_str("returns").is_err());
+    }
+
+    #[test]
+    fn sweeps_the_grid() {
+        let date = |i: usize| NaiveDate::from_ymd_opt(2020, 1, 1 + i as u32).unwrap();
+        let mut ts = TimeSeries::new("test");
+        for (i, close) in [100, 100, 100, 110, 120, 130, 100, 90, 95].iter().enumerate() {
+            ts.insert(date(i), Bar::from_close(BigDecimal::from(*close)));
+        }
+        let config = SweepConfig { windows: vec![2, 5], ranges: vec![BigDecimal::from(1), BigDecimal::from_str("1.05").unwrap()], metric: Metric::TotalReturn };
+        // The averages come from the whole history, so the 2 day one is there on the first day
+        let mut runs = sweep(&Backtest::plain(&BigDecimal::from(1000)), &config, &[(String::from("test"), ts)], date(3), date(8));
+        rank(&mut runs, config.metric);
+        let end_money: Vec<(usize, String, BigDecimal)> = runs.iter().map(|r| (r.window, r.range.to_string(), r.end_money.clone())).collect();
+        assert_eq!(end_money, [
+            (2, String::from("1.05"), BigDecimal::from(1000)),
+            (2, String::from("1"), BigDecimal::from(910)),
+            (5, String::from("1"), BigDecimal::from(840)),
+            (5, String::from("1.05"), BigDecimal::from(840)),
+        ]);
+        assert_eq!(runs[1].metrics.trades, 2);
+    }
+}
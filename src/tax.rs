@@ -0,0 +1,174 @@
+//! Capital gains tax on realized gains, like the Austrian KESt.
+//!
+//! Bought shares are kept as lots with what they cost. A sale takes its shares from the lots, first in
+//! first out or at the average cost of all shares, and pays `rate` on the gain right away. With loss
+//! offsetting, gains and losses of a calendar year are netted and tax paid earlier in the year is
+//! refunded when a later loss lowers the net gain. Fees don't lower the gain, like for the KESt.
+
+use std::{collections::VecDeque, str::FromStr};
+
+use mysql_common::bigdecimal::{BigDecimal, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::numeric::{self, Rounding, MONEY_SCALE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LotMethod {
+    Fifo,
+    /// All shares cost their average price, the way Austrian banks book the KESt.
+    AverageCost,
+}
+
+impl FromStr for LotMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(LotMethod::Fifo),
+            "average" => Ok(LotMethod::AverageCost),
+            other => Err(format!("Unknown lot method '{}', expected fifo or average", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaxModel {
+    /// Share of the gain, 0.275 for the KESt. 0 turns taxes off.
+    #[serde(with = "crate::persist::decimal")]
+    pub rate: BigDecimal,
+    pub lots: LotMethod,
+    pub loss_offset: bool,
+}
+
+impl Default for TaxModel {
+    fn default() -> Self {
+        TaxModel {
+            rate: BigDecimal::zero(),
+            lots: LotMethod::Fifo,
+            loss_offset: true,
+        }
+    }
+}
+
+/// Shares bought together and what they cost without fees.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lot {
+    #[serde(with = "crate::persist::decimal")]
+    pub shares: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub cost: BigDecimal,
+}
+
+/// Realized gain and the tax paid on it in one calendar year.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct TaxYear {
+    #[serde(with = "crate::persist::decimal")]
+    pub gain: BigDecimal,
+    #[serde(with = "crate::persist::decimal")]
+    pub tax: BigDecimal,
+}
+
+impl TaxModel {
+    pub fn is_active(&self) -> bool {
+        !self.rate.is_zero()
+    }
+
+    pub fn add_lot(&self, lots: &mut VecDeque<Lot>, shares: &BigDecimal, cost: &BigDecimal) {
+        match (self.lots, lots.front_mut()) {
+            (LotMethod::AverageCost, Some(lot)) => {
+                lot.shares += shares;
+                lot.cost += cost;
+            }
+            _ => lots.push_back(Lot { shares: shares.clone(), cost: cost.clone() }),
+        }
+    }
+
+    /// Removes `shares` from the lots, oldest first, and returns what they cost.
+    pub fn take(&self, lots: &mut VecDeque<Lot>, shares: &BigDecimal) -> BigDecimal {
+        let mut shares = shares.clone();
+        let mut cost = BigDecimal::zero();
+        while shares > BigDecimal::zero() {
+            let lot = lots.front_mut().expect("Selling more shares than the lots hold!");
+            if lot.shares <= shares {
+                shares -= &lot.shares;
+                cost += lots.pop_front().unwrap().cost;
+            } else {
+                let part = numeric::round(&(&lot.cost * &shares / &lot.shares), MONEY_SCALE, Rounding::HalfUp);
+                lot.cost -= &part;
+                lot.shares -= &shares;
+                cost += part;
+                shares = BigDecimal::zero();
+            }
+        }
+        cost
+    }
+
+    /// Books a realized gain, negative for a loss, and returns the tax it costs. Negative is a refund
+    /// of tax paid earlier in the year.
+    pub fn realize(&self, year: &mut TaxYear, gain: &BigDecimal) -> BigDecimal {
+        year.gain += gain;
+        let tax = if self.loss_offset {
+            let due = if year.gain > BigDecimal::zero() { self.tax(&year.gain) } else { BigDecimal::zero() };
+            due - &year.tax
+        } else if gain > &BigDecimal::zero() {
+            self.tax(gain)
+        } else {
+            BigDecimal::zero()
+        };
+        year.tax += &tax;
+        tax
+    }
+
+    fn tax(&self, gain: &BigDecimal) -> BigDecimal {
+        numeric::round(&(gain * &self.rate), MONEY_SCALE, Rounding::HalfUp)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, str::FromStr};
+
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use super::{LotMethod, TaxModel, TaxYear};
+
+    fn d(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn lots() {
+        let mut lots = VecDeque::new();
+        let fifo = TaxModel { rate: d("0.275"), ..TaxModel::default() };
+        fifo.add_lot(&mut lots, &d("10"), &d("1000"));
+        fifo.add_lot(&mut lots, &d("10"), &d("2000"));
+        assert_eq!(fifo.take(&mut lots, &d("15")), d("2000"));
+        assert_eq!(lots.len(), 1);
+
+        let mut lots = VecDeque::new();
+        let average = TaxModel { lots: LotMethod::AverageCost, ..fifo };
+        average.add_lot(&mut lots, &d("10"), &d("1000"));
+        average.add_lot(&mut lots, &d("10"), &d("2000"));
+        assert_eq!(average.take(&mut lots, &d("15")), d("2250"));
+        assert_eq!(average.take(&mut lots, &d("5")), d("750"));
+        assert!(lots.is_empty());
+    }
+
+    #[test]
+    fn offsets_losses_within_the_year() {
+        let kest = TaxModel { rate: d("0.275"), ..TaxModel::default() };
+        let mut year = TaxYear::default();
+        assert_eq!(kest.realize(&mut year, &d("1000")), d("275"));
+        assert_eq!(kest.realize(&mut year, &d("-400")), d("-110"));
+        assert_eq!(kest.realize(&mut year, &d("-1000")), d("-165"));
+        assert_eq!(kest.realize(&mut year, &d("500")), d("27.50"));
+        assert_eq!(year.tax, d("27.50"));
+
+        let no_offset = TaxModel { loss_offset: false, ..kest };
+        let mut year = TaxYear::default();
+        assert_eq!(no_offset.realize(&mut year, &d("1000")), d("275"));
+        assert_eq!(no_offset.realize(&mut year, &d("-400")), d("0"));
+        assert_eq!(year.tax, d("275"));
+    }
+}
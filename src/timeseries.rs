@@ -1,48 +1,565 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, ops::{Bound, Range, RangeBounds}, str::FromStr};
 
-use mysql::chrono::{NaiveDate};
-use mysql_common::bigdecimal::BigDecimal;
+use mysql::chrono::{Datelike, NaiveDate};
+use mysql_common::bigdecimal::{BigDecimal, ToPrimitive};
 
+use serde::{Deserialize, Serialize};
+
+use crate::{numeric::round_half_up, persist::Persist};
+
+/// One trading day. Only the close is always known, derived series like the 200avg carry nothing else.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bar {
+    #[serde(with = "crate::persist::option_decimal")]
+    pub open: Option<BigDecimal>,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub high: Option<BigDecimal>,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub low: Option<BigDecimal>,
+    #[serde(with = "crate::persist::decimal")]
+    pub close: BigDecimal,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub adjusted_close: Option<BigDecimal>,
+    pub volume: Option<u64>,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub dividend: Option<BigDecimal>,
+    #[serde(with = "crate::persist::option_decimal")]
+    pub split_coefficient: Option<BigDecimal>,
+}
+
+impl Bar {
+    pub fn from_close(close: BigDecimal) -> Self {
+        Bar {
+            open: None,
+            high: None,
+            low: None,
+            close,
+            adjusted_close: None,
+            volume: None,
+            dividend: None,
+            split_coefficient: None,
+        }
+    }
+
+    pub fn with_split<T: Into<BigDecimal>>(mut self, split_coefficient: T) -> Self {
+        self.split_coefficient = Some(split_coefficient.into());
+        self
+    }
+
+    /// Split coefficient of the day, 1 when there was no split or none is known.
+    pub fn split(&self) -> BigDecimal {
+        self.view().split()
+    }
+
+    pub fn has_split(&self) -> bool {
+        self.view().has_split()
+    }
+
+    pub fn view(&self) -> BarRef<'_> {
+        BarRef {
+            open: self.open.as_ref(),
+            high: self.high.as_ref(),
+            low: self.low.as_ref(),
+            close: &self.close,
+            adjusted_close: self.adjusted_close.as_ref(),
+            volume: self.volume,
+            dividend: self.dividend.as_ref(),
+            split_coefficient: self.split_coefficient.as_ref(),
+        }
+    }
+}
+
+/// A bar of a [`TimeSeries`], borrowed from its columns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarRef<'a> {
+    pub open: Option<&'a BigDecimal>,
+    pub high: Option<&'a BigDecimal>,
+    pub low: Option<&'a BigDecimal>,
+    pub close: &'a BigDecimal,
+    pub adjusted_close: Option<&'a BigDecimal>,
+    pub volume: Option<u64>,
+    pub dividend: Option<&'a BigDecimal>,
+    pub split_coefficient: Option<&'a BigDecimal>,
+}
+
+impl<'a> BarRef<'a> {
+    pub fn open_or_close(&self) -> &'a BigDecimal {
+        self.open.unwrap_or(self.close)
+    }
+
+    pub fn high_or_close(&self) -> &'a BigDecimal {
+        self.high.unwrap_or(self.close)
+    }
+
+    pub fn low_or_close(&self) -> &'a BigDecimal {
+        self.low.unwrap_or(self.close)
+    }
+
+    /// Split coefficient of the day, 1 when there was no split or none is known.
+    pub fn split(&self) -> BigDecimal {
+        self.split_coefficient.cloned().unwrap_or_else(|| BigDecimal::from(1))
+    }
+
+    pub fn has_split(&self) -> bool {
+        self.split_coefficient.is_some_and(|x| *x != BigDecimal::from(1))
+    }
+
+    pub fn to_bar(&self) -> Bar {
+        Bar {
+            open: self.open.cloned(),
+            high: self.high.cloned(),
+            low: self.low.cloned(),
+            close: self.close.clone(),
+            adjusted_close: self.adjusted_close.cloned(),
+            volume: self.volume,
+            dividend: self.dividend.cloned(),
+            split_coefficient: self.split_coefficient.cloned(),
+        }
+    }
+}
+
+/// Bar size of a series. Periods follow the calendar: ISO weeks from monday, months, quarters starting in january, april, july and october.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Quarterly,
+    Yearly,
+}
+
+impl Frequency {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Frequency::Daily => "daily",
+            Frequency::Weekly => "weekly",
+            Frequency::Monthly => "monthly",
+            Frequency::Quarterly => "quarterly",
+            Frequency::Yearly => "yearly",
+        }
+    }
+
+    /// Whether both dates fall into the same period.
+    pub fn same_period(&self, a: &NaiveDate, b: &NaiveDate) -> bool {
+        self.period(a) == self.period(b)
+    }
+
+    /// Identifies the period `date` falls into, equal for all days of one period.
+    fn period(&self, date: &NaiveDate) -> (i32, u32) {
+        match self {
+            Frequency::Daily => (date.year(), date.ordinal()),
+            Frequency::Weekly => (date.iso_week().year(), date.iso_week().week()),
+            Frequency::Monthly => (date.year(), date.month()),
+            Frequency::Quarterly => (date.year(), date.month0() / 3),
+            Frequency::Yearly => (date.year(), 0),
+        }
+    }
+}
+
+impl FromStr for Frequency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match [Frequency::Daily, Frequency::Weekly, Frequency::Monthly, Frequency::Quarterly, Frequency::Yearly].iter().find(|f| f.name() == s) {
+            Some(frequency) => Ok(*frequency),
+            None => Err(format!("Unknown frequency '{}', expected daily, weekly, monthly, quarterly or yearly", s)),
+        }
+    }
+}
+
+/// Bars of one equity in date order.
+///
+/// Stored by column, a sorted date vector and one vector per field of the bar: positional access is
+/// O(1), dates are found by binary search and appending a later day is a push. Inserting before the
+/// last day moves the tail, so bulk data in arbitrary order is better collected in a map and
+/// converted with [`TimeSeries::from_map`]. Saved files hold the bars row by row.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(into = "Rows", from = "Rows")]
 pub struct TimeSeries {
     pub equity_name: String,
-    pub entries: BTreeMap<NaiveDate, (BigDecimal, Option<f32>)>,
+    dates: Vec<NaiveDate>,
+    open: Vec<Option<BigDecimal>>,
+    high: Vec<Option<BigDecimal>>,
+    low: Vec<Option<BigDecimal>>,
+    close: Vec<BigDecimal>,
+    adjusted_close: Vec<Option<BigDecimal>>,
+    volume: Vec<Option<u64>>,
+    dividend: Vec<Option<BigDecimal>>,
+    split_coefficient: Vec<Option<BigDecimal>>,
+}
+
+/// The saved layout of a series.
+#[derive(Serialize, Deserialize)]
+struct Rows {
+    equity_name: String,
+    dates: Vec<NaiveDate>,
+    bars: Vec<Bar>,
+}
+
+impl From<TimeSeries> for Rows {
+    fn from(ts: TimeSeries) -> Self {
+        let equity_name = ts.equity_name.clone();
+        let (dates, bars) = ts.into_map().into_iter().unzip();
+        Rows { equity_name, dates, bars }
+    }
 }
 
+impl From<Rows> for TimeSeries {
+    fn from(rows: Rows) -> Self {
+        let mut ts = TimeSeries::new(&rows.equity_name);
+        for (date, bar) in rows.dates.into_iter().zip(rows.bars) {
+            ts.insert(date, bar);
+        }
+        ts
+    }
+}
 
 impl TimeSeries {
-    pub fn correct_splits(&mut self) {
-        for i in &self.entries.clone() {
-            if i.1.1.is_some() && i.1.1.unwrap() != 1.0 {
-                self.entries.range_mut(..i.0).for_each(|x| {
-                    x.1.0 = &x.1.0 / i.1.1.unwrap();
-                });
+    pub fn new(equity_name: &str) -> Self {
+        TimeSeries {
+            equity_name: String::from(equity_name),
+            ..TimeSeries::default()
+        }
+    }
+
+    pub fn from_map(equity_name: &str, entries: BTreeMap<NaiveDate, Bar>) -> Self {
+        let mut ts = TimeSeries::new(equity_name);
+        for (date, bar) in entries {
+            ts.push(date, bar);
+        }
+        ts
+    }
+
+    pub fn into_map(self) -> BTreeMap<NaiveDate, Bar> {
+        let bars = self.open.into_iter().zip(self.high).zip(self.low).zip(self.close).zip(self.adjusted_close).zip(self.volume).zip(self.dividend).zip(self.split_coefficient)
+            .map(|(((((((open, high), low), close), adjusted_close), volume), dividend), split_coefficient)| Bar { open, high, low, close, adjusted_close, volume, dividend, split_coefficient });
+        self.dates.into_iter().zip(bars).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.dates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dates.is_empty()
+    }
+
+    /// Inserts or replaces the bar of `date`, returning the replaced one.
+    pub fn insert(&mut self, date: NaiveDate, bar: Bar) -> Option<Bar> {
+        if self.dates.last().is_none_or(|last| *last < date) {
+            self.push(date, bar);
+            return None;
+        }
+        match self.position(&date) {
+            Ok(i) => {
+                let old = self.bar(i).to_bar();
+                self.set(i, bar);
+                Some(old)
+            }
+            Err(i) => {
+                self.dates.insert(i, date);
+                self.open.insert(i, bar.open);
+                self.high.insert(i, bar.high);
+                self.low.insert(i, bar.low);
+                self.close.insert(i, bar.close);
+                self.adjusted_close.insert(i, bar.adjusted_close);
+                self.volume.insert(i, bar.volume);
+                self.dividend.insert(i, bar.dividend);
+                self.split_coefficient.insert(i, bar.split_coefficient);
+                None
             }
         }
     }
 
-    pub fn nth(&self, index: usize) -> (NaiveDate, (BigDecimal, Option<f32>)) {
-        let date = self.entries.keys().nth(index).unwrap();
-        let entry = self.entries.get(date).unwrap();
-        (*date, (entry.0.clone(), entry.1))
+    /// Appends a day after the last one.
+    fn push(&mut self, date: NaiveDate, bar: Bar) {
+        self.dates.push(date);
+        self.open.push(bar.open);
+        self.high.push(bar.high);
+        self.low.push(bar.low);
+        self.close.push(bar.close);
+        self.adjusted_close.push(bar.adjusted_close);
+        self.volume.push(bar.volume);
+        self.dividend.push(bar.dividend);
+        self.split_coefficient.push(bar.split_coefficient);
     }
 
-    pub fn between(&self, start: usize, end: usize) -> Vec<(NaiveDate, (BigDecimal, Option<f32>))> {
-        if start >= self.entries.len() || end >= self.entries.len() || start == end {
+    /// Replaces the bar at `index`.
+    pub fn set(&mut self, index: usize, bar: Bar) {
+        self.open[index] = bar.open;
+        self.high[index] = bar.high;
+        self.low[index] = bar.low;
+        self.close[index] = bar.close;
+        self.adjusted_close[index] = bar.adjusted_close;
+        self.volume[index] = bar.volume;
+        self.dividend[index] = bar.dividend;
+        self.split_coefficient[index] = bar.split_coefficient;
+    }
+
+    /// Inserts all entries of `other`, its bars win on dates both have.
+    pub fn extend(&mut self, other: TimeSeries) {
+        for (date, bar) in other.into_map() {
+            self.insert(date, bar);
+        }
+    }
+
+    /// Index of `date`, or where it would have to be inserted.
+    pub fn position(&self, date: &NaiveDate) -> Result<usize, usize> {
+        self.dates.binary_search(date)
+    }
+
+    /// The bar at `index`, panics past the end.
+    pub fn bar(&self, index: usize) -> BarRef<'_> {
+        BarRef {
+            open: self.open[index].as_ref(),
+            high: self.high[index].as_ref(),
+            low: self.low[index].as_ref(),
+            close: &self.close[index],
+            adjusted_close: self.adjusted_close[index].as_ref(),
+            volume: self.volume[index],
+            dividend: self.dividend[index].as_ref(),
+            split_coefficient: self.split_coefficient[index].as_ref(),
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&NaiveDate, BarRef<'_>)> + ExactSizeIterator {
+        self.dates.iter().enumerate().map(move |(i, date)| (date, self.bar(i)))
+    }
+
+    pub fn dates(&self) -> impl DoubleEndedIterator<Item = &NaiveDate> + ExactSizeIterator {
+        self.dates.iter()
+    }
+
+    pub fn closes(&self) -> impl DoubleEndedIterator<Item = &BigDecimal> + ExactSizeIterator {
+        self.close.iter()
+    }
+
+    pub fn date_column(&self) -> &[NaiveDate] {
+        &self.dates
+    }
+
+    pub fn close_column(&self) -> &[BigDecimal] {
+        &self.close
+    }
+
+    pub fn get(&self, date: &NaiveDate) -> Option<BarRef<'_>> {
+        self.position(date).ok().map(|i| self.bar(i))
+    }
+
+    pub fn first(&self) -> Option<(&NaiveDate, BarRef<'_>)> {
+        self.nth(0)
+    }
+
+    pub fn last(&self) -> Option<(&NaiveDate, BarRef<'_>)> {
+        self.len().checked_sub(1).and_then(|i| self.nth(i))
+    }
+
+    pub fn nth(&self, index: usize) -> Option<(&NaiveDate, BarRef<'_>)> {
+        Some((self.dates.get(index)?, self.bar(index)))
+    }
+
+    /// Entries from index `start` up to but excluding `end`, empty unless `start < end < len`.
+    pub fn between(&self, start: usize, end: usize) -> Vec<(&NaiveDate, BarRef<'_>)> {
+        if start >= end || end >= self.len() {
             return Vec::new();
         }
-        let date1 = self.entries.keys().nth(start).unwrap();
-        let date2 = self.entries.keys().nth(end).unwrap();
-        let mut result: Vec<(NaiveDate, (BigDecimal, Option<f32>))> = Vec::new();
-        for i in self.entries.iter().filter(|x| { x.0 >= date1 && x.0 < date2}) {
-            let d = *i.0;
-            let c = i.1.0.clone();
-            let s = i.1.1;
-            result.push((d, (c, s)));
+        (start..end).map(|i| (&self.dates[i], self.bar(i))).collect()
+    }
+
+    /// Copy of the entries whose date lies in `range`.
+    pub fn slice<R: RangeBounds<NaiveDate>>(&self, range: R) -> TimeSeries {
+        let start = match range.start_bound() {
+            Bound::Included(date) => self.dates.partition_point(|d| d < date),
+            Bound::Excluded(date) => self.dates.partition_point(|d| d <= date),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(date) => self.dates.partition_point(|d| d <= date),
+            Bound::Excluded(date) => self.dates.partition_point(|d| d < date),
+            Bound::Unbounded => self.len(),
+        };
+        let r = start..end.max(start);
+        TimeSeries {
+            equity_name: self.equity_name.clone(),
+            dates: self.dates[r.clone()].to_vec(),
+            open: self.open[r.clone()].to_vec(),
+            high: self.high[r.clone()].to_vec(),
+            low: self.low[r.clone()].to_vec(),
+            close: self.close[r.clone()].to_vec(),
+            adjusted_close: self.adjusted_close[r.clone()].to_vec(),
+            volume: self.volume[r.clone()].to_vec(),
+            dividend: self.dividend[r.clone()].to_vec(),
+            split_coefficient: self.split_coefficient[r].to_vec(),
+        }
+    }
+
+    /// Applies every split to the bars before it: prices and dividends are divided, volumes
+    /// multiplied. The provider's adjusted close already accounts for all splits and stays as it is.
+    /// Prices keep their full precision, volumes are rounded half up to whole shares.
+    pub fn correct_splits(&mut self) {
+        let splits: Vec<(usize, BigDecimal)> = (0..self.len()).map(|i| self.bar(i)).enumerate().filter(|x| x.1.has_split()).map(|x| (x.0, x.1.split())).collect();
+        for (end, factor) in splits {
+            for column in [&mut self.open, &mut self.high, &mut self.low, &mut self.dividend] {
+                for price in column[..end].iter_mut().flatten() {
+                    *price = &*price / &factor;
+                }
+            }
+            for close in &mut self.close[..end] {
+                *close = &*close / &factor;
+            }
+            for volume in self.volume[..end].iter_mut().flatten() {
+                *volume = round_half_up(&(BigDecimal::from(*volume) * &factor), 0).to_u64().unwrap();
+            }
+        }
+    }
+
+    /// Applies `f` to every price.
+    pub fn map_prices<F: Fn(&BigDecimal) -> BigDecimal>(&mut self, f: F) {
+        for column in [&mut self.open, &mut self.high, &mut self.low, &mut self.adjusted_close] {
+            for price in column.iter_mut().flatten() {
+                *price = f(price);
+            }
+        }
+        for close in &mut self.close {
+            *close = f(close);
+        }
+    }
+
+    pub fn get_max_close(&self) -> Option<BigDecimal> {
+        self.closes().max().cloned()
+    }
+
+    /// Aggregates the bars of each calendar period into one, dated on the last trading day of the period.
+    /// It opens with the first open, has the highest high, lowest low, last close and the summed volume
+    /// and dividends. Its split coefficient is the product of the period's splits, so splits can still be corrected.
+    pub fn resample(&self, frequency: Frequency) -> TimeSeries {
+        if frequency == Frequency::Daily {
+            return self.clone();
+        }
+        let mut result = TimeSeries::new(&self.equity_name);
+        let mut start = 0;
+        for end in 1..=self.len() {
+            if end < self.len() && frequency.period(&self.dates[end]) == frequency.period(&self.dates[start]) {
+                continue;
+            }
+            result.push(self.dates[end - 1], self.aggregate(start..end));
+            start = end;
         }
         result
     }
 
-    pub fn get_max_close(&self) -> BigDecimal {
-        self.entries.values().max_by(|x, y| x.0.cmp(&y.0)).unwrap().0.clone()
+    /// One bar out of the bars in `range`, a field stays empty if none of the bars has it.
+    fn aggregate(&self, range: Range<usize>) -> Bar {
+        let bars: Vec<BarRef> = range.map(|i| self.bar(i)).collect();
+        let (first, last) = (bars[0], bars[bars.len() - 1]);
+        let any = |f: fn(&BarRef) -> bool| bars.iter().any(f);
+        Bar {
+            open: first.open.cloned(),
+            high: if any(|b| b.high.is_some()) { bars.iter().map(|b| b.high_or_close()).max().cloned() } else { None },
+            low: if any(|b| b.low.is_some()) { bars.iter().map(|b| b.low_or_close()).min().cloned() } else { None },
+            close: last.close.clone(),
+            adjusted_close: last.adjusted_close.cloned(),
+            volume: if any(|b| b.volume.is_some()) { Some(bars.iter().filter_map(|b| b.volume).sum()) } else { None },
+            dividend: if any(|b| b.dividend.is_some()) { Some(bars.iter().filter_map(|b| b.dividend).sum()) } else { None },
+            split_coefficient: if any(|b| b.split_coefficient.is_some()) { Some(bars.iter().fold(BigDecimal::from(1), |p, b| p * b.split())) } else { None },
+        }
     }
-}
\ No newline at end of file
+}
+
+impl Persist for TimeSeries {
+    const KIND: &'static str = "timeseries";
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::NaiveDate;
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use super::{Bar, Frequency, TimeSeries};
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 1, d).unwrap()
+    }
+
+    #[test]
+    fn lookups_and_splits() {
+        let mut ts = TimeSeries::new("test");
+        ts.insert(day(1), Bar { volume: Some(10), ..Bar::from_close(BigDecimal::from(400)).with_split(1) });
+        ts.insert(day(2), Bar::from_close(BigDecimal::from(100)).with_split(4));
+        ts.insert(day(3), Bar::from_close(BigDecimal::from(110)).with_split(1));
+
+        assert_eq!(ts.nth(1).unwrap().0, &day(2));
+        assert!(ts.nth(3).is_none());
+        assert_eq!(ts.between(0, 2).iter().map(|x| *x.0).collect::<Vec<_>>(), vec![day(1), day(2)]);
+        assert!(ts.between(1, 3).is_empty());
+        assert!(ts.get(&day(4)).is_none());
+        assert_eq!(ts.slice(day(2)..).len(), 2);
+        assert!(ts.slice(day(4)..day(5)).is_empty());
+        assert_eq!(ts.slice(day(1)..day(3)).len(), 2);
+        assert_eq!(ts.slice(day(1)..=day(3)).len(), 3);
+        assert!(ts.slice(day(3)..day(1)).is_empty());
+        assert_eq!(ts.get_max_close(), Some(BigDecimal::from(400)));
+        assert_eq!(TimeSeries::new("empty").get_max_close(), None);
+
+        ts.correct_splits();
+        assert_eq!(ts.get(&day(1)).unwrap().close, &BigDecimal::from(100));
+        assert_eq!(ts.get(&day(1)).unwrap().volume, Some(40));
+        assert_eq!(ts.get(&day(2)).unwrap().close, &BigDecimal::from(100));
+    }
+
+    #[test]
+    fn inserts_keep_date_order() {
+        let mut ts = TimeSeries::new("test");
+        ts.insert(day(3), Bar::from_close(BigDecimal::from(3)));
+        ts.insert(day(1), Bar::from_close(BigDecimal::from(1)));
+        ts.insert(day(2), Bar::from_close(BigDecimal::from(2)));
+        assert_eq!(ts.insert(day(2), Bar::from_close(BigDecimal::from(20))), Some(Bar::from_close(BigDecimal::from(2))));
+        assert_eq!(ts.dates().cloned().collect::<Vec<_>>(), vec![day(1), day(2), day(3)]);
+        assert_eq!(ts.position(&day(4)), Err(3));
+
+        let mut later = TimeSeries::new("test");
+        later.insert(day(3), Bar::from_close(BigDecimal::from(30)));
+        later.insert(day(4), Bar::from_close(BigDecimal::from(4)));
+        ts.extend(later);
+        assert_eq!(ts.closes().cloned().collect::<Vec<_>>(), [1, 20, 30, 4].iter().map(|x| BigDecimal::from(*x)).collect::<Vec<_>>());
+        assert_eq!(TimeSeries::from_map("test", ts.clone().into_map()), ts);
+    }
+
+    #[test]
+    fn resamples_by_calendar_period() {
+        let date = |m: u32, d: u32| NaiveDate::from_ymd_opt(2020, m, d).unwrap();
+        let bar = |open: i32, high: i32, low: i32, close: i32, volume: u64| Bar {
+            open: Some(BigDecimal::from(open)),
+            high: Some(BigDecimal::from(high)),
+            low: Some(BigDecimal::from(low)),
+            volume: Some(volume),
+            ..Bar::from_close(BigDecimal::from(close)).with_split(1)
+        };
+        let mut ts = TimeSeries::new("test");
+        // Thursday 2nd and Friday 3rd of January are ISO week 1, monday the 6th starts week 2
+        ts.insert(date(1, 2), bar(10, 12, 9, 11, 100));
+        ts.insert(date(1, 3), bar(11, 15, 10, 14, 200));
+        ts.insert(date(1, 6), bar(14, 14, 8, 9, 300));
+        ts.insert(date(1, 31), bar(9, 10, 9, 10, 400));
+        ts.insert(date(2, 3), Bar::from_close(BigDecimal::from(20)).with_split(2));
+        ts.insert(date(4, 1), bar(20, 21, 19, 21, 500));
+
+        let weekly = ts.resample(Frequency::Weekly);
+        assert_eq!(weekly.dates().cloned().collect::<Vec<_>>(), vec![date(1, 3), date(1, 6), date(1, 31), date(2, 3), date(4, 1)]);
+        assert_eq!(weekly.first().unwrap().1.to_bar(), bar(10, 15, 9, 14, 300));
+
+        let monthly = ts.resample(Frequency::Monthly);
+        assert_eq!(monthly.dates().cloned().collect::<Vec<_>>(), vec![date(1, 31), date(2, 3), date(4, 1)]);
+        assert_eq!(monthly.first().unwrap().1.to_bar(), bar(10, 15, 8, 10, 1000));
+        assert_eq!(monthly.nth(1).unwrap().1.to_bar(), Bar::from_close(BigDecimal::from(20)).with_split(2));
+
+        let quarterly = ts.resample(Frequency::Quarterly);
+        assert_eq!(quarterly.len(), 2);
+        let q1 = quarterly.first().unwrap().1.to_bar();
+        assert_eq!((q1.high.clone(), q1.low.clone(), q1.volume, q1.split()), (Some(BigDecimal::from(20)), Some(BigDecimal::from(8)), Some(1000), BigDecimal::from(2)));
+        assert_eq!(ts.resample(Frequency::Yearly).len(), 1);
+        assert_eq!(ts.resample(Frequency::Daily), ts);
+        assert_eq!("quarterly".parse::<Frequency>(), Ok(Frequency::Quarterly));
+    }
+}
@@ -0,0 +1,298 @@
+//! Walk-forward analysis of the strategies.
+//!
+//! The date range is split into folds: an in-sample period of `in_sample` months is followed by an
+//! out-of-sample period of `out_of_sample` months, and the next fold starts that many months later. In
+//! every fold the strategy's parameters are chosen by the sweep metric on the in-sample period, from the
+//! windows and bands of the sweep grid, and then traded out of sample. The out-of-sample periods don't
+//! overlap, so they are traded one after the other with one depot and their equity curves make one
+//! curve. A position open at the end of a fold is carried into the next one and only sold after the
+//! last, orders still waiting for their fill at a fold's end are dropped. How much worse the metric
+//! gets out of sample than in sample shows how much the tuning fit noise.
+
+use std::{collections::BTreeMap, fmt, io::{BufWriter, Write}};
+
+use mysql::chrono::{Months, NaiveDate};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{benchmark::Comparison, buy::{self, Backtest, Depot, Metrics, RunState}, indicators, ledger, persist::Persist, strategy::StrategySpec, sweep::{Metric, SweepConfig}, timeseries::{Bar, TimeSeries}};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalkForwardConfig {
+    /// Months to choose the parameters on.
+    pub in_sample: u32,
+    /// Months to trade them, also how far the folds move.
+    pub out_of_sample: u32,
+}
+
+impl Default for WalkForwardConfig {
+    fn default() -> Self {
+        WalkForwardConfig { in_sample: 24, out_of_sample: 6 }
+    }
+}
+
+impl WalkForwardConfig {
+    /// The in-sample and out-of-sample periods of all folds, first and last day included. The last
+    /// out-of-sample period is cut at `end_date`.
+    pub fn periods(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<((NaiveDate, NaiveDate), (NaiveDate, NaiveDate))> {
+        let mut periods = Vec::new();
+        let mut start = start_date;
+        while let Some(oos_start) = start.checked_add_months(Months::new(self.in_sample)).filter(|d| *d <= end_date) {
+            let oos_end = oos_start.checked_add_months(Months::new(self.out_of_sample)).unwrap().pred_opt().unwrap().min(end_date);
+            periods.push(((start, oos_start.pred_opt().unwrap()), (oos_start, oos_end)));
+            start = start.checked_add_months(Months::new(self.out_of_sample)).unwrap();
+        }
+        periods
+    }
+}
+
+/// One fold and the parameters chosen in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fold {
+    pub in_sample: (NaiveDate, NaiveDate),
+    pub out_of_sample: (NaiveDate, NaiveDate),
+    /// Days of the average, `None` for strategies that don't use one.
+    pub window: Option<usize>,
+    pub spec: StrategySpec,
+    /// The metric of the chosen parameters in sample.
+    pub in_sample_score: Option<f64>,
+    pub out_of_sample_score: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalkForwardResult {
+    pub symbol: String,
+    /// Without parameters, they are chosen per fold.
+    pub strategy: String,
+    pub metric: Metric,
+    pub folds: Vec<Fold>,
+    /// The out-of-sample periods one after the other.
+    pub equity: TimeSeries,
+    /// Of the out-of-sample equity curve.
+    pub metrics: Metrics,
+    /// Of the out-of-sample equity curve, `None` when the configured benchmark has no data.
+    pub benchmark: Option<Comparison>,
+    /// The depot after the last fold, everything sold, with the fills of all out-of-sample periods.
+    pub depot: Depot,
+}
+
+impl WalkForwardResult {
+    /// Means of the in-sample and out-of-sample scores of the folds that have both.
+    pub fn mean_scores(&self) -> Option<(f64, f64)> {
+        let scores: Vec<(f64, f64)> = self.folds.iter().filter_map(|f| Some((f.in_sample_score?, f.out_of_sample_score?))).collect();
+        if scores.is_empty() {
+            return None;
+        }
+        let n = scores.len() as f64;
+        Some((scores.iter().map(|x| x.0).sum::<f64>() / n, scores.iter().map(|x| x.1).sum::<f64>() / n))
+    }
+
+    /// How much worse the mean score is out of sample, as share of the in-sample one. Negative when
+    /// it got better.
+    pub fn degradation(&self) -> Option<f64> {
+        let (in_sample, out_of_sample) = self.mean_scores()?;
+        let (in_sample, out_of_sample) = if self.metric == Metric::MaxDrawdown { (-in_sample, -out_of_sample) } else { (in_sample, out_of_sample) };
+        Some((in_sample - out_of_sample) / in_sample.abs()).filter(|x| x.is_finite())
+    }
+}
+
+impl fmt::Display for WalkForwardResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let score = |x: Option<f64>| x.map_or(String::from("-"), |x| format!("{:.2}", x));
+        let (in_sample, out_of_sample) = self.mean_scores().map_or((None, None), |(a, b)| (Some(a), Some(b)));
+        writeln!(f, "{} out-of-sample Endvalue: {}€, {} {} in sample, {} out of sample, degradation {}", self.strategy, self.depot.money, self.metric.name(),
+            score(in_sample), score(out_of_sample), self.degradation().map_or(String::from("-"), |x| format!("{:.2}%", x * 100.0)))?;
+        write!(f, "{}", self.metrics)?;
+        if let Some(benchmark) = &self.benchmark {
+            write!(f, "\n{}", benchmark)?;
+        }
+        for fold in &self.folds {
+            write!(f, "\n    {} to {}: {}, {} {} in sample, {} out of sample", fold.out_of_sample.0, fold.out_of_sample.1, params(fold), self.metric.name(),
+                score(fold.in_sample_score), score(fold.out_of_sample_score))?;
+        }
+        Ok(())
+    }
+}
+
+fn params(fold: &Fold) -> String {
+    match (&fold.spec, fold.window) {
+        (StrategySpec::Avg200Range(Some(range)), Some(window)) => format!("window {}, range {}", window, range),
+        (_, Some(window)) => format!("window {}", window),
+        _ => String::from("no parameters"),
+    }
+}
+
+impl Persist for Vec<WalkForwardResult> {
+    const KIND: &'static str = "walk_forward_results";
+    const SINCE: u32 = 16;
+}
+
+/// The parameters a strategy is tuned over, with the window of the average they use.
+fn candidates(spec: &StrategySpec, grid: &SweepConfig) -> Vec<(Option<usize>, StrategySpec)> {
+    match spec {
+        StrategySpec::BuyAndHold => vec![(None, spec.clone())],
+        StrategySpec::Avg200 => grid.windows.iter().map(|w| (Some(*w), StrategySpec::Avg200)).collect(),
+        StrategySpec::Avg200Range(_) => grid.windows.iter()
+            .flat_map(|w| grid.ranges.iter().map(move |r| (Some(*w), StrategySpec::Avg200Range(Some(r.clone())))))
+            .collect(),
+    }
+}
+
+/// Walks the strategies of `symbol` forward over `ts`, its full adjusted history, and compares the
+/// out-of-sample equity to the named `benchmark`. The averages are computed over the whole history,
+/// so they are there from the first day of each period.
+#[allow(clippy::too_many_arguments)]
+pub fn walk_forward(backtest: &Backtest, config: &WalkForwardConfig, grid: &SweepConfig, symbol: &str, ts: &TimeSeries, benchmark: Option<&(String, TimeSeries)>, start_date: NaiveDate, end_date: NaiveDate) -> Vec<WalkForwardResult> {
+    let periods = config.periods(start_date, end_date);
+    let averages: BTreeMap<usize, TimeSeries> = grid.windows.par_iter().map(|w| (*w, indicators::sma(ts, *w))).collect();
+    let average = |window: Option<usize>, (from, to): (NaiveDate, NaiveDate)| window.map_or(TimeSeries::default(), |w| averages[&w].slice(from..=to));
+    let strategy = |spec: &StrategySpec| spec.build(&backtest.avg200_range, &backtest.numeric);
+    let in_sample = |spec: &StrategySpec, window: Option<usize>, period: (NaiveDate, NaiveDate)| {
+        let mut depot = backtest.depot(symbol);
+        let run = buy::run(strategy(spec).as_mut(), &ts.slice(period.0..=period.1), &average(window, period), backtest.frequency, None, &mut depot);
+        Metrics::of(&backtest.start_depot, &run.equity, &ledger::round_trips(&depot.ledger), run.exposure, backtest.risk_free_rate)
+    };
+
+    backtest.strategies_for(symbol).iter().map(|spec| {
+        let candidates = candidates(spec, grid);
+        let mut depot = backtest.depot(symbol);
+        let mut equity = TimeSeries::new(&ts.equity_name);
+        let mut invested_days = 0.0;
+        let mut folds = Vec::new();
+        for (in_sample_period, (from, to)) in &periods {
+            let scores: Vec<Option<f64>> = candidates.par_iter().map(|(window, candidate)| grid.metric.score(&in_sample(candidate, *window, *in_sample_period))).collect();
+            // The first of equally good candidates, the first one if none has a score
+            let best = (0..candidates.len()).rev().max_by(|a, b| scores[*a].partial_cmp(&scores[*b]).unwrap()).unwrap();
+            let (window, chosen) = &candidates[best];
+            // Out of sample the position of the last fold is kept, the fold starts with its value
+            let start_value = equity.last().map_or(depot.money.clone(), |x| x.1.close.clone());
+            let run = buy::run_open(strategy(chosen).as_mut(), &ts.slice(*from..=*to), &average(*window, (*from, *to)), backtest.frequency, None, &mut depot);
+            let trips: Vec<_> = ledger::round_trips(&depot.ledger).into_iter().filter(|t| t.exit_date >= *from).collect();
+            let metrics = Metrics::of(&start_value, &run.equity, &trips, run.exposure, backtest.risk_free_rate);
+            invested_days += run.exposure * run.equity.len() as f64;
+            equity.extend(run.equity);
+            folds.push(Fold {
+                in_sample: *in_sample_period,
+                out_of_sample: (*from, *to),
+                window: *window,
+                spec: chosen.clone(),
+                in_sample_score: scores[best].map(|x| if grid.metric == Metric::MaxDrawdown { -x } else { x }),
+                out_of_sample_score: grid.metric.value(&metrics),
+            });
+        }
+        if let Some((date, _)) = equity.last() {
+            let date = *date;
+            let mut state = RunState { date, slippage_rate: depot.costs.slippage_rate(None), ..RunState::default() };
+            depot.full_sell(ts.get(&date).unwrap().close, &mut state);
+            equity.insert(date, Bar::from_close(depot.money.clone()));
+        }
+        let exposure = if equity.is_empty() { 0.0 } else { invested_days / equity.len() as f64 };
+        WalkForwardResult {
+            symbol: String::from(symbol),
+            strategy: String::from(spec.kind()),
+            metric: grid.metric,
+            folds,
+            metrics: Metrics::of(&backtest.start_depot, &equity, &ledger::round_trips(&depot.ledger), exposure, backtest.risk_free_rate),
+            benchmark: benchmark.map(|(name, benchmark)| Comparison::of(name, &equity, benchmark, backtest.risk_free_rate)),
+            equity,
+            depot,
+        }
+    }).collect()
+}
+
+pub fn write_folds_csv<W: Write>(out: W, result: &WalkForwardResult) {
+    let mut out = BufWriter::new(out);
+    let option = |x: Option<f64>| x.map_or(String::new(), |x| x.to_string());
+    writeln!(out, "in_sample_start,in_sample_end,out_of_sample_start,out_of_sample_end,window,range,in_sample_{0},out_of_sample_{0}", result.metric.name()).unwrap();
+    for fold in &result.folds {
+        let range = match &fold.spec {
+            StrategySpec::Avg200Range(Some(range)) => range.to_string(),
+            _ => String::new(),
+        };
+        writeln!(out, "{},{},{},{},{},{},{},{}", fold.in_sample.0, fold.in_sample.1, fold.out_of_sample.0, fold.out_of_sample.1, fold.window.map_or(String::new(), |w| w.to_string()),
+            range, option(fold.in_sample_score), option(fold.out_of_sample_score)).expect("Couldn't write csv row!");
+    }
+    out.flush().unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mysql::chrono::{Days, NaiveDate};
+    use mysql_common::bigdecimal::BigDecimal;
+
+    use crate::{buy::{Backtest, Depot, Metrics}, strategy::StrategySpec, sweep::{Metric, SweepConfig}, timeseries::{Bar, TimeSeries}};
+
+    use super::{walk_forward, Fold, WalkForwardConfig, WalkForwardResult};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn splits_into_rolling_folds() {
+        let config = WalkForwardConfig { in_sample: 12, out_of_sample: 6 };
+        let periods = config.periods(date(2018, 1, 1), date(2020, 3, 31));
+        assert_eq!(periods.len(), 3);
+        assert_eq!(periods[0], ((date(2018, 1, 1), date(2018, 12, 31)), (date(2019, 1, 1), date(2019, 6, 30))));
+        assert_eq!(periods[1].0.0, date(2018, 7, 1));
+        // The last out-of-sample period ends with the data
+        assert_eq!(periods[2].1, (date(2020, 1, 1), date(2020, 3, 31)));
+        assert!(config.periods(date(2018, 1, 1), date(2018, 12, 31)).is_empty());
+    }
+
+    #[test]
+    fn measures_the_degradation() {
+        let fold = |is: f64, oos: f64| Fold {
+            in_sample: (date(2018, 1, 1), date(2018, 12, 31)),
+            out_of_sample: (date(2019, 1, 1), date(2019, 6, 30)),
+            window: Some(200),
+            spec: StrategySpec::Avg200,
+            in_sample_score: Some(is),
+            out_of_sample_score: Some(oos),
+        };
+        let mut result = WalkForwardResult {
+            symbol: String::from("test"),
+            strategy: String::from("avg200"),
+            metric: Metric::Sharpe,
+            folds: vec![fold(1.5, 0.5), fold(0.5, 0.5)],
+            equity: TimeSeries::default(),
+            metrics: Metrics::default(),
+            benchmark: None,
+            depot: Depot::new(&0.into()),
+        };
+        assert_eq!(result.mean_scores(), Some((1.0, 0.5)));
+        assert_eq!(result.degradation(), Some(0.5));
+
+        // Drawdowns get worse when they grow
+        result.metric = Metric::MaxDrawdown;
+        result.folds = vec![fold(0.1, 0.15)];
+        assert!((result.degradation().unwrap() - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn carries_the_position_across_folds() {
+        // Rising by 1 every day from 100 on january 1st
+        let mut ts = TimeSeries::new("test");
+        for i in 0..121 {
+            ts.insert(date(2020, 1, 1).checked_add_days(Days::new(i)).unwrap(), Bar::from_close(BigDecimal::from(100 + i)));
+        }
+        let config = WalkForwardConfig { in_sample: 1, out_of_sample: 1 };
+        let grid = SweepConfig { windows: vec![2, 5], ranges: vec![BigDecimal::from(1)], metric: Metric::TotalReturn };
+        let own = (String::from("test buy_and_hold"), ts.clone());
+        let results = walk_forward(&Backtest::plain(&BigDecimal::from(1000)), &config, &grid, "test", &ts, Some(&own), date(2020, 1, 1), date(2020, 4, 30));
+        assert_eq!(results.iter().map(|r| r.strategy.as_str()).collect::<Vec<_>>(), ["buy_and_hold", "avg200", "avg200_range"]);
+
+        // Bought at 131 on february 1st and held through the folds of march and april
+        let hold = &results[0];
+        assert_eq!(hold.folds.len(), 3);
+        assert_eq!(hold.depot.ledger.len(), 2);
+        assert_eq!((hold.depot.ledger[0].price.clone(), hold.depot.ledger[1].price.clone()), (BigDecimal::from(131), BigDecimal::from(220)));
+        assert_eq!(hold.depot.money, BigDecimal::from(1623));
+        assert_eq!((hold.equity.len(), hold.equity.last().unwrap().1.close.clone()), (90, BigDecimal::from(1623)));
+        assert_eq!(hold.benchmark.as_ref().unwrap().benchmark, "test buy_and_hold");
+        assert!(hold.benchmark.as_ref().unwrap().beta.unwrap() > 0.0);
+        // The march fold starts with the value at the end of february
+        assert!((hold.folds[1].out_of_sample_score.unwrap() - (83.0 + 7.0 * 190.0) / (83.0 + 7.0 * 159.0) + 1.0).abs() < 1e-9);
+    }
+}